@@ -0,0 +1,186 @@
+//! Compressed storage of the spans of a persisted trace.
+//!
+//! Span lists are stored as zstd-compressed JSON. Most of a span list is
+//! field names, attribute keys and service names repeated from trace to
+//! trace, so `--compact` trains a zstd dictionary on the stored traces, which
+//! then compresses small traces several times better than zstd alone. Rows
+//! written before compression existed keep their plain `spans_json` until
+//! they are rewritten.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use anyhow::Result;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::state::SpanEvent;
+
+const LEVEL: i32 = 3;
+/// Size of trained dictionaries (zstd's own default).
+const DICTIONARY_SIZE: usize = 110 * 1024;
+/// Fewer samples than this do not train a useful dictionary.
+const MIN_TRAINING_SAMPLES: usize = 64;
+
+/// The spans of a `traces` row, as stored.
+pub struct StoredSpans {
+    /// Uncompressed JSON, for rows written before compression; empty otherwise.
+    pub json: String,
+    pub zstd: Option<Vec<u8>>,
+    /// Dictionary `zstd` was compressed with, if any.
+    pub dictionary: Option<i64>,
+}
+
+/// The spans of a trace, ready to be stored.
+pub struct EncodedSpans {
+    pub zstd: Vec<u8>,
+    pub dictionary: Option<i64>,
+    /// Size of the uncompressed JSON.
+    pub raw_size: usize,
+}
+
+struct Dictionary {
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+/// The dictionaries stored in the database. New rows are compressed with the
+/// most recent one.
+#[derive(Default)]
+pub struct SpanCodec {
+    dictionaries: HashMap<i64, Dictionary>,
+    current: Option<i64>,
+}
+
+impl SpanCodec {
+    /// Register dictionary `id`; it is used for new rows if it is the most recent.
+    pub fn add_dictionary(&mut self, id: i64, bytes: &[u8]) {
+        let dictionary = Dictionary {
+            encoder: EncoderDictionary::copy(bytes, LEVEL),
+            decoder: DecoderDictionary::copy(bytes),
+        };
+        self.dictionaries.insert(id, dictionary);
+        if self.current.is_none_or(|current| id > current) {
+            self.current = Some(id);
+        }
+    }
+
+    /// Forget the dictionaries no row refers to anymore.
+    pub fn retain_dictionaries(&mut self, used: &[i64]) {
+        self.dictionaries.retain(|id, _| used.contains(id) || Some(*id) == self.current);
+    }
+
+    /// The dictionary new rows are compressed with.
+    pub fn current_dictionary(&self) -> Option<i64> {
+        self.current
+    }
+
+    pub fn encode(&self, spans: &[SpanEvent]) -> Result<EncodedSpans> {
+        self.compress(&serde_json::to_vec(spans)?)
+    }
+
+    /// Compress a JSON span list.
+    pub fn compress(&self, json: &[u8]) -> Result<EncodedSpans> {
+        let zstd = match self.current.and_then(|id| self.dictionaries.get(&id)) {
+            Some(dictionary) => {
+                zstd::bulk::Compressor::with_prepared_dictionary(&dictionary.encoder)?.compress(json)?
+            }
+            None => zstd::bulk::compress(json, LEVEL)?,
+        };
+        Ok(EncodedSpans { zstd, dictionary: self.current, raw_size: json.len() })
+    }
+
+    /// The uncompressed JSON of `stored`; `None` if it is corrupt or its
+    /// dictionary is missing.
+    pub fn decompress(&self, stored: &StoredSpans) -> Option<Vec<u8>> {
+        let Some(zstd) = &stored.zstd else {
+            return Some(stored.json.as_bytes().to_vec());
+        };
+        let mut json = Vec::new();
+        match stored.dictionary {
+            Some(id) => {
+                let dictionary = self.dictionaries.get(&id)?;
+                zstd::stream::read::Decoder::with_prepared_dictionary(zstd.as_slice(), &dictionary.decoder)
+                    .ok()?
+                    .read_to_end(&mut json)
+                    .ok()?;
+            }
+            None => {
+                zstd::stream::read::Decoder::new(zstd.as_slice()).ok()?.read_to_end(&mut json).ok()?;
+            }
+        }
+        Some(json)
+    }
+
+    /// `None` if `stored` cannot be decompressed or is in an incompatible
+    /// span format.
+    pub fn decode(&self, stored: &StoredSpans) -> Option<Vec<SpanEvent>> {
+        match &stored.zstd {
+            None => serde_json::from_str(&stored.json).ok(),
+            Some(_) => serde_json::from_slice(&self.decompress(stored)?).ok(),
+        }
+    }
+}
+
+/// Train a dictionary on uncompressed span lists. `None` when there are too
+/// few samples or zstd could not build one from them.
+pub fn train_dictionary(samples: &[Vec<u8>]) -> Option<Vec<u8>> {
+    if samples.len() < MIN_TRAINING_SAMPLES {
+        return None;
+    }
+    match zstd::dict::from_samples(samples, DICTIONARY_SIZE) {
+        Ok(dictionary) => Some(dictionary),
+        Err(e) => {
+            tracing::warn!("Could not train a compression dictionary: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::span;
+
+    fn stored(encoded: EncodedSpans) -> StoredSpans {
+        StoredSpans { json: String::new(), zstd: Some(encoded.zstd), dictionary: encoded.dictionary }
+    }
+
+    #[test]
+    fn spans_round_trip_with_and_without_a_dictionary() {
+        let spans = vec![span("t1", "api", "ok", 1), span("t1", "db", "error", 2)];
+        let json = serde_json::to_string(&spans).unwrap();
+        let mut codec = SpanCodec::default();
+
+        let plain = StoredSpans { json: json.clone(), zstd: None, dictionary: None };
+        assert_eq!(codec.decompress(&plain).unwrap(), json.as_bytes());
+        let encoded = codec.encode(&spans).unwrap();
+        assert_eq!((encoded.dictionary, encoded.raw_size), (None, json.len()));
+        assert_eq!(codec.decompress(&stored(encoded)).unwrap(), json.as_bytes());
+
+        // Any bytes make a raw-content dictionary.
+        codec.add_dictionary(7, json.as_bytes());
+        let encoded = stored(codec.encode(&spans).unwrap());
+        assert_eq!(encoded.dictionary, Some(7));
+        assert_eq!(codec.decompress(&encoded).unwrap(), json.as_bytes());
+        assert_eq!(codec.decode(&encoded).unwrap().len(), 2);
+
+        // Without its dictionary, the row cannot be read.
+        assert!(SpanCodec::default().decompress(&encoded).is_none());
+    }
+
+    #[test]
+    fn only_the_current_and_used_dictionaries_are_kept() {
+        let mut codec = SpanCodec::default();
+        for id in [3, 1, 2] {
+            codec.add_dictionary(id, b"dictionary");
+        }
+        assert_eq!(codec.current_dictionary(), Some(3));
+
+        codec.retain_dictionaries(&[1]);
+        let mut kept: Vec<i64> = codec.dictionaries.keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, [1, 3]);
+        codec.retain_dictionaries(&[]);
+        assert_eq!(codec.dictionaries.keys().collect::<Vec<_>>(), [&3]);
+    }
+}
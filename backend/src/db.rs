@@ -1,13 +1,20 @@
 //! SQLite persistence layer for completed traces.
 
+use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Mutex;
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex, RwLock};
 
-use anyhow::Result;
-use rusqlite::{params, Connection};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use rusqlite::{params, params_from_iter, types::ValueRef, Connection, OpenFlags, OptionalExtension};
 use serde::{Deserialize, Serialize};
 
-use crate::state::{SpanEvent, TraceComplete};
+use crate::codec::{self, SpanCodec, StoredSpans};
+use crate::resource::ResourceInfo;
+use crate::metrics::{series_key, RESOLUTIONS};
+use crate::retention::{self, MetricRetention, RetentionMatch, RetentionPolicy};
+use crate::state::{linked_trace_ids, now_unix_nano, Attributes, MetricEvent, SpanEvent, TraceComplete, TraceStats};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TraceBounds {
@@ -16,143 +23,586 @@ pub struct TraceBounds {
     pub count: i64,
 }
 
+/// One span link, as stored in the `trace_links` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceLinkRef {
+    pub trace_id: String,
+    pub span_id: String,
+    pub linked_trace_id: String,
+    pub linked_span_id: String,
+}
+
+/// Why a trace was pinned. Pinned traces are never pruned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TracePin {
+    pub note: String,
+    pub label: String,
+    pub pinned_at: u64,
+}
+
+/// A pin with a summary of its trace, as listed by `/api/pins`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PinnedTrace {
+    pub trace_id: String,
+    #[serde(flatten)]
+    pub pin: TracePin,
+    /// `None` while the trace is not persisted yet.
+    pub root_span_name: Option<String>,
+    pub started_at: Option<u64>,
+    pub duration_ms: Option<f64>,
+}
+
+/// Span links leaving and entering a trace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceLinks {
+    pub trace_id: String,
+    /// Links recorded by spans of this trace.
+    pub outgoing: Vec<TraceLinkRef>,
+    /// Links recorded by spans of other traces that point into this trace.
+    pub incoming: Vec<TraceLinkRef>,
+}
+
+/// Conditions that must all hold for a single span of a trace.
+#[derive(Debug, Clone, Default)]
+pub struct SpanFilter {
+    /// Exact span name.
+    pub name: Option<String>,
+    /// Prefix of the span target (instrumentation scope).
+    pub target: Option<String>,
+    /// "ok" | "error" | "unset".
+    pub status: Option<String>,
+    pub min_duration_ms: Option<f64>,
+    pub max_duration_ms: Option<f64>,
+    /// `(key, value)` pairs; a `None` value only requires the key to be set.
+    /// Values are compared to the attribute rendered as a string.
+    pub attributes: Vec<(String, Option<String>)>,
+}
+
+impl SpanFilter {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.target.is_none()
+            && self.status.is_none()
+            && self.min_duration_ms.is_none()
+            && self.max_duration_ms.is_none()
+            && self.attributes.is_empty()
+    }
+}
+
+/// Column [`Db::query_traces`] sorts by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceSort {
+    #[default]
+    StartedAt,
+    Duration,
+    SpanCount,
+}
+
+impl TraceSort {
+    fn column(self) -> &'static str {
+        match self {
+            TraceSort::StartedAt => "started_at",
+            TraceSort::Duration  => "duration_ms",
+            TraceSort::SpanCount => "span_count",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Value of the sort column, as stored by SQLite.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SortValue {
+    Int(i64),
+    Real(f64),
+}
+
+impl rusqlite::ToSql for SortValue {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        match self {
+            SortValue::Int(v)  => v.to_sql(),
+            SortValue::Real(v) => v.to_sql(),
+        }
+    }
+}
+
+/// Position after the last trace of a page. Handed to clients as an opaque
+/// token, which is only valid for the sort it was issued for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceCursor {
+    sort: TraceSort,
+    order: SortOrder,
+    value: SortValue,
+    trace_id: String,
+}
+
+impl TraceCursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    /// `None` if `token` is malformed or was issued for another sort.
+    pub fn decode(token: &str, sort: TraceSort, order: SortOrder) -> Option<Self> {
+        let json = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(token).ok()?;
+        let cursor: TraceCursor = serde_json::from_slice(&json).ok()?;
+        (cursor.sort == sort && cursor.order == order).then_some(cursor)
+    }
+}
+
+/// Counting stops here: beyond it `total_estimate` is a lower bound.
+const TOTAL_ESTIMATE_CAP: usize = 100_000;
+
+/// One page of [`Db::query_traces`] results.
+#[derive(Debug, Clone, Serialize)]
+pub struct TracePage {
+    pub traces: Vec<TraceComplete>,
+    /// Pass as `cursor` to get the next page; `None` on the last page.
+    pub next_cursor: Option<String>,
+    /// Number of traces matching the filters, exact up to 100 000.
+    pub total_estimate: u64,
+}
+
+/// Selects the series of one metric for [`Db::query_metrics`].
+#[derive(Debug, Clone, Default)]
+pub struct MetricQuery {
+    pub metric_name: String,
+    pub service: Option<String>,
+    pub resource_id: Option<String>,
+    /// `(key, value)` attribute conditions; `None` only requires the key.
+    pub attributes: Vec<(String, Option<String>)>,
+    pub from_ns: i64,
+    pub to_ns: i64,
+    /// Width of the returned points.
+    pub step_secs: u64,
+    /// Seconds per stored point to read: 0 (raw) or a rollup resolution.
+    pub resolution: u64,
+}
+
+/// Points of one series, aggregated per step.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricSeriesPoints {
+    pub service_name: String,
+    pub metric_name: String,
+    pub kind: String,
+    pub unit: String,
+    pub description: String,
+    pub resource_id: String,
+    pub attributes: Attributes,
+    pub points: Vec<MetricPoint>,
+}
+
+/// The data points of a series within one step.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricPoint {
+    /// Start of the step, in Unix nanoseconds.
+    pub time: u64,
+    /// Data points aggregated.
+    pub samples: u64,
+    pub avg: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Value of the latest data point.
+    pub last: f64,
+    /// Mean per-second rate of sums.
+    pub rate: Option<f64>,
+    /// Highest percentile estimates of the step's data points: the worst
+    /// case over the step, not percentiles of all its observations.
+    pub p50_max: Option<f64>,
+    pub p90_max: Option<f64>,
+    pub p99_max: Option<f64>,
+}
+
+/// Result of [`Db::query_metrics`].
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricQueryResult {
+    /// Seconds per stored point the result was computed from (0 = raw).
+    pub resolution: u64,
+    pub step: u64,
+    pub series: Vec<MetricSeriesPoints>,
+    /// More than [`MAX_QUERY_SERIES`] series matched; the rest are left out.
+    pub truncated: bool,
+}
+
+/// Series returned by one [`Db::query_metrics`] call at most.
+pub const MAX_QUERY_SERIES: usize = 200;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    #[default]
+    Exact,
+    Substring,
+}
+
+/// Filters for [`Db::query_traces`].
+#[derive(Debug, Clone, Default)]
+pub struct TraceQuery {
+    pub from_ns: i64,
+    pub to_ns: i64,
+    pub limit: usize,
+    /// Substring of the name of any service with a span in the trace.
+    pub service: Option<String>,
+    pub min_duration_ms: Option<f64>,
+    pub max_duration_ms: Option<f64>,
+    /// Exact matches on the resources of the trace's spans
+    /// (`deployment.environment`, `service.version`, `host.name`, `k8s.pod.name`).
+    pub environment: Option<String>,
+    pub version: Option<String>,
+    pub host: Option<String>,
+    pub pod: Option<String>,
+    /// `true`: only traces with an error span; `false`: only traces without.
+    pub has_error: Option<bool>,
+    pub min_spans: Option<usize>,
+    /// A service with at least one span in the trace.
+    pub involves_service: Option<String>,
+    /// A `service.instance.id` with at least one span in the trace.
+    pub involves_instance: Option<String>,
+    /// How `involves_service` and `involves_instance` are compared.
+    pub involves_match: MatchMode,
+    /// Match traces containing at least one span satisfying all of these.
+    pub span: SpanFilter,
+    pub sort: TraceSort,
+    pub order: SortOrder,
+    /// Start after this position (from a previous page's `next_cursor`).
+    pub after: Option<TraceCursor>,
+}
+
 pub struct Db {
+    /// The only connection that writes.
     conn: Mutex<Connection>,
+    readers: ReadPool,
+    /// Compression dictionaries for `spans_zstd`.
+    codec: RwLock<SpanCodec>,
 }
 
 // rusqlite::Connection is Send, so Db is Send + Sync via Mutex.
 unsafe impl Send for Db {}
 unsafe impl Sync for Db {}
 
+/// Read-only connections used by queries. With WAL they read concurrently
+/// with each other and with the writer.
+struct ReadPool {
+    idle: Mutex<Vec<Connection>>,
+    available: Condvar,
+}
+
+impl ReadPool {
+    /// Take an idle connection, waiting for one if all are in use.
+    fn get(&self) -> PooledConnection<'_> {
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            if let Some(conn) = idle.pop() {
+                return PooledConnection { pool: self, conn: Some(conn) };
+            }
+            idle = self.available.wait(idle).unwrap();
+        }
+    }
+}
+
+/// A connection borrowed from a [`ReadPool`], returned to it on drop.
+struct PooledConnection<'a> {
+    pool: &'a ReadPool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
 impl Db {
-    pub fn open(path: &Path) -> Result<Self> {
-        let conn = Connection::open(path)?;
+    /// Open the database at `path`, creating it or migrating it to
+    /// [`SCHEMA_VERSION`] as needed, with `read_connections` read-only
+    /// connections for queries. Fails on databases written by a newer
+    /// version.
+    pub fn open(path: &Path, read_connections: usize) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
         conn.execute_batch(
             "PRAGMA journal_mode=WAL;
-             PRAGMA synchronous=NORMAL;
-             CREATE TABLE IF NOT EXISTS traces (
-                 trace_id       TEXT PRIMARY KEY,
-                 root_span_name TEXT NOT NULL,
-                 duration_ms    REAL NOT NULL,
-                 started_at     INTEGER NOT NULL,
-                 spans_json     TEXT NOT NULL,
-                 service_name   TEXT NOT NULL DEFAULT '',
-                 instance_id    TEXT NOT NULL DEFAULT ''
-             );
-             CREATE INDEX IF NOT EXISTS idx_started_at ON traces(started_at);
-             CREATE INDEX IF NOT EXISTS idx_service_name ON traces(service_name);",
+             PRAGMA synchronous=NORMAL;",
         )?;
+        let rebuild = migrate(&mut conn)?;
+        let codec = load_codec(&conn)?;
+        if rebuild {
+            rebuild_derived(&mut conn, &codec)?;
+        }
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI;
+        let readers = (0..read_connections.max(1))
+            .map(|_| Connection::open_with_flags(path, flags))
+            .collect::<rusqlite::Result<_>>()?;
         Ok(Self {
             conn: Mutex::new(conn),
+            readers: ReadPool { idle: Mutex::new(readers), available: Condvar::new() },
+            codec: RwLock::new(codec),
         })
     }
 
-    pub fn insert_trace(
-        &self,
-        trace: &TraceComplete,
-        service_name: &str,
-        instance_id: &str,
-    ) -> Result<()> {
-        let spans_json = serde_json::to_string(&trace.spans)?;
+    /// Persist a finalized trace. When the trace is already stored (its late
+    /// spans were finalized separately), the new spans are merged into the
+    /// stored ones by span_id and the summary columns are recomputed.
+    /// Returns the trace as stored.
+    pub fn insert_trace(&self, trace: &TraceComplete) -> Result<TraceComplete> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let trace = write_trace(&tx, &self.codec.read().unwrap(), trace)?;
+        tx.commit()?;
+        Ok(trace)
+    }
+
+    /// [`Db::insert_trace`] for several traces, in a single transaction.
+    pub fn insert_traces(&self, traces: &[TraceComplete]) -> Result<Vec<TraceComplete>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let codec = self.codec.read().unwrap();
+        let stored = traces.iter().map(|trace| write_trace(&tx, &codec, trace)).collect::<Result<_>>()?;
+        tx.commit()?;
+        Ok(stored)
+    }
+
+    /// Persist a newly registered resource. Resources are immutable (their ID
+    /// is a hash of their attributes), so an existing row is left untouched.
+    pub fn insert_resource(&self, resource: &ResourceInfo) -> Result<()> {
+        let attributes_json = serde_json::to_string(&resource.attributes)?;
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "INSERT OR REPLACE INTO traces \
-             (trace_id, root_span_name, duration_ms, started_at, spans_json, service_name, instance_id) \
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT OR IGNORE INTO resources \
+             (resource_id, attributes_json, service_name, service_version, environment, host_name, k8s_pod_name, first_seen) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             params![
-                trace.trace_id,
-                trace.root_span_name,
-                trace.duration_ms,
-                trace.started_at as i64,
-                spans_json,
-                service_name,
-                instance_id,
+                resource.resource_id,
+                attributes_json,
+                resource.service_name,
+                resource.service_version,
+                resource.environment,
+                resource.host_name,
+                resource.k8s_pod_name,
+                resource.first_seen_unix_nano as i64,
             ],
         )?;
         Ok(())
     }
 
-    pub fn query_traces(
-        &self,
-        from_ns: i64,
-        to_ns: i64,
-        limit: usize,
-        service: Option<&str>,
-        min_duration_ms: Option<f64>,
-        max_duration_ms: Option<f64>,
-    ) -> Result<Vec<TraceComplete>> {
-        let conn = self.conn.lock().unwrap();
+    /// All persisted resources, used to seed the in-memory registry on startup.
+    pub fn load_resources(&self) -> Result<Vec<ResourceInfo>> {
+        let conn = self.readers.get();
+        let mut stmt = conn.prepare("SELECT attributes_json, first_seen FROM resources")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let mut resources = Vec::new();
+        for row in rows {
+            let (attributes_json, first_seen) = row?;
+            let attributes = serde_json::from_str(&attributes_json)?;
+            resources.push(ResourceInfo::new(attributes, first_seen as u64));
+        }
+        Ok(resources)
+    }
 
-        // Build the query dynamically based on which optional filters are set.
-        let mut sql = String::from(
-            "SELECT trace_id, root_span_name, duration_ms, started_at, spans_json, instance_id \
-             FROM traces \
-             WHERE started_at >= ?1 AND started_at <= ?2",
+    /// Span links recorded by `trace_id` and span links pointing into it.
+    pub fn trace_links(&self, trace_id: &str) -> Result<TraceLinks> {
+        let conn = self.readers.get();
+        let select = |sql: &str| -> Result<Vec<TraceLinkRef>> {
+            let mut stmt = conn.prepare(sql)?;
+            let rows = stmt.query_map(params![trace_id], |row| {
+                Ok(TraceLinkRef {
+                    trace_id: row.get(0)?,
+                    span_id: row.get(1)?,
+                    linked_trace_id: row.get(2)?,
+                    linked_span_id: row.get(3)?,
+                })
+            })?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        };
+        Ok(TraceLinks {
+            trace_id: trace_id.to_string(),
+            outgoing: select(
+                "SELECT trace_id, span_id, linked_trace_id, linked_span_id \
+                 FROM trace_links WHERE trace_id = ?1",
+            )?,
+            incoming: select(
+                "SELECT trace_id, span_id, linked_trace_id, linked_span_id \
+                 FROM trace_links WHERE linked_trace_id = ?1 AND trace_id != ?1",
+            )?,
+        })
+    }
+
+    /// One page of the traces matching `query`, in the requested order.
+    pub fn query_traces(&self, query: &TraceQuery) -> Result<TracePage> {
+        let conn = self.readers.get();
+        let column = query.sort.column();
+
+        let (filter_sql, filter_args) = trace_filters(query);
+        let total_estimate: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM (SELECT 1 FROM traces{filter_sql} LIMIT {TOTAL_ESTIMATE_CAP})"),
+            params_from_iter(filter_args.iter()),
+            |row| row.get(0),
+        )?;
+
+        let (mut sql, mut args) = trace_filters(query);
+        sql.insert_str(
+            0,
+            &format!(
+                "SELECT {TRACE_COLUMNS}, {column} FROM traces"
+            ),
         );
-        if service.is_some() {
-            sql.push_str(" AND service_name LIKE ?4");
-        }
-        if min_duration_ms.is_some() {
-            let pos = if service.is_some() { 5 } else { 4 };
-            sql.push_str(&format!(" AND duration_ms >= ?{pos}"));
-        }
-        if max_duration_ms.is_some() {
-            let pos = match (service.is_some(), min_duration_ms.is_some()) {
-                (true,  true)  => 6,
-                (true,  false) => 5,
-                (false, true)  => 5,
-                (false, false) => 4,
-            };
-            sql.push_str(&format!(" AND duration_ms <= ?{pos}"));
+        let (cmp, dir) = match query.order {
+            SortOrder::Asc  => (">", "ASC"),
+            SortOrder::Desc => ("<", "DESC"),
+        };
+        if let Some(after) = &query.after {
+            // Keyset pagination: strictly after the last row of the previous page.
+            sql.push_str(&format!(
+                " AND ({column} {cmp} ? OR ({column} = ? AND trace_id {cmp} ?))"
+            ));
+            args.push(Box::new(after.value));
+            args.push(Box::new(after.value));
+            args.push(Box::new(after.trace_id.clone()));
         }
-        sql.push_str(" ORDER BY started_at ASC LIMIT ?3");
+        // Unbounded: rows are read until the page holds `limit` readable
+        // traces, and one more row tells whether there is a next page.
+        sql.push_str(&format!(" ORDER BY {column} {dir}, trace_id {dir}"));
 
         let mut stmt = conn.prepare(&sql)?;
-
-        // Bind positional params. rusqlite requires known-at-compile-time slice
-        // lengths, so we build a Vec<Box<dyn rusqlite::ToSql>>.
-        let service_pattern = service.map(|s| format!("%{s}%"));
-        let mut extra: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
-        if let Some(ref pat) = service_pattern { extra.push(Box::new(pat.clone())); }
-        if let Some(v) = min_duration_ms      { extra.push(Box::new(v)); }
-        if let Some(v) = max_duration_ms      { extra.push(Box::new(v)); }
-
-        let base: [&dyn rusqlite::ToSql; 3] = [&from_ns, &to_ns, &(limit as i64)];
-        let extra_refs: Vec<&dyn rusqlite::ToSql> = extra.iter().map(|b| b.as_ref()).collect();
-        let all_params: Vec<&dyn rusqlite::ToSql> =
-            base.iter().copied().chain(extra_refs).collect();
-
-        let rows = stmt.query_map(all_params.as_slice(), |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, f64>(2)?,
-                row.get::<_, i64>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, String>(5)?,
-            ))
+        let rows = stmt.query_map(params_from_iter(args.iter()), |row| {
+            let sort_value = match row.get_ref(8)? {
+                ValueRef::Real(v) => SortValue::Real(v),
+                other => SortValue::Int(other.as_i64()?),
+            };
+            Ok((trace_row(row)?, sort_value))
         })?;
+        let codec = self.codec.read().unwrap();
         let mut traces = Vec::new();
+        let mut last: Option<TraceCursor> = None;
+        let mut has_more = false;
+        let mut unreadable = 0;
         for row in rows {
-            let (trace_id, root_span_name, duration_ms, started_at, spans_json, instance_id) = row?;
-            let spans: Vec<SpanEvent> = match serde_json::from_str(&spans_json) {
-                Ok(s) => s,
-                Err(_) => continue, // skip rows from old incompatible format
-            };
-            traces.push(TraceComplete {
-                trace_id,
-                spans,
-                root_span_name,
-                duration_ms,
-                started_at: started_at as u64,
-                instance_id,
+            let (row, value) = row?;
+            if traces.len() == query.limit {
+                has_more = true;
+                break;
+            }
+            last = Some(TraceCursor {
+                sort: query.sort,
+                order: query.order,
+                value,
+                trace_id: row.0.clone(),
             });
+            match trace_from_row(row, &codec) {
+                Some(trace) => traces.push(trace),
+                None => unreadable += 1,
+            }
+        }
+        let ids: Vec<&str> = traces.iter().map(|t| t.trace_id.as_str()).collect();
+        let mut pins = select_pins(&conn, &ids)?;
+        for trace in &mut traces {
+            trace.pin = pins.remove(&trace.trace_id);
+        }
+        if unreadable > 0 {
+            tracing::warn!(
+                "{} traces are stored in an older format and were left out; run with --migrate to convert them",
+                unreadable
+            );
         }
-        Ok(traces)
+        Ok(TracePage {
+            traces,
+            next_cursor: last.filter(|_| has_more).map(|c| c.encode()),
+            total_estimate: total_estimate as u64,
+        })
     }
 
-    pub fn get_bounds(&self) -> Result<Option<TraceBounds>> {
+    /// The stored trace with this ID, if any.
+    pub fn get_trace(&self, trace_id: &str) -> Result<Option<TraceComplete>> {
+        let conn = self.readers.get();
+        let row = conn
+            .query_row(
+                &format!("SELECT {TRACE_COLUMNS} FROM traces WHERE trace_id = ?1"),
+                params![trace_id],
+                trace_row,
+            )
+            .optional()?;
+        Ok(row.and_then(|row| trace_from_row(row, &self.codec.read().unwrap())))
+    }
+
+    pub fn has_trace(&self, trace_id: &str) -> Result<bool> {
+        let conn = self.readers.get();
+        Ok(conn.query_row("SELECT EXISTS (SELECT 1 FROM traces WHERE trace_id = ?1)", params![trace_id], |row| {
+            row.get(0)
+        })?)
+    }
+
+    /// Pin `trace_id`, or update the note and label of its pin. The trace
+    /// need not be persisted yet.
+    pub fn pin_trace(&self, trace_id: &str, note: &str, label: &str, now: u64) -> Result<TracePin> {
+        let conn = self.conn.lock().unwrap();
+        let pinned_at: i64 = conn.query_row(
+            "INSERT INTO trace_pins (trace_id, note, label, pinned_at) VALUES (?1, ?2, ?3, ?4) \
+             ON CONFLICT(trace_id) DO UPDATE SET note = excluded.note, label = excluded.label \
+             RETURNING pinned_at",
+            params![trace_id, note, label, now as i64],
+            |row| row.get(0),
+        )?;
+        Ok(TracePin { note: note.to_string(), label: label.to_string(), pinned_at: pinned_at as u64 })
+    }
+
+    /// Remove the pin of `trace_id`; `false` if it was not pinned.
+    pub fn unpin_trace(&self, trace_id: &str) -> Result<bool> {
         let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM trace_pins WHERE trace_id = ?1", params![trace_id])? > 0)
+    }
+
+    pub fn get_pin(&self, trace_id: &str) -> Result<Option<TracePin>> {
+        let conn = self.readers.get();
+        Ok(select_pins(&conn, &[trace_id])?.remove(trace_id))
+    }
+
+    /// Every pin, most recent first, optionally only those with `label`.
+    pub fn list_pins(&self, label: Option<&str>) -> Result<Vec<PinnedTrace>> {
+        let conn = self.readers.get();
+        let mut stmt = conn.prepare(
+            "SELECT p.trace_id, p.note, p.label, p.pinned_at, t.root_span_name, t.started_at, t.duration_ms \
+             FROM trace_pins p LEFT JOIN traces t ON t.trace_id = p.trace_id \
+             WHERE ?1 IS NULL OR p.label = ?1 \
+             ORDER BY p.pinned_at DESC, p.trace_id",
+        )?;
+        let rows = stmt.query_map(params![label], |row| {
+            Ok(PinnedTrace {
+                trace_id: row.get(0)?,
+                pin: TracePin {
+                    note: row.get(1)?,
+                    label: row.get(2)?,
+                    pinned_at: row.get::<_, i64>(3)? as u64,
+                },
+                root_span_name: row.get(4)?,
+                started_at: row.get::<_, Option<i64>>(5)?.map(|t| t as u64),
+                duration_ms: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn get_bounds(&self) -> Result<Option<TraceBounds>> {
+        let conn = self.readers.get();
         let mut stmt =
             conn.prepare("SELECT MIN(started_at), MAX(started_at), COUNT(*) FROM traces")?;
         let (min, max, count): (Option<i64>, Option<i64>, i64) =
@@ -167,14 +617,1764 @@ impl Db {
         }
     }
 
-    /// Delete all traces whose `started_at` is older than `older_than_ns` (nanoseconds).
-    /// Returns the number of rows deleted.
-    pub fn prune(&self, older_than_ns: i64) -> Result<usize> {
+    /// Convert the rows whose `spans_json` no longer decodes, written by
+    /// older versions, to the current span format and recompute everything
+    /// derived from them. Rows that cannot be converted are left untouched.
+    pub fn migrate_rows(&self) -> Result<MigrateReport> {
+        let rows: Vec<TraceRow> = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!("SELECT {TRACE_COLUMNS} FROM traces"))?;
+            let rows = stmt.query_map([], trace_row)?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let mut report = MigrateReport::default();
+        for (trace_id, _, _, _, instance_id, stored) in rows {
+            let (spans_json, spans) = {
+                let codec = self.codec.read().unwrap();
+                (codec.decompress(&stored), codec.decode(&stored))
+            };
+            if spans.is_some() {
+                continue;
+            }
+            let legacy = spans_json
+                .and_then(|json| String::from_utf8(json).ok())
+                .and_then(|json| decode_legacy_spans(&trace_id, &instance_id, &json));
+            match legacy {
+                Some(spans) => {
+                    // The stored spans do not decode, so nothing is merged.
+                    self.insert_trace(&TraceComplete::from_spans(&trace_id, spans))?;
+                    report.rewritten += 1;
+                }
+                None => {
+                    tracing::warn!("Trace {} could not be converted and was left as is", trace_id);
+                    report.unreadable += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Train a compression dictionary on the most recent traces, recompress
+    /// every stored trace with it, drop the dictionaries no longer used and
+    /// VACUUM the file to release the space. Without enough traces to train
+    /// on, rows are still compressed, with the current dictionary if any.
+    pub fn compact(&self) -> Result<CompactReport> {
+        let mut report = CompactReport { bytes_before: self.file_size()?, ..Default::default() };
+
+        let samples: Vec<Vec<u8>> = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {TRACE_COLUMNS} FROM traces ORDER BY started_at DESC LIMIT {TRAINING_SAMPLES}"
+            ))?;
+            let rows = stmt.query_map([], trace_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+            let codec = self.codec.read().unwrap();
+            rows.iter().filter_map(|row| codec.decompress(&row.5)).collect()
+        };
+        if let Some(bytes) = codec::train_dictionary(&samples) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute(
+                "INSERT INTO zstd_dicts (dict, created_at) VALUES (?1, ?2)",
+                params![bytes, now_unix_nano() as i64],
+            )?;
+            self.codec.write().unwrap().add_dictionary(conn.last_insert_rowid(), &bytes);
+        }
+
+        let ids: Vec<String> = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT trace_id FROM traces")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        // In batches, so that the writer thread is not held up for the whole run.
+        for chunk in ids.chunks(COMPACT_BATCH_SIZE) {
+            let mut conn = self.conn.lock().unwrap();
+            let codec = self.codec.read().unwrap();
+            let tx = conn.transaction()?;
+            {
+                let mut select = tx.prepare(&format!("SELECT {TRACE_COLUMNS} FROM traces WHERE trace_id = ?1"))?;
+                let mut update = tx.prepare(
+                    "UPDATE traces SET spans_json = '', spans_zstd = ?2, spans_dict = ?3, spans_size = ?4 \
+                     WHERE trace_id = ?1",
+                )?;
+                for trace_id in chunk {
+                    let Some((.., stored)) = select.query_row(params![trace_id], trace_row).optional()? else {
+                        continue; // pruned in the meantime
+                    };
+                    if stored.zstd.is_some() && stored.dictionary == codec.current_dictionary() {
+                        continue;
+                    }
+                    let Some(json) = codec.decompress(&stored) else {
+                        report.unreadable += 1;
+                        continue;
+                    };
+                    let spans = codec.compress(&json)?;
+                    update.execute(params![trace_id, spans.zstd, spans.dictionary, spans.raw_size as i64])?;
+                    report.rewritten += 1;
+                }
+            }
+            tx.commit()?;
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut codec = self.codec.write().unwrap();
+        conn.execute(
+            "DELETE FROM zstd_dicts WHERE dict_id IS NOT ?1 \
+             AND dict_id NOT IN (SELECT spans_dict FROM traces WHERE spans_dict IS NOT NULL)",
+            params![codec.current_dictionary()],
+        )?;
+        let used: Vec<i64> = {
+            let mut stmt = conn.prepare("SELECT dict_id FROM zstd_dicts")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        codec.retain_dictionaries(&used);
+        report.dictionary = codec.current_dictionary();
+        drop(codec);
+        conn.execute_batch("VACUUM")?;
+        drop(conn);
+        report.bytes_after = self.file_size()?;
+        Ok(report)
+    }
+
+    /// Size and compression of the stored spans.
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        let conn = self.readers.get();
+        let (traces, compressed_traces, raw_bytes, stored_bytes): (i64, i64, i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COUNT(spans_zstd), COALESCE(SUM(spans_size), 0), \
+                    COALESCE(SUM(COALESCE(length(spans_zstd), length(CAST(spans_json AS BLOB)))), 0) \
+             FROM traces",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        drop(conn);
+        Ok(StorageStats {
+            traces: traces as u64,
+            compressed_traces: compressed_traces as u64,
+            raw_bytes: raw_bytes as u64,
+            stored_bytes: stored_bytes as u64,
+            compression_ratio: if stored_bytes == 0 { 0.0 } else { raw_bytes as f64 / stored_bytes as f64 },
+            dictionary: self.codec.read().unwrap().current_dictionary(),
+            file_bytes: self.file_size()?,
+        })
+    }
+
+    /// Size of the database file, not counting its WAL.
+    fn file_size(&self) -> Result<u64> {
+        let conn = self.readers.get();
+        let size: i64 = conn.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(size as u64)
+    }
+
+    /// Delete the traces `policy` no longer keeps at `now_ns`: first those
+    /// past their retention, then, while trace data takes more than
+    /// `max_db_size_mb`, the oldest ones. Freed pages are then returned to
+    /// the file system.
+    pub fn prune(&self, policy: &RetentionPolicy, now_ns: i64) -> Result<PruneReport> {
         let conn = self.conn.lock().unwrap();
-        let n = conn.execute(
-            "DELETE FROM traces WHERE started_at < ?1",
-            params![older_than_ns],
+        let mut report = PruneReport::default();
+
+        if let Some((sql, args)) = expiry_sql(policy, now_ns) {
+            report.expired = conn.execute(&sql, params_from_iter(args.iter()))?;
+        }
+
+        if let Some(max_mb) = policy.max_db_size_mb {
+            let max_bytes = max_mb.saturating_mul(1024 * 1024) as i64;
+            // Only traces are evicted, so only they count toward the budget;
+            // the whole file is a cheap upper bound of their size.
+            while used_bytes(&conn)? > max_bytes {
+                let used = trace_bytes(&conn)?;
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM traces WHERE trace_id NOT IN (SELECT trace_id FROM trace_pins)",
+                    [],
+                    |row| row.get(0),
+                )?;
+                if used <= max_bytes || count == 0 {
+                    break;
+                }
+                // Evict about as many traces as the excess holds on average.
+                let evict = ((used - max_bytes) / (used / count).max(1) + 1).min(count);
+                report.evicted += conn.execute(
+                    "DELETE FROM traces WHERE trace_id IN \
+                     (SELECT trace_id FROM traces WHERE trace_id NOT IN (SELECT trace_id FROM trace_pins) \
+                      ORDER BY started_at LIMIT ?1)",
+                    params![evict],
+                )?;
+                delete_orphans(&conn)?;
+            }
+        }
+
+        if report.expired > 0 || report.evicted > 0 {
+            delete_orphans(&conn)?;
+            // auto_vacuum is incremental (see `MIGRATIONS`); each step of the
+            // pragma frees one page.
+            let mut stmt = conn.prepare("PRAGMA incremental_vacuum")?;
+            let mut rows = stmt.query([])?;
+            while rows.next()?.is_some() {}
+        }
+        Ok(report)
+    }
+
+    /// Store metric data points and fold them into their rollups, in the
+    /// same transaction. Points already stored (same series and timestamp)
+    /// are skipped. The percentiles of a rollup are the highest of its
+    /// points, as bucket counts are not kept. Returns the number of points
+    /// stored.
+    pub fn insert_metrics(&self, metrics: &[MetricEvent]) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut stored = 0;
+        {
+            let mut series_stmt = tx.prepare(
+                "INSERT INTO metric_series \
+                 (series_key, resource_id, service_name, metric_name, kind, unit, description, attributes_json, \
+                  first_seen, last_seen) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9) \
+                 ON CONFLICT(series_key) DO UPDATE SET \
+                  kind = excluded.kind, unit = excluded.unit, description = excluded.description, \
+                  first_seen = MIN(first_seen, excluded.first_seen), last_seen = MAX(last_seen, excluded.last_seen) \
+                 RETURNING series_id",
+            )?;
+            let mut attr_stmt = tx.prepare(
+                "INSERT OR IGNORE INTO metric_series_attributes (series_id, key, value) VALUES (?1, ?2, ?3)",
+            )?;
+            let mut raw_stmt = tx.prepare(
+                "INSERT OR IGNORE INTO metric_points \
+                 (series_id, resolution, time, samples, value_min, value_max, value_sum, value_last, last_time, \
+                  rate_sum, rate_samples, p50, p90, p99) \
+                 VALUES (?1, 0, ?2, 1, ?3, ?3, ?3, ?3, ?2, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            let mut rollup_stmt = tx.prepare(
+                "INSERT INTO metric_points \
+                 (series_id, resolution, time, samples, value_min, value_max, value_sum, value_last, last_time, \
+                  rate_sum, rate_samples, p50, p90, p99) \
+                 VALUES (?1, ?2, ?3, 1, ?4, ?4, ?4, ?4, ?5, ?6, ?7, ?8, ?9, ?10) \
+                 ON CONFLICT(series_id, resolution, time) DO UPDATE SET \
+                  samples = samples + 1, \
+                  value_min = MIN(value_min, excluded.value_min), \
+                  value_max = MAX(value_max, excluded.value_max), \
+                  value_sum = value_sum + excluded.value_sum, \
+                  value_last = CASE WHEN excluded.last_time >= last_time THEN excluded.value_last ELSE value_last END, \
+                  last_time = MAX(last_time, excluded.last_time), \
+                  rate_sum = rate_sum + excluded.rate_sum, \
+                  rate_samples = rate_samples + excluded.rate_samples, \
+                  p50 = MAX(COALESCE(p50, excluded.p50), COALESCE(excluded.p50, p50)), \
+                  p90 = MAX(COALESCE(p90, excluded.p90), COALESCE(excluded.p90, p90)), \
+                  p99 = MAX(COALESCE(p99, excluded.p99), COALESCE(excluded.p99, p99))",
+            )?;
+            let mut series_ids: HashMap<String, i64> = HashMap::new();
+            for event in metrics {
+                let Some(sample) = event.value.sample() else { continue };
+                let time = event.timestamp_unix_nano as i64;
+                let key = series_key(&event.resource_id, &event.metric_name, &event.attributes);
+                let series_id = match series_ids.get(&key) {
+                    Some(&id) => id,
+                    None => {
+                        let id: i64 = series_stmt.query_row(
+                            params![
+                                key,
+                                event.resource_id,
+                                event.service_name,
+                                event.metric_name,
+                                event.value.kind(),
+                                event.unit,
+                                event.description,
+                                serde_json::to_string(&event.attributes)?,
+                                time,
+                            ],
+                            |row| row.get(0),
+                        )?;
+                        for (attr_key, value) in &event.attributes {
+                            attr_stmt.execute(params![id, attr_key, value.to_string()])?;
+                        }
+                        series_ids.insert(key, id);
+                        id
+                    }
+                };
+                let (rate_sum, rate_samples) = sample.rate.map_or((0.0, 0), |rate| (rate, 1));
+                let (p50, p90, p99) = match sample.percentiles {
+                    Some(p) => (Some(p.p50), Some(p.p90), Some(p.p99)),
+                    None => (None, None, None),
+                };
+                if raw_stmt.execute(params![series_id, time, sample.value, rate_sum, rate_samples, p50, p90, p99])?
+                    == 0
+                {
+                    continue;
+                }
+                stored += 1;
+                for resolution in &RESOLUTIONS[1..] {
+                    let width = (*resolution * 1_000_000_000) as i64;
+                    rollup_stmt.execute(params![
+                        series_id,
+                        *resolution as i64,
+                        time - time.rem_euclid(width),
+                        sample.value,
+                        time,
+                        rate_sum,
+                        rate_samples,
+                        p50,
+                        p90,
+                        p99,
+                    ])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(stored)
+    }
+
+    /// Points of the series of `query.metric_name` matching the query,
+    /// aggregated into steps of `query.step_secs`.
+    pub fn query_metrics(&self, query: &MetricQuery) -> Result<MetricQueryResult> {
+        let conn = self.readers.get();
+
+        let mut sql = String::from(
+            "SELECT series_id, service_name, metric_name, kind, unit, description, resource_id, attributes_json \
+             FROM metric_series WHERE metric_name = ? AND last_seen >= ?",
+        );
+        let mut args: Vec<Box<dyn rusqlite::ToSql>> =
+            vec![Box::new(query.metric_name.clone()), Box::new(query.from_ns)];
+        if let Some(service) = &query.service {
+            sql.push_str(" AND service_name = ?");
+            args.push(Box::new(service.clone()));
+        }
+        if let Some(resource_id) = &query.resource_id {
+            sql.push_str(" AND resource_id = ?");
+            args.push(Box::new(resource_id.clone()));
+        }
+        for (key, value) in &query.attributes {
+            sql.push_str(
+                " AND series_id IN (SELECT series_id FROM metric_series_attributes WHERE key = ?",
+            );
+            args.push(Box::new(key.clone()));
+            if let Some(value) = value {
+                sql.push_str(" AND value = ?");
+                args.push(Box::new(value.clone()));
+            }
+            sql.push(')');
+        }
+        sql.push_str(" ORDER BY service_name, attributes_json LIMIT ?");
+        args.push(Box::new(MAX_QUERY_SERIES as i64 + 1));
+
+        let mut series: Vec<(i64, MetricSeriesPoints)> = {
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(args.iter()), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                    row.get::<_, String>(6)?,
+                    row.get::<_, String>(7)?,
+                ))
+            })?;
+            let mut series = Vec::new();
+            for row in rows {
+                let (id, service_name, metric_name, kind, unit, description, resource_id, attributes_json) = row?;
+                series.push((
+                    id,
+                    MetricSeriesPoints {
+                        service_name,
+                        metric_name,
+                        kind,
+                        unit,
+                        description,
+                        resource_id,
+                        attributes: serde_json::from_str(&attributes_json)?,
+                        points: Vec::new(),
+                    },
+                ));
+            }
+            series
+        };
+        let truncated = series.len() > MAX_QUERY_SERIES;
+        series.truncate(MAX_QUERY_SERIES);
+
+        let step = i64::try_from(query.step_secs.max(1))
+            .ok()
+            .and_then(|s| s.checked_mul(1_000_000_000))
+            .context("metric query step is too long")?;
+        let mut stmt = conn.prepare(
+            "SELECT time, samples, value_min, value_max, value_sum, value_last, last_time, \
+             rate_sum, rate_samples, p50, p90, p99 \
+             FROM metric_points WHERE series_id = ?1 AND resolution = ?2 AND time >= ?3 AND time < ?4 \
+             ORDER BY time",
         )?;
-        Ok(n)
+        for (id, series) in &mut series {
+            // Rollup rows start at their bucket, which may begin before `from`.
+            let bucket = (query.resolution * 1_000_000_000).max(1) as i64;
+            let from = query.from_ns.saturating_sub(query.from_ns.rem_euclid(bucket));
+            let mut rows = stmt.query(params![*id, query.resolution as i64, from, query.to_ns])?;
+            let mut current: Option<StepAggregate> = None;
+            while let Some(row) = rows.next()? {
+                let time: i64 = row.get(0)?;
+                let start = time - time.rem_euclid(step);
+                if current.as_ref().is_some_and(|c| c.point.time != start as u64) {
+                    series.points.extend(current.take().map(StepAggregate::finish));
+                }
+                current.get_or_insert_with(|| StepAggregate::new(start)).add(row)?;
+            }
+            series.points.extend(current.map(StepAggregate::finish));
+        }
+
+        Ok(MetricQueryResult {
+            resolution: query.resolution,
+            step: query.step_secs.max(1),
+            series: series.into_iter().map(|(_, s)| s).collect(),
+            truncated,
+        })
+    }
+
+    /// Delete metric points past the retention of their resolution, then
+    /// the series left without points. Returns the number of points deleted.
+    pub fn prune_metrics(&self, retention: &MetricRetention, now_ns: i64) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let mut deleted = 0;
+        for resolution in RESOLUTIONS {
+            if let Some(cutoff) = retention::cutoff_ns(now_ns, retention.days(resolution)) {
+                deleted += conn.execute(
+                    "DELETE FROM metric_points WHERE resolution = ?1 AND time < ?2",
+                    params![resolution as i64, cutoff],
+                )?;
+            }
+        }
+        if deleted > 0 {
+            conn.execute_batch(
+                "DELETE FROM metric_series WHERE NOT EXISTS \
+                  (SELECT 1 FROM metric_points p WHERE p.series_id = metric_series.series_id); \
+                 DELETE FROM metric_series_attributes WHERE series_id NOT IN (SELECT series_id FROM metric_series);",
+            )?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// The stored points of one step of a [`Db::query_metrics`] series.
+struct StepAggregate {
+    point: MetricPoint,
+    last_time: i64,
+    rate_sum: f64,
+    rate_samples: u64,
+}
+
+impl StepAggregate {
+    fn new(start: i64) -> Self {
+        let point = MetricPoint {
+            time: start as u64,
+            samples: 0,
+            avg: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            last: 0.0,
+            rate: None,
+            p50_max: None,
+            p90_max: None,
+            p99_max: None,
+        };
+        Self { point, last_time: i64::MIN, rate_sum: 0.0, rate_samples: 0 }
+    }
+
+    /// Add a `metric_points` row (`time, samples, value_min, value_max,
+    /// value_sum, value_last, last_time, rate_sum, rate_samples, p50, p90, p99`).
+    fn add(&mut self, row: &rusqlite::Row) -> rusqlite::Result<()> {
+        let point = &mut self.point;
+        point.samples += row.get::<_, i64>(1)? as u64;
+        point.min = point.min.min(row.get(2)?);
+        point.max = point.max.max(row.get(3)?);
+        // Summed here, divided in `finish`.
+        point.avg += row.get::<_, f64>(4)?;
+        let last_time: i64 = row.get(6)?;
+        if last_time >= self.last_time {
+            point.last = row.get(5)?;
+            self.last_time = last_time;
+        }
+        self.rate_sum += row.get::<_, f64>(7)?;
+        self.rate_samples += row.get::<_, i64>(8)? as u64;
+        for (i, stored) in [&mut point.p50_max, &mut point.p90_max, &mut point.p99_max].into_iter().enumerate() {
+            if let Some(p) = row.get::<_, Option<f64>>(9 + i)? {
+                *stored = Some(stored.map_or(p, |s| s.max(p)));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> MetricPoint {
+        self.point.avg /= self.point.samples.max(1) as f64;
+        self.point.rate = (self.rate_samples > 0).then(|| self.rate_sum / self.rate_samples as f64);
+        self.point
+    }
+}
+
+/// Append ` <keyword> started_at < ?`, or ` <keyword> 0` when kept forever.
+fn push_cutoff(sql: &mut String, args: &mut Vec<Box<dyn rusqlite::ToSql>>, keyword: &str, cutoff: Option<i64>) {
+    sql.push_str(keyword);
+    match cutoff {
+        Some(cutoff) => {
+            sql.push_str(" started_at < ?");
+            args.push(Box::new(cutoff));
+        }
+        None => sql.push_str(" 0"),
+    }
+}
+
+/// The `DELETE` of the traces past their retention at `now_ns`, with its
+/// arguments; `None` when every trace is kept forever. Pinned traces are
+/// never deleted.
+fn expiry_sql(policy: &RetentionPolicy, now_ns: i64) -> Option<(String, Vec<Box<dyn rusqlite::ToSql>>)> {
+    // CASE WHEN <rule 1> THEN started_at < <cutoff 1> ... ELSE started_at < <default cutoff> END
+    let mut sql = String::from(
+        "DELETE FROM traces WHERE started_at < ? \
+         AND trace_id NOT IN (SELECT trace_id FROM trace_pins) AND",
+    );
+    let mut args: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+    let cutoffs: Vec<Option<i64>> = policy
+        .rules
+        .iter()
+        .map(|rule| rule.days)
+        .chain([policy.default_days])
+        .map(|days| retention::cutoff_ns(now_ns, days))
+        .collect();
+    // The most recent cutoff bounds every candidate, so the index on
+    // started_at narrows the scan.
+    let latest = cutoffs.iter().flatten().max()?;
+    args.push(Box::new(*latest));
+    // SQLite rejects a CASE without WHEN.
+    if !policy.rules.is_empty() {
+        sql.push_str(" CASE");
+    }
+    for (rule, cutoff) in policy.rules.iter().zip(&cutoffs) {
+        match &rule.matches {
+            RetentionMatch::Error => sql.push_str(" WHEN error_count > 0"),
+            RetentionMatch::Service(name) => {
+                sql.push_str(" WHEN trace_id IN (SELECT trace_id FROM trace_services WHERE service_name = ?)");
+                args.push(Box::new(name.clone()));
+            }
+        }
+        push_cutoff(&mut sql, &mut args, " THEN", *cutoff);
+    }
+    if policy.rules.is_empty() {
+        push_cutoff(&mut sql, &mut args, "", cutoffs[0]);
+    } else {
+        push_cutoff(&mut sql, &mut args, " ELSE", cutoffs[policy.rules.len()]);
+        sql.push_str(" END");
+    }
+    Some((sql, args))
+}
+
+/// The tables whose rows belong to a trace, deleted along with it.
+const TRACE_TABLES: [&str; 6] =
+    ["traces", "trace_links", "trace_resources", "spans", "span_attributes", "trace_services"];
+
+/// Delete the rows of the side tables whose trace is gone.
+fn delete_orphans(conn: &Connection) -> Result<()> {
+    for table in TRACE_TABLES.into_iter().filter(|&table| table != "traces") {
+        conn.execute(&format!("DELETE FROM {table} WHERE trace_id NOT IN (SELECT trace_id FROM traces)"), [])?;
+    }
+    Ok(())
+}
+
+/// Bytes of the pages holding trace data: the tables whose rows go with
+/// their trace, and their indexes.
+fn trace_bytes(conn: &Connection) -> Result<i64> {
+    let tables = TRACE_TABLES.map(|table| format!("'{table}'")).join(", ");
+    Ok(conn.query_row(
+        &format!(
+            "SELECT COALESCE(SUM(pgsize), 0) FROM dbstat \
+             WHERE aggregate = TRUE AND name IN (SELECT name FROM sqlite_schema WHERE tbl_name IN ({tables}))"
+        ),
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+/// Bytes of the database file in use, not counting free pages.
+fn used_bytes(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row(
+        "SELECT (page_count - freelist_count) * page_size \
+         FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+/// The `WHERE` clause selecting the traces that match `query`'s filters
+/// (not its cursor), with its arguments.
+fn trace_filters(query: &TraceQuery) -> (String, Vec<Box<dyn rusqlite::ToSql>>) {
+    // Build the query dynamically based on which optional filters are set.
+    let mut sql = String::from(" WHERE started_at >= ? AND started_at <= ?");
+    let mut args: Vec<Box<dyn rusqlite::ToSql>> = vec![Box::new(query.from_ns), Box::new(query.to_ns)];
+
+    if let Some(service) = &query.service {
+        sql.push_str(" AND trace_id IN (SELECT trace_id FROM trace_services WHERE service_name LIKE ? ESCAPE '\\')");
+        args.push(Box::new(format!("%{}%", escape_like(service))));
+    }
+    if let Some(v) = query.min_duration_ms {
+        sql.push_str(" AND duration_ms >= ?");
+        args.push(Box::new(v));
+    }
+    if let Some(v) = query.max_duration_ms {
+        sql.push_str(" AND duration_ms <= ?");
+        args.push(Box::new(v));
+    }
+    match query.has_error {
+        Some(true)  => sql.push_str(" AND error_count > 0"),
+        Some(false) => sql.push_str(" AND error_count = 0"),
+        None => {}
+    }
+    if let Some(v) = query.min_spans {
+        sql.push_str(" AND span_count >= ?");
+        args.push(Box::new(v as i64));
+    }
+    let involves = [
+        ("service_name", &query.involves_service),
+        ("instance_id",  &query.involves_instance),
+    ];
+    for (column, value) in involves {
+        let Some(v) = value else { continue };
+        match query.involves_match {
+            MatchMode::Exact => {
+                sql.push_str(&format!(
+                    " AND trace_id IN (SELECT trace_id FROM trace_services WHERE {column} = ?)"
+                ));
+                args.push(Box::new(v.clone()));
+            }
+            MatchMode::Substring => {
+                sql.push_str(&format!(
+                    " AND trace_id IN (SELECT trace_id FROM trace_services WHERE {column} LIKE ? ESCAPE '\\')"
+                ));
+                args.push(Box::new(format!("%{}%", escape_like(v))));
+            }
+        }
+    }
+    // Resource filters match traces with at least one span from a matching resource.
+    let resource_filters = [
+        ("service_version", &query.version),
+        ("environment",     &query.environment),
+        ("host_name",       &query.host),
+        ("k8s_pod_name",    &query.pod),
+    ];
+    for (column, value) in resource_filters {
+        if let Some(v) = value {
+            sql.push_str(&format!(
+                " AND trace_id IN (SELECT tr.trace_id FROM trace_resources tr \
+                 JOIN resources r ON r.resource_id = tr.resource_id WHERE r.{column} = ?)"
+            ));
+            args.push(Box::new(v.clone()));
+        }
+    }
+    if !query.span.is_empty() {
+        let span = &query.span;
+        sql.push_str(" AND trace_id IN (SELECT s.trace_id FROM spans s WHERE 1 = 1");
+        if let Some(v) = &span.name {
+            sql.push_str(" AND s.name = ?");
+            args.push(Box::new(v.clone()));
+        }
+        if let Some(v) = &span.target {
+            sql.push_str(" AND s.target LIKE ? ESCAPE '\\'");
+            args.push(Box::new(format!("{}%", escape_like(v))));
+        }
+        if let Some(v) = &span.status {
+            sql.push_str(" AND s.status = ?");
+            args.push(Box::new(v.clone()));
+        }
+        if let Some(v) = span.min_duration_ms {
+            sql.push_str(" AND s.duration_ms >= ?");
+            args.push(Box::new(v));
+        }
+        if let Some(v) = span.max_duration_ms {
+            sql.push_str(" AND s.duration_ms <= ?");
+            args.push(Box::new(v));
+        }
+        for (key, value) in &span.attributes {
+            sql.push_str(
+                " AND EXISTS (SELECT 1 FROM span_attributes a \
+                 WHERE a.trace_id = s.trace_id AND a.span_id = s.span_id AND a.key = ?",
+            );
+            args.push(Box::new(key.clone()));
+            if let Some(value) = value {
+                sql.push_str(" AND a.value = ?");
+                args.push(Box::new(value.clone()));
+            }
+            sql.push(')');
+        }
+        sql.push(')');
+    }
+    (sql, args)
+}
+
+/// Columns read into a [`TraceRow`] by [`trace_row`].
+const TRACE_COLUMNS: &str =
+    "trace_id, root_span_name, duration_ms, started_at, instance_id, spans_json, spans_zstd, spans_dict";
+
+/// `(trace_id, root_span_name, duration_ms, started_at, instance_id, spans)`
+type TraceRow = (String, String, f64, i64, String, StoredSpans);
+
+fn trace_row(row: &rusqlite::Row) -> rusqlite::Result<TraceRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        StoredSpans { json: row.get(5)?, zstd: row.get(6)?, dictionary: row.get(7)? },
+    ))
+}
+
+/// Decode a `traces` row; `None` if its spans are in an incompatible format.
+fn trace_from_row(row: TraceRow, codec: &SpanCodec) -> Option<TraceComplete> {
+    let (trace_id, root_span_name, duration_ms, started_at, instance_id, stored) = row;
+    let spans = codec.decode(&stored)?;
+    let linked_trace_ids = linked_trace_ids(&trace_id, &spans);
+    let stats = TraceStats::of(&spans);
+    Some(TraceComplete {
+        trace_id,
+        spans,
+        root_span_name,
+        duration_ms,
+        started_at: started_at as u64,
+        instance_id,
+        linked_trace_ids,
+        stats,
+        pin: None,
+    })
+}
+
+/// The pins of those of `trace_ids` that are pinned.
+fn select_pins(conn: &Connection, trace_ids: &[&str]) -> Result<HashMap<String, TracePin>> {
+    if trace_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let placeholders = vec!["?"; trace_ids.len()].join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT trace_id, note, label, pinned_at FROM trace_pins WHERE trace_id IN ({placeholders})"
+    ))?;
+    let rows = stmt.query_map(params_from_iter(trace_ids), |row| {
+        Ok((
+            row.get(0)?,
+            TracePin { note: row.get(1)?, label: row.get(2)?, pinned_at: row.get::<_, i64>(3)? as u64 },
+        ))
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Write `trace` in `tx`, merged with the spans already stored for it.
+fn write_trace(tx: &rusqlite::Transaction, codec: &SpanCodec, trace: &TraceComplete) -> Result<TraceComplete> {
+    let existing: Option<TraceRow> = tx
+        .query_row(&format!("SELECT {TRACE_COLUMNS} FROM traces WHERE trace_id = ?1"), params![trace.trace_id], trace_row)
+        .optional()?;
+    let stored: Option<Vec<SpanEvent>> = existing.and_then(|row| codec.decode(&row.5));
+    let trace = match stored {
+        Some(stored) => {
+            let mut by_id: HashMap<String, SpanEvent> =
+                stored.into_iter().map(|s| (s.span_id.clone(), s)).collect();
+            for span in &trace.spans {
+                by_id.insert(span.span_id.clone(), span.clone());
+            }
+            TraceComplete::from_spans(&trace.trace_id, by_id.into_values().collect())
+        }
+        None => trace.clone(),
+    };
+    let spans = codec.encode(&trace.spans)?;
+    tx.execute(
+        "INSERT OR REPLACE INTO traces \
+         (trace_id, root_span_name, duration_ms, started_at, spans_json, spans_zstd, spans_dict, spans_size, \
+          service_name, instance_id, span_count, error_count, service_count, services_json, max_depth) \
+         VALUES (?1, ?2, ?3, ?4, '', ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            trace.trace_id,
+            trace.root_span_name,
+            trace.duration_ms,
+            trace.started_at as i64,
+            spans.zstd,
+            spans.dictionary,
+            spans.raw_size as i64,
+            trace.root_service_name(),
+            trace.instance_id,
+            trace.stats.span_count as i64,
+            trace.stats.error_count as i64,
+            trace.stats.services.len() as i64,
+            serde_json::to_string(&trace.stats.services)?,
+            trace.stats.max_depth as i64,
+        ],
+    )?;
+    tx.execute("DELETE FROM trace_links WHERE trace_id = ?1", params![trace.trace_id])?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO trace_links (trace_id, span_id, linked_trace_id, linked_span_id) \
+             VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for span in &trace.spans {
+            for link in &span.links {
+                stmt.execute(params![trace.trace_id, span.span_id, link.trace_id, link.span_id])?;
+            }
+        }
+    }
+    insert_spans(tx, &trace)?;
+    tx.execute("DELETE FROM trace_resources WHERE trace_id = ?1", params![trace.trace_id])?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR IGNORE INTO trace_resources (trace_id, resource_id) VALUES (?1, ?2)",
+        )?;
+        for span in trace.spans.iter().filter(|s| !s.resource_id.is_empty()) {
+            stmt.execute(params![trace.trace_id, span.resource_id])?;
+        }
+    }
+    Ok(trace)
+}
+
+/// Replace the rows of `trace` in the `spans`, `span_attributes` and
+/// `trace_services` tables.
+fn insert_spans(tx: &rusqlite::Transaction, trace: &TraceComplete) -> Result<()> {
+    tx.execute("DELETE FROM spans WHERE trace_id = ?1", params![trace.trace_id])?;
+    tx.execute("DELETE FROM span_attributes WHERE trace_id = ?1", params![trace.trace_id])?;
+    tx.execute("DELETE FROM trace_services WHERE trace_id = ?1", params![trace.trace_id])?;
+    let mut span_stmt = tx.prepare(
+        "INSERT OR REPLACE INTO spans \
+         (trace_id, span_id, parent_span_id, name, target, service_name, kind, status, start_time, duration_ms) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    )?;
+    let mut attr_stmt = tx.prepare(
+        "INSERT OR REPLACE INTO span_attributes (trace_id, span_id, key, position, value) \
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    let mut service_stmt = tx.prepare(
+        "INSERT OR IGNORE INTO trace_services (trace_id, service_name, instance_id) VALUES (?1, ?2, ?3)",
+    )?;
+    for span in &trace.spans {
+        span_stmt.execute(params![
+            trace.trace_id,
+            span.span_id,
+            span.parent_span_id,
+            span.name,
+            span.target,
+            span.service_name,
+            span.kind,
+            span.status,
+            span.start_time_unix_nano as i64,
+            span.duration_ms,
+        ])?;
+        for (position, (key, value)) in span.attributes.iter().enumerate() {
+            attr_stmt.execute(params![trace.trace_id, span.span_id, key, position as i64, value.to_string()])?;
+        }
+        service_stmt.execute(params![trace.trace_id, span.service_name, span.instance_id])?;
+    }
+    Ok(())
+}
+
+/// Schema version of databases written by this build, stored in
+/// `PRAGMA user_version`.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// A forward step of the schema. Databases created before the schema was
+/// versioned are at version 0 but may already contain any of these tables and
+/// columns, so every step must be idempotent.
+struct Migration {
+    description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+    /// The step adds tables or columns derived from `spans_json`, which are
+    /// then recomputed for every stored trace.
+    rebuild: bool,
+    /// The step runs in a transaction. Steps that cannot, such as a VACUUM,
+    /// must leave the database consistent after each statement.
+    transactional: bool,
+}
+
+/// Applying `MIGRATIONS[i]` brings a database to version `i + 1`. Only ever
+/// append to this list.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "traces",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS traces (
+                     trace_id       TEXT PRIMARY KEY,
+                     root_span_name TEXT NOT NULL,
+                     duration_ms    REAL NOT NULL,
+                     started_at     INTEGER NOT NULL,
+                     spans_json     TEXT NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_started_at ON traces(started_at);",
+            )?;
+            add_column(conn, "traces", "service_name", "TEXT NOT NULL DEFAULT ''")?;
+            add_column(conn, "traces", "instance_id", "TEXT NOT NULL DEFAULT ''")?;
+            conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_service_name ON traces(service_name);")?;
+            Ok(())
+        },
+        rebuild: false,
+        transactional: true,
+    },
+    Migration {
+        description: "span links",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS trace_links (
+                     trace_id        TEXT NOT NULL,
+                     span_id         TEXT NOT NULL,
+                     linked_trace_id TEXT NOT NULL,
+                     linked_span_id  TEXT NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_links_trace_id ON trace_links(trace_id);
+                 CREATE INDEX IF NOT EXISTS idx_links_linked_trace_id ON trace_links(linked_trace_id);",
+            )?;
+            Ok(())
+        },
+        rebuild: false,
+        transactional: true,
+    },
+    Migration {
+        description: "resources",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS resources (
+                     resource_id     TEXT PRIMARY KEY,
+                     attributes_json TEXT NOT NULL,
+                     service_name    TEXT NOT NULL DEFAULT '',
+                     service_version TEXT NOT NULL DEFAULT '',
+                     environment     TEXT NOT NULL DEFAULT '',
+                     host_name       TEXT NOT NULL DEFAULT '',
+                     k8s_pod_name    TEXT NOT NULL DEFAULT '',
+                     first_seen      INTEGER NOT NULL
+                 );
+                 CREATE TABLE IF NOT EXISTS trace_resources (
+                     trace_id    TEXT NOT NULL,
+                     resource_id TEXT NOT NULL,
+                     PRIMARY KEY (trace_id, resource_id)
+                 ) WITHOUT ROWID;
+                 CREATE INDEX IF NOT EXISTS idx_trace_resources_resource_id ON trace_resources(resource_id);",
+            )?;
+            Ok(())
+        },
+        rebuild: false,
+        transactional: true,
+    },
+    Migration {
+        description: "span index",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS spans (
+                     trace_id       TEXT NOT NULL,
+                     span_id        TEXT NOT NULL,
+                     parent_span_id TEXT,
+                     name           TEXT NOT NULL,
+                     target         TEXT NOT NULL,
+                     service_name   TEXT NOT NULL,
+                     kind           TEXT NOT NULL,
+                     status         TEXT NOT NULL,
+                     start_time     INTEGER NOT NULL,
+                     duration_ms    REAL NOT NULL,
+                     PRIMARY KEY (trace_id, span_id)
+                 ) WITHOUT ROWID;
+                 CREATE INDEX IF NOT EXISTS idx_spans_name ON spans(name);
+                 CREATE INDEX IF NOT EXISTS idx_spans_target ON spans(target);
+                 CREATE TABLE IF NOT EXISTS span_attributes (
+                     trace_id TEXT NOT NULL,
+                     span_id  TEXT NOT NULL,
+                     key      TEXT NOT NULL,
+                     value    TEXT NOT NULL,
+                     PRIMARY KEY (trace_id, span_id, key)
+                 ) WITHOUT ROWID;
+                 CREATE INDEX IF NOT EXISTS idx_span_attributes_key_value ON span_attributes(key, value);",
+            )?;
+            Ok(())
+        },
+        rebuild: true,
+        transactional: true,
+    },
+    Migration {
+        description: "derived trace statistics",
+        apply: |conn| {
+            add_column(conn, "traces", "span_count", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column(conn, "traces", "error_count", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column(conn, "traces", "service_count", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column(conn, "traces", "services_json", "TEXT NOT NULL DEFAULT '[]'")?;
+            add_column(conn, "traces", "max_depth", "INTEGER NOT NULL DEFAULT 0")?;
+            conn.execute_batch(
+                "CREATE INDEX IF NOT EXISTS idx_duration_ms ON traces(duration_ms);
+                 CREATE INDEX IF NOT EXISTS idx_span_count ON traces(span_count);
+                 CREATE INDEX IF NOT EXISTS idx_error_count ON traces(error_count);
+                 CREATE INDEX IF NOT EXISTS idx_max_depth ON traces(max_depth);",
+            )?;
+            Ok(())
+        },
+        rebuild: true,
+        transactional: true,
+    },
+    Migration {
+        description: "trace services",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS trace_services (
+                     trace_id     TEXT NOT NULL,
+                     service_name TEXT NOT NULL,
+                     instance_id  TEXT NOT NULL,
+                     PRIMARY KEY (trace_id, service_name, instance_id)
+                 ) WITHOUT ROWID;
+                 CREATE INDEX IF NOT EXISTS idx_trace_services_service_name ON trace_services(service_name);
+                 CREATE INDEX IF NOT EXISTS idx_trace_services_instance_id ON trace_services(instance_id);
+                 CREATE INDEX IF NOT EXISTS idx_spans_service_name ON spans(service_name);",
+            )?;
+            Ok(())
+        },
+        rebuild: true,
+        transactional: true,
+    },
+    Migration {
+        description: "compressed spans",
+        apply: |conn| {
+            add_column(conn, "traces", "spans_zstd", "BLOB")?;
+            add_column(conn, "traces", "spans_dict", "INTEGER")?;
+            if add_column(conn, "traces", "spans_size", "INTEGER NOT NULL DEFAULT 0")? {
+                conn.execute("UPDATE traces SET spans_size = length(CAST(spans_json AS BLOB))", [])?;
+            }
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS zstd_dicts (
+                     dict_id    INTEGER PRIMARY KEY,
+                     dict       BLOB NOT NULL,
+                     created_at INTEGER NOT NULL
+                 );",
+            )?;
+            Ok(())
+        },
+        rebuild: false,
+        transactional: true,
+    },
+    Migration {
+        description: "trace pins",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS trace_pins (
+                     trace_id  TEXT PRIMARY KEY,
+                     note      TEXT NOT NULL,
+                     label     TEXT NOT NULL,
+                     pinned_at INTEGER NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_trace_pins_label ON trace_pins(label);",
+            )?;
+            Ok(())
+        },
+        rebuild: false,
+        transactional: true,
+    },
+    Migration {
+        description: "metrics",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS metric_series (
+                     series_id       INTEGER PRIMARY KEY,
+                     series_key      TEXT NOT NULL UNIQUE,
+                     resource_id     TEXT NOT NULL,
+                     service_name    TEXT NOT NULL,
+                     metric_name     TEXT NOT NULL,
+                     kind            TEXT NOT NULL,
+                     unit            TEXT NOT NULL,
+                     description     TEXT NOT NULL,
+                     attributes_json TEXT NOT NULL,
+                     first_seen      INTEGER NOT NULL,
+                     last_seen       INTEGER NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_metric_series_name ON metric_series(metric_name, service_name);
+                 CREATE TABLE IF NOT EXISTS metric_series_attributes (
+                     series_id INTEGER NOT NULL,
+                     key       TEXT NOT NULL,
+                     value     TEXT NOT NULL,
+                     PRIMARY KEY (series_id, key)
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_metric_series_attributes_key_value
+                     ON metric_series_attributes(key, value);
+                 -- Raw points (resolution 0) and rollups (seconds per point),
+                 -- all stored as aggregates of the raw points they cover.
+                 CREATE TABLE IF NOT EXISTS metric_points (
+                     series_id    INTEGER NOT NULL,
+                     resolution   INTEGER NOT NULL,
+                     time         INTEGER NOT NULL,
+                     samples      INTEGER NOT NULL,
+                     value_min    REAL NOT NULL,
+                     value_max    REAL NOT NULL,
+                     value_sum    REAL NOT NULL,
+                     value_last   REAL NOT NULL,
+                     last_time    INTEGER NOT NULL,
+                     rate_sum     REAL NOT NULL,
+                     rate_samples INTEGER NOT NULL,
+                     p50          REAL,
+                     p90          REAL,
+                     p99          REAL,
+                     PRIMARY KEY (series_id, resolution, time)
+                 ) WITHOUT ROWID;
+                 CREATE INDEX IF NOT EXISTS idx_metric_points_resolution_time ON metric_points(resolution, time);",
+            )?;
+            Ok(())
+        },
+        rebuild: false,
+        transactional: true,
+    },
+    Migration {
+        description: "span attribute positions",
+        apply: |conn| {
+            // Attribute keys may repeat within a span; the position keeps
+            // every occurrence. The primary key changes, so the table is
+            // recreated and refilled by the rebuild.
+            let has_position: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info('span_attributes') WHERE name = 'position')",
+                [],
+                |row| row.get(0),
+            )?;
+            if !has_position {
+                conn.execute_batch(
+                    "DROP TABLE IF EXISTS span_attributes;
+                     CREATE TABLE span_attributes (
+                         trace_id TEXT NOT NULL,
+                         span_id  TEXT NOT NULL,
+                         key      TEXT NOT NULL,
+                         position INTEGER NOT NULL,
+                         value    TEXT NOT NULL,
+                         PRIMARY KEY (trace_id, span_id, key, position)
+                     ) WITHOUT ROWID;
+                     CREATE INDEX IF NOT EXISTS idx_span_attributes_key_value ON span_attributes(key, value);",
+                )?;
+            }
+            Ok(())
+        },
+        rebuild: true,
+        transactional: true,
+    },
+    Migration {
+        description: "incremental auto-vacuum",
+        apply: |conn| {
+            // New databases have no tables yet when this is set; existing
+            // ones only switch on a VACUUM, so that `prune` can shrink them.
+            conn.execute_batch("PRAGMA auto_vacuum=INCREMENTAL;")?;
+            let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+            if auto_vacuum != 2 {
+                let has_traces: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM traces)", [], |row| row.get(0))?;
+                if has_traces {
+                    tracing::info!("Switching the database to incremental auto-vacuum; this rewrites the file once");
+                }
+                conn.execute_batch("VACUUM")?;
+            }
+            Ok(())
+        },
+        rebuild: false,
+        transactional: false,
+    },
+];
+
+/// Bring the schema up to [`SCHEMA_VERSION`], one transaction per step
+/// unless the step cannot run in one.
+/// Returns whether the derived tables and columns must be rebuilt.
+fn migrate(conn: &mut Connection) -> Result<bool> {
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "the database has schema version {version} but this build only supports up to \
+             {SCHEMA_VERSION}; upgrade otel-ui-backend or use another --db-path"
+        );
+    }
+    let mut rebuild = false;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let failed = || format!("schema migration {} ({}) failed", i + 1, migration.description);
+        if migration.transactional {
+            let tx = conn.transaction()?;
+            (migration.apply)(&tx).with_context(failed)?;
+            tx.pragma_update(None, "user_version", i as u32 + 1)?;
+            tx.commit()?;
+        } else {
+            (migration.apply)(conn).with_context(failed)?;
+            conn.pragma_update(None, "user_version", i as u32 + 1)?;
+        }
+        rebuild |= migration.rebuild;
+    }
+    if version < SCHEMA_VERSION {
+        tracing::info!("Migrated the database from schema version {} to {}", version, SCHEMA_VERSION);
+    }
+    Ok(rebuild)
+}
+
+/// The compression dictionaries stored in the database.
+fn load_codec(conn: &Connection) -> Result<SpanCodec> {
+    let mut codec = SpanCodec::default();
+    let mut stmt = conn.prepare("SELECT dict_id, dict FROM zstd_dicts")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?;
+    for row in rows {
+        let (id, bytes) = row?;
+        codec.add_dictionary(id, &bytes);
+    }
+    Ok(codec)
+}
+
+/// Add `column` to `table` unless it already exists. Returns `true` if added.
+fn add_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<bool> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"), [])?;
+    }
+    Ok(!exists)
+}
+
+/// Recompute the derived columns and the span-level tables of every stored
+/// trace from its spans.
+fn rebuild_derived(conn: &mut Connection, codec: &SpanCodec) -> Result<()> {
+    let tx = conn.transaction()?;
+    let rows: Vec<TraceRow> = {
+        let mut stmt = tx.prepare(&format!("SELECT {TRACE_COLUMNS} FROM traces"))?;
+        let rows = stmt.query_map([], trace_row)?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    let (mut count, mut unreadable) = (0, 0);
+    {
+        let mut stmt = tx.prepare(
+            "UPDATE traces SET span_count = ?2, error_count = ?3, service_count = ?4, \
+             services_json = ?5, max_depth = ?6 WHERE trace_id = ?1",
+        )?;
+        for (trace_id, _, _, _, _, stored) in rows {
+            let Some(spans) = codec.decode(&stored) else {
+                unreadable += 1;
+                continue;
+            };
+            let trace = TraceComplete::from_spans(&trace_id, spans);
+            stmt.execute(params![
+                trace_id,
+                trace.stats.span_count as i64,
+                trace.stats.error_count as i64,
+                trace.stats.services.len() as i64,
+                serde_json::to_string(&trace.stats.services)?,
+                trace.stats.max_depth as i64,
+            ])?;
+            insert_spans(&tx, &trace)?;
+            count += 1;
+        }
+    }
+    tx.commit()?;
+    if count > 0 {
+        tracing::info!("Indexed the spans of {} stored traces", count);
+    }
+    if unreadable > 0 {
+        tracing::warn!(
+            "{} stored traces are in an older format and were not indexed; run with --migrate to convert them",
+            unreadable
+        );
+    }
+    Ok(())
+}
+
+/// Traces sampled to train a compression dictionary.
+const TRAINING_SAMPLES: usize = 5_000;
+/// Traces recompressed per transaction by [`Db::compact`].
+const COMPACT_BATCH_SIZE: usize = 500;
+
+/// Outcome of [`Db::compact`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactReport {
+    /// Traces recompressed.
+    pub rewritten: usize,
+    /// Traces whose spans could not be decompressed, left as they were.
+    pub unreadable: usize,
+    /// Dictionary traces are now compressed with.
+    pub dictionary: Option<i64>,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Span storage figures, as served by `/api/db/stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageStats {
+    pub traces: u64,
+    /// Traces whose spans are stored compressed.
+    pub compressed_traces: u64,
+    /// Size of the span lists as JSON.
+    pub raw_bytes: u64,
+    /// Size of the span lists as stored.
+    pub stored_bytes: u64,
+    /// `raw_bytes / stored_bytes`.
+    pub compression_ratio: f64,
+    /// Dictionary new traces are compressed with, once `--compact` trained one.
+    pub dictionary: Option<i64>,
+    /// Size of the database file, not counting its WAL.
+    pub file_bytes: u64,
+}
+
+/// Outcome of [`Db::prune`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneReport {
+    /// Traces past their retention.
+    pub expired: usize,
+    /// Traces evicted to bring the database under its size limit.
+    pub evicted: usize,
+}
+
+/// Outcome of [`Db::migrate_rows`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MigrateReport {
+    /// Rows converted to the current span format.
+    pub rewritten: usize,
+    /// Rows that could not be converted and were left as they were.
+    pub unreadable: usize,
+}
+
+/// Decode `spans_json` written by an older version: fields added since are
+/// filled with defaults (`trace_id` from the row, `instance_id` from the
+/// row's column), timestamps may be strings and attributes may be a
+/// `{key: value}` object instead of `[key, value]` pairs.
+fn decode_legacy_spans(trace_id: &str, instance_id: &str, spans_json: &str) -> Option<Vec<SpanEvent>> {
+    use serde_json::{Map, Value};
+
+    fn attributes(value: Option<Value>) -> Value {
+        match value {
+            Some(Value::Object(map)) => Value::Array(
+                map.into_iter()
+                    .map(|(k, v)| {
+                        let v = match v {
+                            Value::String(_) => v,
+                            Value::Null => Value::String(String::new()),
+                            other => Value::String(other.to_string()),
+                        };
+                        Value::Array(vec![Value::String(k), v])
+                    })
+                    .collect(),
+            ),
+            Some(Value::Null) | None => Value::Array(Vec::new()),
+            Some(other) => other,
+        }
+    }
+    fn nanos(value: Option<&Value>) -> Option<u64> {
+        match value {
+            Some(Value::String(s)) => s.parse().ok(),
+            Some(v) => v.as_u64(),
+            None => Some(0),
+        }
+    }
+    fn records(value: Option<Value>) -> Value {
+        match value {
+            Some(Value::Array(items)) => Value::Array(
+                items
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::Object(mut record) => {
+                            let attrs = attributes(record.remove("attributes"));
+                            record.insert("attributes".into(), attrs);
+                            Value::Object(record)
+                        }
+                        other => other,
+                    })
+                    .collect(),
+            ),
+            _ => Value::Array(Vec::new()),
+        }
+    }
+
+    let Value::Array(items) = serde_json::from_str(spans_json).ok()? else {
+        return None;
+    };
+    items
+        .into_iter()
+        .map(|item| {
+            let Value::Object(mut span) = item else { return None };
+            span.get("span_id")?;
+            let start = nanos(span.get("start_time_unix_nano"))?;
+            let end = nanos(span.get("end_time_unix_nano"))?.max(start);
+            let mut out = Map::new();
+            out.insert("trace_id".into(), trace_id.into());
+            out.insert("parent_span_id".into(), Value::Null);
+            for key in ["name", "target", "service_name"] {
+                out.insert(key.into(), "".into());
+            }
+            out.insert("instance_id".into(), instance_id.into());
+            out.insert("status".into(), "unset".into());
+            out.insert("duration_ms".into(), ((end - start) as f64 / 1_000_000.0).into());
+            let attrs = attributes(span.remove("attributes"));
+            let events = records(span.remove("events"));
+            let links = records(span.remove("links"));
+            for (key, value) in span {
+                if !value.is_null() || key == "parent_span_id" {
+                    out.insert(key, value);
+                }
+            }
+            out.insert("start_time_unix_nano".into(), start.into());
+            out.insert("end_time_unix_nano".into(), end.into());
+            out.insert("attributes".into(), attrs);
+            out.insert("events".into(), events);
+            out.insert("links".into(), links);
+            if out.get("parent_span_id").and_then(Value::as_str) == Some("") {
+                out.insert("parent_span_id".into(), Value::Null);
+            }
+            if out.get("status").and_then(Value::as_str) == Some("") {
+                out.insert("status".into(), "unset".into());
+            }
+            serde_json::from_value(Value::Object(out)).ok()
+        })
+        .collect()
+}
+
+/// Escape `%`, `_` and `\` for a `LIKE ... ESCAPE '\'` pattern.
+fn escape_like(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retention::RetentionRule;
+    use crate::state::{AttrValue, MetricValue};
+    use crate::testing::{span, trace, TempDb, TempPath};
+
+    const DAY: i64 = 24 * 3_600 * 1_000_000_000;
+    const NOW: i64 = 100 * DAY;
+
+    fn policy(rules: &[&str], default_days: u64) -> RetentionPolicy {
+        RetentionPolicy {
+            rules: rules.iter().map(|r| r.parse::<RetentionRule>().unwrap()).collect(),
+            default_days,
+            ..RetentionPolicy::default()
+        }
+    }
+
+    /// The start of a trace `days` days before `NOW`.
+    fn ago(days: i64) -> u64 {
+        (NOW - days * DAY) as u64
+    }
+
+    #[test]
+    fn expiry_sql_handles_zero_one_and_many_rules() {
+        let db = TempDb::new("expiry-sql");
+        let check = |policy: &RetentionPolicy, expected: &str, args: usize| {
+            let (sql, a) = expiry_sql(policy, NOW).unwrap();
+            let tail = sql.split(" AND").last().unwrap();
+            assert_eq!(tail, expected);
+            assert_eq!(a.len(), args);
+            db.conn.lock().unwrap().prepare(&sql).unwrap();
+        };
+
+        assert!(expiry_sql(&policy(&[], 0), NOW).is_none());
+        check(&policy(&[], 7), " started_at < ?", 2);
+        check(
+            &policy(&["error:30d"], 7),
+            " CASE WHEN error_count > 0 THEN started_at < ? ELSE started_at < ? END",
+            3,
+        );
+        check(
+            &policy(&["error:0d", "service=payments:14d"], 0),
+            " CASE WHEN error_count > 0 THEN 0 \
+             WHEN trace_id IN (SELECT trace_id FROM trace_services WHERE service_name = ?) THEN started_at < ? \
+             ELSE 0 END",
+            3,
+        );
+    }
+
+    #[test]
+    fn prune_applies_the_first_matching_rule() {
+        let db = TempDb::new("prune");
+        for trace in [
+            trace("error", "payments", "error", ago(10)),
+            trace("payments", "payments", "ok", ago(10)),
+            trace("old", "web", "ok", ago(10)),
+            trace("recent", "web", "ok", ago(1)),
+            trace("pinned", "web", "ok", ago(10)),
+        ] {
+            db.insert_trace(&trace).unwrap();
+        }
+        db.pin_trace("pinned", "", "", NOW as u64).unwrap();
+
+        let report = db.prune(&policy(&["error:30d", "service=payments:5d"], 3), NOW).unwrap();
+        assert_eq!(report.expired, 2);
+        for (trace_id, kept) in [("error", true), ("payments", false), ("old", false), ("recent", true), ("pinned", true)] {
+            assert_eq!(db.has_trace(trace_id).unwrap(), kept, "{trace_id}");
+        }
+    }
+
+    #[test]
+    fn prune_without_rules_uses_the_default() {
+        let db = TempDb::new("prune-default");
+        db.insert_trace(&trace("old", "web", "ok", ago(10))).unwrap();
+        db.insert_trace(&trace("recent", "web", "ok", ago(1))).unwrap();
+
+        assert_eq!(db.prune(&policy(&[], 0), NOW).unwrap().expired, 0);
+        assert_eq!(db.prune(&policy(&[], 3), NOW).unwrap().expired, 1);
+        assert!(!db.has_trace("old").unwrap());
+        assert!(db.has_trace("recent").unwrap());
+    }
+
+    /// Every page of `query`, `limit` traces at a time, as trace IDs.
+    fn all_pages(db: &Db, mut query: TraceQuery) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        loop {
+            let page = db.query_traces(&query).unwrap();
+            pages.push(page.traces.iter().map(|t| t.trace_id.clone()).collect());
+            let Some(cursor) = page.next_cursor else { return pages };
+            query.after = Some(TraceCursor::decode(&cursor, query.sort, query.order).unwrap());
+        }
+    }
+
+    #[test]
+    fn trace_pages_are_filled_past_unreadable_rows() {
+        let db = TempDb::new("pages");
+        for i in 0..10 {
+            db.insert_trace(&trace(&format!("t{i}"), "web", "ok", ago(1) + i)).unwrap();
+        }
+        // Rows in a format this build no longer reads.
+        db.conn
+            .lock()
+            .unwrap()
+            .execute_batch("UPDATE traces SET spans_zstd = NULL, spans_json = '{}' WHERE trace_id IN ('t6', 't7', 't8')")
+            .unwrap();
+
+        let pages = all_pages(&db, TraceQuery { from_ns: 0, to_ns: NOW, limit: 3, ..TraceQuery::default() });
+        assert_eq!(pages, [vec!["t0", "t1", "t2"], vec!["t3", "t4", "t5"], vec!["t9"]]);
+    }
+
+    #[test]
+    fn pages_follow_each_sort_and_order_through_ties() {
+        let db = TempDb::new("sorts");
+        // (trace_id, start, duration in µs, spans)
+        for (trace_id, start, duration_us, spans) in
+            [("a", 5, 1_500, 3), ("b", 1, 3_000, 1), ("c", 3, 1_500, 2), ("d", 3, 250, 3), ("e", 2, 10_000, 2)]
+        {
+            let start = ago(1) + start;
+            let mut root = span(trace_id, "api", "ok", start);
+            root.end_time_unix_nano = start + duration_us * 1_000;
+            let children = (1..spans).map(|i| SpanEvent {
+                span_id: format!("{:016x}", i + 1),
+                parent_span_id: Some(root.span_id.clone()),
+                end_time_unix_nano: start,
+                ..span(trace_id, "api", "ok", start)
+            });
+            let spans = std::iter::once(root.clone()).chain(children).collect();
+            db.insert_trace(&TraceComplete::from_spans(trace_id, spans)).unwrap();
+        }
+
+        for (sort, ascending) in [
+            (TraceSort::StartedAt, ["b", "e", "c", "d", "a"]),
+            (TraceSort::Duration, ["d", "a", "c", "b", "e"]),
+            (TraceSort::SpanCount, ["b", "c", "e", "a", "d"]),
+        ] {
+            for order in [SortOrder::Asc, SortOrder::Desc] {
+                let query = TraceQuery { from_ns: 0, to_ns: NOW, limit: 2, sort, order, ..TraceQuery::default() };
+                let ids: Vec<String> = all_pages(&db, query).into_iter().flatten().collect();
+                let mut expected = ascending.to_vec();
+                if order == SortOrder::Desc {
+                    expected.reverse();
+                }
+                assert_eq!(ids, expected, "{sort:?} {order:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn cursors_only_resume_the_sort_they_were_issued_for() {
+        let db = TempDb::new("cursors");
+        for i in 0..3 {
+            db.insert_trace(&trace(&format!("t{i}"), "web", "ok", ago(1) + i)).unwrap();
+        }
+        let page = db.query_traces(&TraceQuery { from_ns: 0, to_ns: NOW, limit: 2, ..TraceQuery::default() }).unwrap();
+        assert_eq!(page.total_estimate, 3);
+        let cursor = page.next_cursor.unwrap();
+
+        assert!(TraceCursor::decode(&cursor, TraceSort::StartedAt, SortOrder::Asc).is_some());
+        assert!(TraceCursor::decode(&cursor, TraceSort::StartedAt, SortOrder::Desc).is_none());
+        assert!(TraceCursor::decode(&cursor, TraceSort::Duration, SortOrder::Asc).is_none());
+        assert!(TraceCursor::decode("not a cursor", TraceSort::StartedAt, SortOrder::Asc).is_none());
+    }
+
+    #[test]
+    fn compact_round_trips_every_trace() {
+        let db = TempDb::new("compact");
+        let traces: Vec<TraceComplete> = (0..2_000)
+            .map(|i| {
+                let mut trace = trace(&format!("t{i:04}"), ["api", "web", "db"][i % 3], "ok", ago(1) + i as u64);
+                trace.spans[0].name = format!("GET /items/{}", i * 7919 % 1000);
+                trace.spans[0].attributes = vec![
+                    ("http.status_code".into(), AttrValue::Int(200 + (i % 5) as i64)),
+                    ("user.id".into(), AttrValue::String(format!("user-{}", i * 31 % 97))),
+                ];
+                trace
+            })
+            .collect();
+        db.insert_traces(&traces).unwrap();
+        // A row written before compression existed.
+        let legacy = serde_json::to_string(&traces[0].spans).unwrap();
+        db.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE traces SET spans_json = ?1, spans_zstd = NULL, spans_dict = NULL WHERE trace_id = 't0000'",
+                params![legacy],
+            )
+            .unwrap();
+        let spans = |db: &Db, trace_id: &str| {
+            serde_json::to_value(db.get_trace(trace_id).unwrap().unwrap().spans).unwrap()
+        };
+
+        let report = db.compact().unwrap();
+        assert_eq!((report.rewritten, report.unreadable), (traces.len(), 0));
+        let dictionary = report.dictionary.expect("a dictionary is trained");
+        let sql = format!("SELECT COUNT(*) FROM traces WHERE spans_zstd IS NULL OR spans_dict IS NOT {dictionary}");
+        assert_eq!(count(&db, &sql), 0);
+
+        // Read back through this codec, and through one loaded from the file.
+        let reopened = Db::open(db.path(), 1).unwrap();
+        for trace in &traces {
+            let expected = serde_json::to_value(&trace.spans).unwrap();
+            assert_eq!(spans(&db, &trace.trace_id), expected);
+            assert_eq!(spans(&reopened, &trace.trace_id), expected);
+        }
+    }
+
+    fn pragma(path: &Path, name: &str) -> i64 {
+        Connection::open(path).unwrap().query_row(&format!("PRAGMA {name}"), [], |row| row.get(0)).unwrap()
+    }
+
+    fn count(db: &Db, sql: &str) -> i64 {
+        db.conn.lock().unwrap().query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn unversioned_databases_migrate_and_rebuild_derived_tables() {
+        let path = TempPath::new("legacy");
+        let mut child = span("legacy", "db", "error", ago(1));
+        child.span_id = "0000000000000002".into();
+        child.parent_span_id = Some("0000000000000001".into());
+        child.attributes = vec![("db.system".into(), AttrValue::String("sqlite".into()))];
+        let spans_json = serde_json::to_string(&[span("legacy", "api", "ok", ago(1)), child]).unwrap();
+        // The schema as it was before it was versioned.
+        let legacy = Connection::open(&*path).unwrap();
+        legacy
+            .execute_batch(
+                "CREATE TABLE traces (
+                     trace_id       TEXT PRIMARY KEY,
+                     root_span_name TEXT NOT NULL,
+                     duration_ms    REAL NOT NULL,
+                     started_at     INTEGER NOT NULL,
+                     spans_json     TEXT NOT NULL
+                 );",
+            )
+            .unwrap();
+        legacy
+            .execute(
+                "INSERT INTO traces VALUES ('legacy', 'GET /', 1.0, ?1, ?2)",
+                params![ago(1) as i64, spans_json],
+            )
+            .unwrap();
+        drop(legacy);
+
+        let db = Db::open(&path, 1).unwrap();
+        assert_eq!(pragma(&path, "user_version"), SCHEMA_VERSION as i64);
+        let page = db.query_traces(&TraceQuery { from_ns: 0, to_ns: NOW, limit: 10, ..TraceQuery::default() }).unwrap();
+        assert_eq!(page.traces.len(), 1);
+        assert_eq!((page.traces[0].stats.span_count, page.traces[0].stats.error_count), (2, 1));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM spans"), 2);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM span_attributes WHERE key = 'db.system'"), 1);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM trace_services"), 2);
+    }
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let db = TempDb::new("idempotent");
+        db.insert_trace(&trace("t1", "web", "ok", ago(1))).unwrap();
+        // As if none had been recorded: every step runs again on the current schema.
+        db.conn.lock().unwrap().pragma_update(None, "user_version", 0).unwrap();
+        let rebuild = migrate(&mut db.conn.lock().unwrap()).unwrap();
+
+        assert!(rebuild);
+        assert_eq!(pragma(db.path(), "user_version"), SCHEMA_VERSION as i64);
+        assert!(db.has_trace("t1").unwrap());
+        // Already at the current version: nothing to do.
+        assert!(!migrate(&mut db.conn.lock().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn open_leaves_a_newer_schema_untouched() {
+        let path = TempPath::new("newer-schema");
+        Connection::open(&*path)
+            .unwrap()
+            .execute_batch(&format!("CREATE TABLE future (x); PRAGMA user_version = {};", SCHEMA_VERSION + 1))
+            .unwrap();
+
+        assert!(Db::open(&path, 1).is_err());
+        assert_eq!(pragma(&path, "user_version"), SCHEMA_VERSION as i64 + 1);
+        assert_eq!(pragma(&path, "auto_vacuum"), 0);
+    }
+
+    #[test]
+    fn migrating_switches_to_incremental_auto_vacuum() {
+        let path = TempPath::new("auto-vacuum");
+        Connection::open(&*path).unwrap().execute_batch("CREATE TABLE legacy (x);").unwrap();
+        assert_eq!(pragma(&path, "auto_vacuum"), 0);
+
+        drop(Db::open(&path, 1).unwrap());
+        assert_eq!(pragma(&path, "user_version"), SCHEMA_VERSION as i64);
+        assert_eq!(pragma(&path, "auto_vacuum"), 2);
+    }
+
+    fn size_budget(max_db_size_mb: u64) -> RetentionPolicy {
+        RetentionPolicy { max_db_size_mb: Some(max_db_size_mb), ..RetentionPolicy::default() }
+    }
+
+    #[test]
+    fn size_budget_ignores_metric_data() {
+        let db = TempDb::new("prune-metrics");
+        let points: Vec<MetricEvent> = (0..40_000)
+            .map(|i| MetricEvent {
+                service_name: "web".into(),
+                metric_name: format!("metric.{}", i % 100),
+                description: String::new(),
+                unit: String::new(),
+                timestamp_unix_nano: (NOW - DAY + i * 1_000_000_000) as u64,
+                attributes: vec![],
+                value: MetricValue::Gauge { value: i as f64 },
+                resource_id: String::new(),
+                scope_name: String::new(),
+                scope_version: String::new(),
+            })
+            .collect();
+        db.insert_metrics(&points).unwrap();
+        db.insert_trace(&trace("old", "web", "ok", ago(2))).unwrap();
+        db.insert_trace(&trace("recent", "web", "ok", ago(1))).unwrap();
+        assert!(used_bytes(&db.conn.lock().unwrap()).unwrap() > 1024 * 1024);
+
+        assert_eq!(db.prune(&size_budget(1), NOW).unwrap().evicted, 0);
+        assert!(db.has_trace("old").unwrap());
+        assert!(db.has_trace("recent").unwrap());
+    }
+
+    #[test]
+    fn size_budget_evicts_the_oldest_traces() {
+        let db = TempDb::new("prune-size");
+        // Incompressible attributes, so that the traces outgrow the budget.
+        let mut state = 1u64;
+        let mut payload = || {
+            (0..1_000)
+                .map(|_| {
+                    state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                    format!("{:x}", state >> 60)
+                })
+                .collect::<String>()
+        };
+        for age in 1..=600 {
+            let mut trace = trace(&format!("{age:04}"), "web", "ok", ago(0) - age);
+            trace.spans[0].attributes.push(("payload".into(), AttrValue::String(payload())));
+            db.insert_trace(&trace).unwrap();
+        }
+        let before = trace_bytes(&db.conn.lock().unwrap()).unwrap();
+        assert!(before > 1024 * 1024);
+
+        let report = db.prune(&size_budget(1), NOW).unwrap();
+        assert!(report.evicted > 0 && report.evicted < 600, "{report:?}");
+        assert!(trace_bytes(&db.conn.lock().unwrap()).unwrap() <= 1024 * 1024);
+        assert!(db.has_trace("0001").unwrap());
+        assert!(!db.has_trace("0600").unwrap());
     }
 }
@@ -1,18 +1,28 @@
+mod codec;
 mod db;
+mod metrics;
 mod otlp;
+mod resource;
+mod retention;
+mod snapshot;
 mod state;
+mod subscription;
+#[cfg(test)]
+mod testing;
+mod writer;
 mod ws;
 
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use clap::Parser;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use db::Db;
-use state::AppState;
+use retention::{MetricRetention, RetentionPolicy, RetentionRule};
+use state::{now_unix_nano, AppState, IngestLimits};
+use writer::{MetricWriter, TraceWriter, WriterConfig};
 
 /// OTel UI backend — receives spans via OTLP gRPC and serves a real-time
 /// trace visualisation UI over WebSockets.
@@ -23,7 +33,7 @@ struct Args {
     #[arg(long, default_value = "[::]:4317")]
     otlp_addr: String,
 
-    /// OTLP HTTP bind address (protobuf over HTTP/1.1, port 4318)
+    /// OTLP HTTP bind address (protobuf or JSON over HTTP/1.1, port 4318)
     #[arg(long, default_value = "[::]:4318")]
     otlp_http_addr: String,
 
@@ -36,16 +46,101 @@ struct Args {
     #[arg(long, env = "OTEL_UI_DB_PATH", default_value = "./otel-ui.db")]
     db_path: PathBuf,
 
-    /// Retain traces for this many days (0 = keep forever).
+    /// Retain traces for this many days (0 = keep forever), unless a
+    /// --retention-rule matching them says otherwise.
     /// Overrides the OTEL_UI_DB_RETENTION_DAYS environment variable.
     #[arg(long, env = "OTEL_UI_DB_RETENTION_DAYS", default_value_t = 7)]
     db_retention_days: u64,
 
-    /// Prune traces older than --db-retention-days and exit immediately.
+    /// Retention for the traces matching a condition, e.g. `error:30d` or
+    /// `service=payments:14d` (0d = keep forever). Repeatable; the first
+    /// matching rule applies.
+    #[arg(long = "retention-rule", env = "OTEL_UI_RETENTION_RULES", value_delimiter = ',')]
+    retention_rules: Vec<RetentionRule>,
+
+    /// Evict the oldest traces once trace data takes more than this many MB.
+    #[arg(long, env = "OTEL_UI_DB_MAX_SIZE_MB")]
+    db_max_size_mb: Option<u64>,
+
+    /// Retain raw metric points for this many days (0 = keep forever).
+    #[arg(long, env = "OTEL_UI_METRICS_RAW_RETENTION_DAYS", default_value_t = 1)]
+    metrics_raw_retention_days: u64,
+
+    /// Retain 1-minute metric rollups for this many days (0 = keep forever).
+    #[arg(long, env = "OTEL_UI_METRICS_MINUTE_RETENTION_DAYS", default_value_t = 7)]
+    metrics_minute_retention_days: u64,
+
+    /// Retain 1-hour metric rollups for this many days (0 = keep forever).
+    #[arg(long, env = "OTEL_UI_METRICS_HOUR_RETENTION_DAYS", default_value_t = 90)]
+    metrics_hour_retention_days: u64,
+
+    /// JSON file with `rules`, `default_days`, `max_db_size_mb` and `metrics`,
+    /// replacing --retention-rule, --db-retention-days, --db-max-size-mb and
+    /// the --metrics-*-retention-days flags.
+    #[arg(long, env = "OTEL_UI_RETENTION_CONFIG")]
+    retention_config: Option<PathBuf>,
+
+    /// Refuse OTLP trace exports (gRPC UNAVAILABLE / HTTP 503) once this many
+    /// traces are waiting for their root span.
+    #[arg(long, env = "OTEL_UI_MAX_IN_FLIGHT_TRACES", default_value_t = 100_000)]
+    max_in_flight_traces: usize,
+
+    /// Refuse OTLP trace exports (gRPC RESOURCE_EXHAUSTED / HTTP 429) once this
+    /// many finalized traces are waiting to be written to SQLite.
+    #[arg(long, env = "OTEL_UI_MAX_PENDING_WRITES", default_value_t = 10_000)]
+    max_pending_writes: usize,
+
+    /// Collapse the points of further series of a metric into its
+    /// `otel.metric.overflow` series once it has this many series.
+    #[arg(long, env = "OTEL_UI_MAX_SERIES_PER_METRIC", default_value_t = 2_000)]
+    max_series_per_metric: usize,
+
+    /// Collapse the points of further series into `otel.metric.overflow`
+    /// series once all metrics together have this many series.
+    #[arg(long, env = "OTEL_UI_MAX_METRIC_SERIES", default_value_t = 100_000)]
+    max_metric_series: usize,
+
+    /// Finalize a trace once this many milliseconds pass without new spans
+    /// after its root span arrived, so that spans exported later by other
+    /// services land in the same trace (0 = finalize on the root span).
+    #[arg(long, env = "OTEL_UI_TRACE_QUIESCENCE_MS", default_value_t = 5_000)]
+    trace_quiescence_ms: u64,
+
+    /// Keep this many recent spans, log records and metric points in memory
+    /// for the snapshot replayed to new WebSocket clients (0 = none).
+    #[arg(long, env = "OTEL_UI_RECENT_BUFFER_ITEMS", default_value_t = 50_000)]
+    recent_buffer_items: usize,
+
+    /// Commit finalized traces to SQLite in transactions of up to this many traces...
+    #[arg(long, env = "OTEL_UI_DB_WRITE_BATCH", default_value_t = 500)]
+    db_write_batch: usize,
+
+    /// ...or once the oldest queued trace has waited this many milliseconds.
+    #[arg(long, env = "OTEL_UI_DB_WRITE_INTERVAL_MS", default_value_t = 10)]
+    db_write_interval_ms: u64,
+
+    /// Read-only SQLite connections serving history queries.
+    #[arg(long, env = "OTEL_UI_DB_READ_CONNECTIONS", default_value_t = 4)]
+    db_read_connections: usize,
+
+    /// Prune traces past their retention, or beyond --db-max-size-mb, and exit immediately.
     #[arg(long, default_value_t = false)]
     prune: bool,
+
+    /// Migrate the database schema, convert traces stored in an older format and exit.
+    #[arg(long, default_value_t = false)]
+    migrate: bool,
+
+    /// Train a compression dictionary on the stored traces, recompress them
+    /// with it, shrink the database file and exit.
+    #[arg(long, default_value_t = false)]
+    compact: bool,
 }
 
+/// OTLP metric exports waiting to be written to SQLite at most; further
+/// exports are still broadcast, but not persisted.
+const METRIC_WRITE_QUEUE: usize = 1_024;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::registry()
@@ -58,28 +153,86 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
+    let mut retention = RetentionPolicy {
+        rules:          args.retention_rules.clone(),
+        default_days:   args.db_retention_days,
+        max_db_size_mb: args.db_max_size_mb,
+        metrics:        MetricRetention {
+            raw_days:    args.metrics_raw_retention_days,
+            minute_days: args.metrics_minute_retention_days,
+            hour_days:   args.metrics_hour_retention_days,
+        },
+    };
+    if let Some(path) = &args.retention_config {
+        retention = retention.with_file(path)?;
+    }
+
     // Open the SQLite database.
-    let db = Arc::new(Db::open(&args.db_path)?);
+    let db = Arc::new(Db::open(&args.db_path, args.db_read_connections)?);
     info!("Opened SQLite database at {:?}", args.db_path);
 
-    // --prune mode: prune old traces and exit.
+    // --migrate mode: the schema was migrated by Db::open, now convert old rows and exit.
+    if args.migrate {
+        let report = db.migrate_rows()?;
+        info!(
+            "Converted {} traces to the current format ({} could not be converted)",
+            report.rewritten, report.unreadable
+        );
+        return Ok(());
+    }
+
+    // --compact mode: recompress stored traces and exit.
+    if args.compact {
+        let report = db.compact()?;
+        info!(
+            "Recompressed {} traces ({} unreadable) with dictionary {:?}: {} → {} bytes",
+            report.rewritten, report.unreadable, report.dictionary, report.bytes_before, report.bytes_after
+        );
+        return Ok(());
+    }
+
+    // --prune mode: prune old traces and metric points and exit.
     if args.prune {
-        if args.db_retention_days == 0 {
-            info!("--db-retention-days is 0 (keep forever), nothing to prune");
+        if !retention.is_active() {
+            info!("Every trace is kept forever, nothing to prune");
         } else {
-            let cutoff_ns = retention_cutoff_ns(args.db_retention_days);
-            let pruned = db.prune(cutoff_ns)?;
+            let report = db.prune(&retention, now_unix_nano() as i64)?;
             info!(
-                "Pruned {} traces older than {} days",
-                pruned, args.db_retention_days
+                "Pruned {} expired traces and evicted {} to stay under the size limit",
+                report.expired, report.evicted
             );
         }
+        if retention.metrics.is_active() {
+            let deleted = db.prune_metrics(&retention.metrics, now_unix_nano() as i64)?;
+            info!("Pruned {} expired metric points", deleted);
+        }
         return Ok(());
     }
 
+    let (writer, written) = TraceWriter::spawn(
+        Arc::clone(&db),
+        WriterConfig {
+            capacity:       args.max_pending_writes,
+            batch_size:     args.db_write_batch.max(1),
+            flush_interval: std::time::Duration::from_millis(args.db_write_interval_ms),
+        },
+    );
     let state = Arc::new(AppState::new(
         Arc::clone(&db),
+        writer,
+        MetricWriter::spawn(Arc::clone(&db), METRIC_WRITE_QUEUE),
+        IngestLimits {
+            max_in_flight_traces:  args.max_in_flight_traces,
+            max_pending_writes:    args.max_pending_writes,
+            max_series_per_metric: args.max_series_per_metric,
+            max_metric_series:     args.max_metric_series,
+        },
+        std::time::Duration::from_millis(args.trace_quiescence_ms),
+        args.recent_buffer_items,
+        retention.clone(),
     ));
+    state.resources.extend(db.load_resources()?);
+    tokio::spawn(Arc::clone(&state).publish_written(written));
 
     // Start the OTLP gRPC receiver
     let otlp_state = state.clone();
@@ -99,35 +252,74 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    // Background task: evict stale in-flight traces
+    // Background task: finalize rooted traces once they stop receiving spans
+    if args.trace_quiescence_ms > 0 {
+        let quiescence_state = state.clone();
+        let period = std::time::Duration::from_millis((args.trace_quiescence_ms / 4).clamp(50, 1_000));
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(period);
+            loop {
+                tick.tick().await;
+                quiescence_state.finalize_quiescent_traces();
+            }
+        });
+    }
+
+    // Background task: evict stale in-flight traces and idle sum series
     let cleanup_state = state.clone();
     tokio::spawn(async move {
         let mut tick = tokio::time::interval(std::time::Duration::from_secs(30));
         loop {
             tick.tick().await;
             cleanup_state.cleanup_stale_traces(std::time::Duration::from_secs(60));
+            cleanup_state.sum_series.evict_idle(std::time::Duration::from_secs(3_600));
+            cleanup_state.metric_series.evict_idle(std::time::Duration::from_secs(3_600));
         }
     });
 
-    // Background task: prune old DB rows once per day (if retention is set).
-    if args.db_retention_days > 0 {
+    // Background task: prune metric points past the retention of their
+    // resolution, hourly.
+    if retention.metrics.is_active() {
         let db_prune = Arc::clone(&db);
-        let retention_days = args.db_retention_days;
+        let metrics = retention.metrics;
         tokio::spawn(async move {
-            // First prune on startup, then every 24 hours.
+            let mut tick = tokio::time::interval(std::time::Duration::from_secs(3_600));
+            loop {
+                tick.tick().await;
+                let db = Arc::clone(&db_prune);
+                match tokio::task::spawn_blocking(move || db.prune_metrics(&metrics, now_unix_nano() as i64)).await {
+                    Ok(Ok(deleted)) if deleted > 0 => info!("Pruned {} old metric points from DB", deleted),
+                    Ok(Err(e)) => tracing::error!("DB metric prune error: {}", e),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    // Background task: prune old DB rows (if retention is set). The size
+    // limit is checked every minute; age-based retention alone only needs a
+    // daily pass.
+    if retention.is_active() {
+        let db_prune = Arc::clone(&db);
+        let period = if retention.max_db_size_mb.is_some() { 60 } else { 86_400 };
+        tokio::spawn(async move {
+            // First prune on startup, then every period.
             loop {
-                let cutoff_ns = retention_cutoff_ns(retention_days);
                 match tokio::task::spawn_blocking({
                     let db = Arc::clone(&db_prune);
-                    move || db.prune(cutoff_ns)
+                    let retention = retention.clone();
+                    move || db.prune(&retention, now_unix_nano() as i64)
                 })
                 .await
                 {
-                    Ok(Ok(n)) if n > 0 => info!("Pruned {} old traces from DB", n),
+                    Ok(Ok(r)) if r.expired + r.evicted > 0 => info!(
+                        "Pruned {} old traces from DB, evicted {} to stay under the size limit",
+                        r.expired, r.evicted
+                    ),
                     Ok(Err(e)) => tracing::error!("DB prune error: {}", e),
                     _ => {}
                 }
-                tokio::time::sleep(std::time::Duration::from_secs(86_400)).await;
+                tokio::time::sleep(std::time::Duration::from_secs(period)).await;
             }
         });
     }
@@ -138,13 +330,3 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
-
-/// Returns the nanosecond timestamp `retention_days` days in the past.
-fn retention_cutoff_ns(retention_days: u64) -> i64 {
-    let now_ns = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_nanos() as i64;
-    let window_ns = retention_days as i64 * 24 * 3_600 * 1_000_000_000;
-    now_ns - window_ns
-}
@@ -0,0 +1,788 @@
+//! Server-side processing of metric data points.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::retention::{self, MetricRetention};
+use crate::state::{AttrValue, Attributes, MetricValue};
+
+/// Percentiles estimated from the buckets of a histogram data point, or
+/// from the quantiles of a summary.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+/// Consecutive buckets of one sign of an exponential histogram.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExponentialBuckets {
+    /// Index of the first bucket; bucket `i` covers `(base^i, base^(i+1)]`
+    /// in absolute value, where `base = 2^(2^-scale)`.
+    pub offset: i32,
+    pub bucket_counts: Vec<u64>,
+}
+
+/// A quantile reported by a summary data point.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct QuantileValue {
+    pub quantile: f64,
+    pub value: f64,
+}
+
+/// `count` observations in `(lower, upper]`. Either bound may be infinite.
+struct Bucket {
+    lower: f64,
+    upper: f64,
+    count: u64,
+}
+
+impl Percentiles {
+    /// Estimate from explicit-bounds buckets: `counts[i]` observations fell
+    /// in `(bounds[i - 1], bounds[i]]`, the first bucket being unbounded
+    /// below and the last unbounded above. `min` and `max` (NaN when
+    /// unknown) close the outer buckets and clamp the estimates. `None` when
+    /// there are no observations or the buckets do not match the bounds.
+    pub fn estimate(bounds: &[f64], counts: &[u64], min: f64, max: f64) -> Option<Self> {
+        if counts.len() != bounds.len() + 1 {
+            return None;
+        }
+        let buckets = counts.iter().enumerate().map(|(i, &count)| Bucket {
+            lower: if i == 0 { f64::NEG_INFINITY } else { bounds[i - 1] },
+            upper: bounds.get(i).copied().unwrap_or(f64::INFINITY),
+            count,
+        });
+        Self::from_buckets(&buckets.collect::<Vec<_>>(), min, max)
+    }
+
+    /// Estimate from the buckets of an exponential histogram, decoded into
+    /// value ranges: negative buckets, the zero bucket `[-zero_threshold,
+    /// zero_threshold]`, then positive buckets.
+    pub fn estimate_exponential(
+        scale: i32,
+        zero_count: u64,
+        zero_threshold: f64,
+        positive: &ExponentialBuckets,
+        negative: &ExponentialBuckets,
+        min: f64,
+        max: f64,
+    ) -> Option<Self> {
+        // 2^(index * 2^-scale), computed in one step to keep precision at
+        // large scales.
+        let bound = |index: i64| (index as f64 * (-scale as f64).exp2()).exp2();
+        let mut buckets: Vec<Bucket> = negative
+            .bucket_counts
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, &count)| {
+                let index = negative.offset as i64 + i as i64;
+                Bucket { lower: -bound(index + 1), upper: -bound(index), count }
+            })
+            .collect();
+        buckets.push(Bucket { lower: -zero_threshold, upper: zero_threshold, count: zero_count });
+        buckets.extend(positive.bucket_counts.iter().enumerate().map(|(i, &count)| {
+            let index = positive.offset as i64 + i as i64;
+            Bucket { lower: bound(index), upper: bound(index + 1), count }
+        }));
+        Self::from_buckets(&buckets, min, max)
+    }
+
+    /// Interpolate between the quantiles a summary reports. `None` unless
+    /// they bracket every percentile (SDKs usually report 0 and 1, the
+    /// minimum and maximum).
+    pub fn from_summary(quantiles: &[QuantileValue]) -> Option<Self> {
+        let mut sorted = quantiles.to_vec();
+        sorted.sort_by(|a, b| a.quantile.total_cmp(&b.quantile));
+        let at = |q: f64| -> Option<f64> {
+            let i = sorted.iter().position(|v| v.quantile >= q)?;
+            let hi = sorted[i];
+            if hi.quantile == q {
+                return Some(hi.value);
+            }
+            let lo = sorted[i.checked_sub(1)?];
+            Some(lo.value + (hi.value - lo.value) * (q - lo.quantile) / (hi.quantile - lo.quantile))
+        };
+        Some(Self { p50: at(0.50)?, p90: at(0.90)?, p99: at(0.99)? })
+    }
+
+    /// `None` when there are no observations, or more than a `u64` holds
+    /// (garbage counts from the exporter).
+    fn from_buckets(buckets: &[Bucket], min: f64, max: f64) -> Option<Self> {
+        let total = buckets.iter().try_fold(0u64, |total, b| total.checked_add(b.count))?;
+        if total == 0 {
+            return None;
+        }
+        Some(Self {
+            p50: quantile(buckets, total, min, max, 0.50)?,
+            p90: quantile(buckets, total, min, max, 0.90)?,
+            p99: quantile(buckets, total, min, max, 0.99)?,
+        })
+    }
+}
+
+/// The `q` quantile of `total` observations, interpolated linearly inside
+/// the bucket holding it.
+fn quantile(buckets: &[Bucket], total: u64, min: f64, max: f64, q: f64) -> Option<f64> {
+    let rank = q * total as f64;
+    let mut below = 0u64;
+    let mut holding = None;
+    for bucket in buckets {
+        let through = below.checked_add(bucket.count)?;
+        if bucket.count > 0 && through as f64 >= rank {
+            holding = Some(bucket);
+            break;
+        }
+        below = through;
+    }
+    let bucket = holding?;
+
+    let lower = match bucket.lower {
+        l if l.is_finite() => l,
+        _ if min.is_finite() => min,
+        // Same assumption as Prometheus: an unbounded first bucket of a
+        // positive histogram starts at zero.
+        _ => bucket.upper.min(0.0),
+    };
+    let upper = match bucket.upper {
+        u if u.is_finite() => u,
+        _ if max.is_finite() => max,
+        _ => lower,
+    };
+    let estimate = lower + (upper - lower) * ((rank - below as f64) / bucket.count as f64);
+    let estimate = if min.is_finite() { estimate.max(min) } else { estimate };
+    Some(if max.is_finite() { estimate.min(max) } else { estimate })
+}
+
+/// Data points dropped by the receiver for one metric.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DroppedPoints {
+    /// Gauge and sum points without a value.
+    pub no_value: u64,
+    /// Metrics of a type this receiver does not know; counted once per
+    /// metric since their points cannot be read.
+    pub unsupported: u64,
+}
+
+/// Why a data point was dropped.
+#[derive(Debug, Clone, Copy)]
+pub enum DropReason {
+    NoValue,
+    Unsupported,
+}
+
+/// Dropped data points per metric name, since startup.
+#[derive(Default)]
+pub struct DroppedPointsRegistry {
+    by_metric: DashMap<String, DroppedPoints>,
+}
+
+impl DroppedPointsRegistry {
+    pub fn record(&self, metric_name: &str, reason: DropReason, points: u64) {
+        let mut entry = self.by_metric.entry(metric_name.to_string()).or_default();
+        match reason {
+            DropReason::NoValue     => entry.no_value += points,
+            DropReason::Unsupported => entry.unsupported += points,
+        }
+    }
+
+    /// Sorted by metric name.
+    pub fn snapshot(&self) -> BTreeMap<String, DroppedPoints> {
+        self.by_metric.iter().map(|e| (e.key().clone(), *e.value())).collect()
+    }
+}
+
+/// How the values of a sum accumulate, as declared by the exporter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Temporality {
+    #[default]
+    Unspecified,
+    /// Each point holds the change since the previous one.
+    Delta,
+    /// Each point holds the total since `start_time_unix_nano`.
+    Cumulative,
+}
+
+impl Temporality {
+    /// From OTLP's `AggregationTemporality` enum value.
+    pub fn from_otlp(value: i32) -> Self {
+        match value {
+            1 => Self::Delta,
+            2 => Self::Cumulative,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+/// Identity of a metric series: resource, metric name and attributes, the
+/// latter in a canonical order and in their typed form, so that the key does
+/// not depend on the attr format in effect.
+pub fn series_key(resource_id: &str, metric_name: &str, attributes: &Attributes) -> String {
+    let mut attributes: Vec<_> = attributes.iter().map(|(k, v)| (k, v.typed_json())).collect();
+    attributes.sort_by(|a, b| a.0.cmp(b.0));
+    format!("{resource_id}\0{metric_name}\0{}", serde_json::to_string(&attributes).unwrap_or_default())
+}
+
+/// What a sum point changed since the previous point of its series.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SumChange {
+    /// `None` for the first point of a cumulative series, for cumulative
+    /// points not after the last one seen, and for unspecified temporality.
+    pub delta: Option<f64>,
+    /// `delta` per second.
+    pub rate: Option<f64>,
+    /// The cumulative series restarted (new start time, or a monotonic sum
+    /// went down); `delta` then counts from the restart.
+    pub reset: bool,
+}
+
+impl SumChange {
+    /// For a point of an overflow series, which mixes many series: only
+    /// delta points stand on their own.
+    pub fn untracked(temporality: Temporality, start: u64, time: u64, value: f64) -> Self {
+        if temporality != Temporality::Delta {
+            return Self::default();
+        }
+        let rate = (start > 0 && time > start).then(|| value / ((time - start) as f64 / 1e9));
+        Self { delta: Some(value), rate, reset: false }
+    }
+}
+
+struct SumState {
+    start: u64,
+    time: u64,
+    value: f64,
+    seen: Instant,
+}
+
+/// The last point of every sum series, to turn cumulative sums into deltas.
+#[derive(Default)]
+pub struct SumTracker {
+    series: DashMap<String, SumState>,
+}
+
+/// Where a point falls relative to the last one of its series.
+enum Previous {
+    /// First point of the series.
+    None,
+    /// The last point: start, time and value.
+    Point(u64, u64, f64),
+    /// Not after the last point (out of order or repeated).
+    Stale,
+}
+
+impl SumTracker {
+    pub fn observe(
+        &self,
+        key: String,
+        temporality: Temporality,
+        is_monotonic: bool,
+        start: u64,
+        time: u64,
+        value: f64,
+    ) -> SumChange {
+        let per_second = |delta: f64, since: u64| (time > since).then(|| delta / ((time - since) as f64 / 1e9));
+
+        match temporality {
+            Temporality::Delta => {
+                // A delta point covers [start, time] on its own, so it needs
+                // no state and may arrive in any order. Without a start time,
+                // the interval goes back to the previous point.
+                let since = if start > 0 {
+                    Some(start)
+                } else {
+                    match self.advance(key, start, time, value) {
+                        Previous::Point(_, prev_time, _) => Some(prev_time),
+                        Previous::None | Previous::Stale => None,
+                    }
+                };
+                SumChange { delta: Some(value), rate: since.and_then(|since| per_second(value, since)), reset: false }
+            }
+            Temporality::Cumulative => match self.advance(key, start, time, value) {
+                Previous::Point(prev_start, prev_time, prev_value) => {
+                    let restarted = start > 0 && start != prev_start;
+                    if restarted || (is_monotonic && value < prev_value) {
+                        // Counted from zero since the restart; without a start
+                        // time, the restart is somewhere after the previous point.
+                        let since = if restarted { start } else { prev_time };
+                        SumChange { delta: Some(value), rate: per_second(value, since), reset: true }
+                    } else {
+                        let delta = value - prev_value;
+                        SumChange { delta: Some(delta), rate: per_second(delta, prev_time), reset: false }
+                    }
+                }
+                // Nothing to compare against.
+                Previous::None | Previous::Stale => SumChange::default(),
+            },
+            Temporality::Unspecified => SumChange::default(),
+        }
+    }
+
+    /// Make the point the last one of its series unless a later point was
+    /// already seen, and return the point it replaced.
+    fn advance(&self, key: String, start: u64, time: u64, value: f64) -> Previous {
+        let state = SumState { start, time, value, seen: Instant::now() };
+        match self.series.entry(key) {
+            Entry::Occupied(mut e) => {
+                let prev = e.get();
+                if time <= prev.time {
+                    return Previous::Stale;
+                }
+                let prev = Previous::Point(prev.start, prev.time, prev.value);
+                e.insert(state);
+                prev
+            }
+            Entry::Vacant(e) => {
+                e.insert(state);
+                Previous::None
+            }
+        }
+    }
+
+    /// Forget the series without a point for `max_idle`.
+    pub fn evict_idle(&self, max_idle: Duration) {
+        self.series.retain(|_, state| state.seen.elapsed() < max_idle);
+    }
+}
+
+/// The only attribute of the series that points are collapsed into once
+/// their metric, or the backend, holds too many series.
+pub const OVERFLOW_ATTRIBUTE: &str = "otel.metric.overflow";
+
+/// Distinct values kept per attribute key as examples.
+const MAX_SAMPLE_VALUES: usize = 10;
+
+/// The metric a data point belongs to, as declared in the export.
+pub struct MetricInfo<'a> {
+    pub name: &'a str,
+    pub description: &'a str,
+    pub unit: &'a str,
+    pub kind: &'static str,
+}
+
+/// The series a data point was registered under.
+pub struct Admission {
+    pub attributes: Attributes,
+    pub series_key: String,
+    /// The point was collapsed into the overflow series.
+    pub overflow: bool,
+}
+
+/// Example values of an attribute key.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LabelValues {
+    pub sample_values: BTreeSet<String>,
+    /// The key had more values than the samples hold.
+    pub more: bool,
+}
+
+struct CatalogMetric {
+    description: String,
+    unit: String,
+    kind: &'static str,
+    services: BTreeSet<String>,
+    /// When each series last had a point, by series key.
+    series: HashMap<String, Instant>,
+    labels: BTreeMap<String, LabelValues>,
+    overflow_points: u64,
+    seen: Instant,
+}
+
+#[derive(Default)]
+struct Catalog {
+    metrics: HashMap<String, CatalogMetric>,
+    series: usize,
+    overflow_points: u64,
+}
+
+/// A metric of [`SeriesCatalog::snapshot`].
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricSummary {
+    pub name: String,
+    pub kind: &'static str,
+    pub unit: String,
+    pub description: String,
+    pub services: BTreeSet<String>,
+    pub series: usize,
+    pub labels: BTreeMap<String, LabelValues>,
+    /// Points collapsed into the overflow series since the metric was
+    /// first seen.
+    pub overflow_points: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SeriesCatalogSnapshot {
+    pub series: usize,
+    pub max_series: usize,
+    pub max_series_per_metric: usize,
+    /// Points collapsed into overflow series since startup.
+    pub overflow_points: u64,
+    /// Sorted by name.
+    pub metrics: Vec<MetricSummary>,
+}
+
+/// The metrics and series seen recently. Once a metric has
+/// `max_series_per_metric` series, or all metrics together `max_series`,
+/// points of further series are collapsed into one series per resource and
+/// metric whose only attribute is [`OVERFLOW_ATTRIBUTE`].
+pub struct SeriesCatalog {
+    max_series_per_metric: usize,
+    max_series: usize,
+    inner: Mutex<Catalog>,
+}
+
+impl SeriesCatalog {
+    pub fn new(max_series_per_metric: usize, max_series: usize) -> Self {
+        Self { max_series_per_metric, max_series, inner: Mutex::default() }
+    }
+
+    /// Register a data point, returning the series it belongs to: its own,
+    /// or the overflow series if there is no room for a new one.
+    pub fn admit(
+        &self,
+        resource_id: &str,
+        service_name: &str,
+        metric: &MetricInfo<'_>,
+        attributes: Attributes,
+    ) -> Admission {
+        let key = series_key(resource_id, metric.name, &attributes);
+        let now = Instant::now();
+        let mut catalog = self.inner.lock().unwrap();
+        let Catalog { metrics, series, overflow_points } = &mut *catalog;
+
+        let entry = metrics.entry(metric.name.to_string()).or_insert_with(|| CatalogMetric {
+            description: String::new(),
+            unit: String::new(),
+            kind: metric.kind,
+            services: BTreeSet::new(),
+            series: HashMap::new(),
+            labels: BTreeMap::new(),
+            overflow_points: 0,
+            seen: now,
+        });
+        entry.seen = now;
+        entry.kind = metric.kind;
+        if entry.description != metric.description {
+            entry.description = metric.description.to_string();
+        }
+        if entry.unit != metric.unit {
+            entry.unit = metric.unit.to_string();
+        }
+        if !entry.services.contains(service_name) {
+            entry.services.insert(service_name.to_string());
+        }
+
+        if let Some(seen) = entry.series.get_mut(&key) {
+            *seen = now;
+            return Admission { attributes, series_key: key, overflow: false };
+        }
+        if entry.series.len() >= self.max_series_per_metric || *series >= self.max_series {
+            if entry.overflow_points == 0 {
+                let limit = if entry.series.len() >= self.max_series_per_metric {
+                    format!("{} series per metric", self.max_series_per_metric)
+                } else {
+                    format!("{} series in total", self.max_series)
+                };
+                tracing::warn!(
+                    "Metric {} reached the limit of {}; points of new series go to its {} series",
+                    metric.name,
+                    limit,
+                    OVERFLOW_ATTRIBUTE,
+                );
+            }
+            entry.overflow_points += 1;
+            *overflow_points += 1;
+            let attributes = vec![(OVERFLOW_ATTRIBUTE.to_string(), AttrValue::Bool(true))];
+            return Admission {
+                series_key: series_key(resource_id, metric.name, &attributes),
+                attributes,
+                overflow: true,
+            };
+        }
+
+        for (k, v) in &attributes {
+            let labels = entry.labels.entry(k.clone()).or_default();
+            let value = v.to_string();
+            if labels.sample_values.len() < MAX_SAMPLE_VALUES {
+                labels.sample_values.insert(value);
+            } else if !labels.sample_values.contains(&value) {
+                labels.more = true;
+            }
+        }
+        entry.series.insert(key.clone(), now);
+        *series += 1;
+        Admission { attributes, series_key: key, overflow: false }
+    }
+
+    /// Metrics whose name contains `name`, reported by `service` if given.
+    pub fn snapshot(&self, name: Option<&str>, service: Option<&str>) -> SeriesCatalogSnapshot {
+        let catalog = self.inner.lock().unwrap();
+        let mut metrics: Vec<MetricSummary> = catalog
+            .metrics
+            .iter()
+            .filter(|(n, _)| name.is_none_or(|name| n.contains(name)))
+            .filter(|(_, m)| service.is_none_or(|service| m.services.contains(service)))
+            .map(|(n, m)| MetricSummary {
+                name: n.clone(),
+                kind: m.kind,
+                unit: m.unit.clone(),
+                description: m.description.clone(),
+                services: m.services.clone(),
+                series: m.series.len(),
+                labels: m.labels.clone(),
+                overflow_points: m.overflow_points,
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        SeriesCatalogSnapshot {
+            series: catalog.series,
+            max_series: self.max_series,
+            max_series_per_metric: self.max_series_per_metric,
+            overflow_points: catalog.overflow_points,
+            metrics,
+        }
+    }
+
+    /// Forget the series without a point for `max_idle`, freeing room for
+    /// new ones, and the metrics without any point for as long.
+    pub fn evict_idle(&self, max_idle: Duration) {
+        let mut catalog = self.inner.lock().unwrap();
+        let mut evicted = 0;
+        catalog.metrics.retain(|_, metric| {
+            let before = metric.series.len();
+            metric.series.retain(|_, seen| seen.elapsed() < max_idle);
+            evicted += before - metric.series.len();
+            metric.seen.elapsed() < max_idle
+        });
+        catalog.series -= evicted;
+    }
+}
+
+/// Resolutions of stored metric points, in seconds per point: raw points
+/// (0), then the rollups derived from them.
+pub const RESOLUTIONS: [u64; 3] = [0, 60, 3_600];
+
+/// The resolution to answer a query with: the coarsest whose points fit in
+/// `step_secs`, or a coarser one if retention already deleted the points of
+/// that resolution at `from_ns`.
+pub fn query_resolution(step_secs: u64, from_ns: i64, now_ns: i64, retention: &MetricRetention) -> u64 {
+    let fits = RESOLUTIONS.iter().rposition(|&r| r <= step_secs).unwrap_or(0);
+    RESOLUTIONS[fits..]
+        .iter()
+        .copied()
+        .find(|&r| retention::cutoff_ns(now_ns, retention.days(r)).is_none_or(|cutoff| from_ns >= cutoff))
+        .unwrap_or(RESOLUTIONS[RESOLUTIONS.len() - 1])
+}
+
+/// The numbers stored for a data point.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// Gauge and sum value; mean observation of histograms and summaries.
+    pub value: f64,
+    pub rate: Option<f64>,
+    pub percentiles: Option<Percentiles>,
+}
+
+impl MetricValue {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Gauge { .. }                => "gauge",
+            Self::Sum { .. }                  => "sum",
+            Self::Histogram { .. }            => "histogram",
+            Self::ExponentialHistogram { .. } => "exponential_histogram",
+            Self::Summary { .. }              => "summary",
+        }
+    }
+
+    /// `None` for distributions without observations, which have no mean.
+    pub fn sample(&self) -> Option<Sample> {
+        let distribution = |count: u64, sum: f64, percentiles: Option<Percentiles>| {
+            (count > 0).then(|| Sample { value: sum / count as f64, rate: None, percentiles })
+        };
+        match self {
+            Self::Gauge { value } => Some(Sample { value: *value, rate: None, percentiles: None }),
+            Self::Sum { value, rate, .. } => Some(Sample { value: *value, rate: *rate, percentiles: None }),
+            Self::Histogram { count, sum, percentiles, .. }
+            | Self::ExponentialHistogram { count, sum, percentiles, .. }
+            | Self::Summary { count, sum, percentiles, .. } => distribution(*count, *sum, *percentiles),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{with_attr_format, AttrFormat};
+
+    fn attrs(pairs: &[(&str, &str)]) -> Attributes {
+        pairs.iter().map(|(k, v)| (k.to_string(), AttrValue::String(v.to_string()))).collect()
+    }
+
+    #[test]
+    fn series_key_ignores_attribute_order_and_attr_format() {
+        let a = attrs(&[("route", "/"), ("method", "GET")]);
+        let b = attrs(&[("method", "GET"), ("route", "/")]);
+        let key = series_key("r", "m", &a);
+        assert_eq!(key, series_key("r", "m", &b));
+        assert_eq!(key, with_attr_format(AttrFormat::String, || series_key("r", "m", &a)));
+        // Typed: the string "1" and the int 1 are different series.
+        let int = vec![("n".to_string(), AttrValue::Int(1))];
+        assert_ne!(series_key("r", "m", &attrs(&[("n", "1")])), series_key("r", "m", &int));
+    }
+
+    fn assert_percentiles(p: Option<Percentiles>, expected: (f64, f64, f64)) {
+        let p = p.expect("percentiles");
+        for (got, want) in [(p.p50, expected.0), (p.p90, expected.1), (p.p99, expected.2)] {
+            assert!((got - want).abs() < 1e-9, "{p:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn estimate_interpolates_inside_explicit_buckets() {
+        let nan = f64::NAN;
+        assert_percentiles(Percentiles::estimate(&[10.0, 20.0], &[0, 10, 0], nan, nan), (15.0, 19.0, 19.9));
+        // min closes the unbounded first bucket; max clamps the estimates.
+        assert_percentiles(Percentiles::estimate(&[10.0], &[4, 0], 2.0, 8.0), (6.0, 8.0, 8.0));
+        assert!(Percentiles::estimate(&[10.0], &[0, 0], nan, nan).is_none());
+        assert!(Percentiles::estimate(&[10.0], &[1, 2, 3], nan, nan).is_none());
+    }
+
+    #[test]
+    fn estimate_rejects_overflowing_counts() {
+        assert!(Percentiles::estimate(&[1.0], &[u64::MAX, 1], f64::NAN, f64::NAN).is_none());
+        let positive = ExponentialBuckets { offset: 0, bucket_counts: vec![u64::MAX] };
+        let negative = ExponentialBuckets::default();
+        assert!(Percentiles::estimate_exponential(0, 1, 0.0, &positive, &negative, f64::NAN, f64::NAN).is_none());
+    }
+
+    #[test]
+    fn estimate_exponential_decodes_bucket_ranges() {
+        let nan = f64::NAN;
+        let empty = ExponentialBuckets::default();
+        // Scale 0: base 2, bucket 1 covers (2, 4].
+        let positive = ExponentialBuckets { offset: 0, bucket_counts: vec![0, 4] };
+        assert_percentiles(Percentiles::estimate_exponential(0, 0, 0.0, &positive, &empty, nan, nan), (3.0, 3.8, 3.98));
+        // Negative bucket 0 covers [-2, -1).
+        let negative = ExponentialBuckets { offset: 0, bucket_counts: vec![4] };
+        assert_percentiles(
+            Percentiles::estimate_exponential(0, 0, 0.0, &empty, &negative, nan, nan),
+            (-1.5, -1.1, -1.01),
+        );
+        // Scale 1: base √2, bucket 2 covers (2, 2√2]; zero bucket below it.
+        let positive = ExponentialBuckets { offset: 2, bucket_counts: vec![2] };
+        let p = Percentiles::estimate_exponential(1, 2, 0.0, &positive, &empty, nan, nan).unwrap();
+        assert_eq!(p.p50, 0.0);
+        assert!((p.p99 - (2.0 + (8f64.sqrt() - 2.0) * 0.98)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn from_summary_interpolates_between_quantiles() {
+        let q = |quantile, value| QuantileValue { quantile, value };
+        assert_percentiles(Percentiles::from_summary(&[q(1.0, 11.0), q(0.0, 1.0), q(0.5, 5.0)]), (5.0, 9.8, 10.88));
+        // p99 is not bracketed.
+        assert!(Percentiles::from_summary(&[q(0.5, 5.0), q(0.9, 9.0)]).is_none());
+        assert!(Percentiles::from_summary(&[]).is_none());
+    }
+
+    const SEC: u64 = 1_000_000_000;
+
+    #[test]
+    fn cumulative_sums_give_deltas_and_detect_resets() {
+        let tracker = SumTracker::default();
+        let observe = |start, time, value| {
+            tracker.observe("k".into(), Temporality::Cumulative, true, start, time * SEC, value)
+        };
+        let first = observe(SEC, 10, 100.0);
+        assert_eq!((first.delta, first.rate, first.reset), (None, None, false));
+        let next = observe(SEC, 20, 150.0);
+        assert_eq!((next.delta, next.rate, next.reset), (Some(50.0), Some(5.0), false));
+        // A new start time: counted from zero since the restart.
+        let restarted = observe(25 * SEC, 30, 10.0);
+        assert_eq!((restarted.delta, restarted.rate, restarted.reset), (Some(10.0), Some(2.0), true));
+        // Going down without a new start time also resets a monotonic sum.
+        let decreased = observe(25 * SEC, 40, 4.0);
+        assert_eq!((decreased.delta, decreased.rate, decreased.reset), (Some(4.0), Some(0.4), true));
+        // Out of order or repeated: no delta, and the state is kept.
+        let stale = observe(25 * SEC, 35, 100.0);
+        assert_eq!((stale.delta, stale.rate, stale.reset), (None, None, false));
+        assert_eq!(observe(25 * SEC, 40, 4.0).delta, None);
+        assert_eq!(observe(25 * SEC, 50, 6.0).delta, Some(2.0));
+    }
+
+    #[test]
+    fn non_monotonic_sums_may_go_down() {
+        let tracker = SumTracker::default();
+        tracker.observe("k".into(), Temporality::Cumulative, false, SEC, 10 * SEC, 10.0);
+        let down = tracker.observe("k".into(), Temporality::Cumulative, false, SEC, 20 * SEC, 4.0);
+        assert_eq!((down.delta, down.rate, down.reset), (Some(-6.0), Some(-0.6), false));
+    }
+
+    #[test]
+    fn delta_sums_use_their_own_interval_in_any_order() {
+        let tracker = SumTracker::default();
+        let observe = |start, time| tracker.observe("k".into(), Temporality::Delta, true, start, time * SEC, 10.0);
+        assert_eq!(observe(10 * SEC, 20).rate, Some(1.0));
+        // Older and repeated points keep their rate.
+        assert_eq!(observe(5 * SEC, 10).rate, Some(2.0));
+        assert_eq!(observe(10 * SEC, 20).rate, Some(1.0));
+        // Without a start time, the interval goes back to the previous point.
+        let tracker = SumTracker::default();
+        let observe = |time| tracker.observe("k".into(), Temporality::Delta, true, 0, time * SEC, 10.0);
+        assert_eq!(observe(10).rate, None);
+        assert_eq!(observe(15).rate, Some(2.0));
+        assert_eq!(observe(12).rate, None);
+        assert_eq!(observe(12).delta, Some(10.0));
+    }
+
+    fn admit(catalog: &SeriesCatalog, metric: &str, id: &str) -> Admission {
+        let info = MetricInfo { name: metric, description: "", unit: "1", kind: "gauge" };
+        catalog.admit("r", "svc", &info, attrs(&[("id", id)]))
+    }
+
+    fn is_overflow(admission: &Admission) -> bool {
+        admission.attributes == vec![(OVERFLOW_ATTRIBUTE.to_string(), AttrValue::Bool(true))]
+    }
+
+    #[test]
+    fn admit_collapses_series_past_the_per_metric_limit() {
+        let catalog = SeriesCatalog::new(2, 100);
+        assert!(!admit(&catalog, "a", "1").overflow);
+        assert!(!admit(&catalog, "a", "2").overflow);
+        let third = admit(&catalog, "a", "3");
+        assert!(third.overflow && is_overflow(&third));
+        // Known series and other metrics are unaffected.
+        assert!(!admit(&catalog, "a", "1").overflow);
+        assert!(!admit(&catalog, "b", "3").overflow);
+        // Every overflowing point goes to the same series.
+        assert_eq!(admit(&catalog, "a", "4").series_key, third.series_key);
+
+        let snapshot = catalog.snapshot(None, None);
+        assert_eq!(snapshot.series, 3);
+        assert_eq!(snapshot.overflow_points, 2);
+        let a = &snapshot.metrics[0];
+        assert_eq!((a.name.as_str(), a.series, a.overflow_points), ("a", 2, 2));
+        assert_eq!(a.labels["id"].sample_values.len(), 2);
+    }
+
+    #[test]
+    fn admit_collapses_series_past_the_global_limit() {
+        let catalog = SeriesCatalog::new(10, 3);
+        assert!(!admit(&catalog, "a", "1").overflow);
+        assert!(!admit(&catalog, "a", "2").overflow);
+        assert!(!admit(&catalog, "b", "1").overflow);
+        assert!(admit(&catalog, "b", "2").overflow);
+        assert!(admit(&catalog, "c", "1").overflow);
+        assert!(!admit(&catalog, "b", "1").overflow);
+
+        let snapshot = catalog.snapshot(None, None);
+        assert_eq!((snapshot.series, snapshot.overflow_points), (3, 2));
+        assert_eq!(snapshot.metrics.iter().map(|m| m.series).collect::<Vec<_>>(), [2, 1, 0]);
+    }
+}
@@ -1,13 +1,16 @@
 //! OTLP gRPC + HTTP server — receives spans and metrics from the OpenTelemetry
 //! Collector and feeds them into the shared AppState.
 
+use std::future::Future;
+use std::io::Read;
 use std::sync::Arc;
 
 use axum::{
     Router,
     body::Bytes,
-    extract::State,
-    http::{HeaderMap, StatusCode},
+    extract::{DefaultBodyLimit, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
     routing::post,
 };
 use opentelemetry_proto::tonic::{
@@ -17,20 +20,29 @@ use opentelemetry_proto::tonic::{
     },
     collector::metrics::v1::{
         metrics_service_server::{MetricsService, MetricsServiceServer},
-        ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+        ExportMetricsPartialSuccess, ExportMetricsServiceRequest, ExportMetricsServiceResponse,
     },
     collector::trace::v1::{
         trace_service_server::{TraceService, TraceServiceServer},
-        ExportTraceServiceRequest, ExportTraceServiceResponse,
+        ExportTracePartialSuccess, ExportTraceServiceRequest, ExportTraceServiceResponse,
     },
-    common::v1::{any_value::Value as AnyValueKind, AnyValue},
-    metrics::v1::{metric::Data, number_data_point::Value as NumberValue},
+    common::v1::{any_value::Value as AnyValueKind, AnyValue, InstrumentationScope, KeyValue},
+    metrics::v1::{exponential_histogram_data_point, metric::Data, number_data_point::Value as NumberValue},
+    resource::v1::Resource,
 };
 use prost::Message;
-use tonic::{transport::Server, Request, Response, Status};
+use serde::{de::DeserializeOwned, Serialize};
+use tonic::{transport::Server, Code, Request, Response, Status};
 use tracing::info;
 
-use crate::state::{AppState, LogEvent, MetricEvent, MetricValue, SpanEvent, WsMessage};
+use crate::metrics::{
+    DropReason, ExponentialBuckets, MetricInfo, Percentiles, QuantileValue, SumChange, Temporality,
+};
+use crate::resource::ResourceInfo;
+use crate::state::{
+    AppState, AttrValue, Attributes, LogEvent, MetricEvent, MetricValue, Overload, SpanEvent,
+    SpanEventRecord, SpanException, SpanLink, WsMessage,
+};
 
 pub struct OtlpTraceReceiver {
     state: Arc<AppState>,
@@ -42,6 +54,10 @@ impl TraceService for OtlpTraceReceiver {
         &self,
         request: Request<ExportTraceServiceRequest>,
     ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+        if let Some(overload) = self.state.overload() {
+            return Err(overload_status(overload));
+        }
+
         let req = request.into_inner();
 
         // ── Build the full batch first ────────────────────────────────────────
@@ -53,48 +69,30 @@ impl TraceService for OtlpTraceReceiver {
         // Fix: collect the entire batch into a Vec, pre-index every span_id →
         // target in one pass, then ingest. Parent IDs are now always resolved.
         let mut batch: Vec<SpanEvent> = Vec::new();
+        let mut rejected_spans: i64 = 0;
 
         for resource_spans in req.resource_spans {
-            let service_name = resource_spans
-                .resource
-                .as_ref()
-                .and_then(|r| {
-                    r.attributes.iter().find(|kv| kv.key == "service.name").and_then(|kv| {
-                        kv.value.as_ref().and_then(|v| {
-                            if let Some(AnyValueKind::StringValue(s)) = &v.value {
-                                Some(s.clone())
-                            } else {
-                                None
-                            }
-                        })
-                    })
-                })
-                .unwrap_or_else(|| "unknown".to_string());
-
-            let instance_id = resource_spans
-                .resource
-                .as_ref()
-                .and_then(|r| {
-                    r.attributes.iter().find(|kv| kv.key == "service.instance.id").and_then(|kv| {
-                        kv.value.as_ref().and_then(|v| {
-                            if let Some(AnyValueKind::StringValue(s)) = &v.value {
-                                Some(s.clone())
-                            } else {
-                                None
-                            }
-                        })
-                    })
+            let resource = self.state.register_resource(resource_attributes(&resource_spans.resource));
+            let service_name = service_name_of(&resource);
+            let instance_id = resource
+                .attributes
+                .iter()
+                .find(|(k, _)| k == "service.instance.id")
+                .and_then(|(_, v)| match v {
+                    AttrValue::String(s) => Some(s.clone()),
+                    _ => None,
                 })
                 .unwrap_or_default();
 
             for scope_spans in resource_spans.scope_spans {
-                let scope_target = scope_spans
-                    .scope
-                    .as_ref()
-                    .map(|s| s.name.clone())
-                    .unwrap_or_default();
+                let (scope_name, scope_version) = scope_of(&scope_spans.scope);
+                let scope_target = scope_name.clone();
 
                 for span in scope_spans.spans {
+                    if !is_valid_id(&span.trace_id, 16) || !is_valid_id(&span.span_id, 8) {
+                        rejected_spans += 1;
+                        continue;
+                    }
                     let trace_id = hex::encode(&span.trace_id);
                     let span_id  = hex::encode(&span.span_id);
                     let parent_span_id = if span.parent_span_id.is_empty() {
@@ -103,13 +101,13 @@ impl TraceService for OtlpTraceReceiver {
                         Some(hex::encode(&span.parent_span_id))
                     };
 
-                    let mut attributes: Vec<(String, String)> = Vec::new();
+                    let mut attributes: Attributes = Vec::new();
                     let mut span_target = scope_target.clone();
 
                     for kv in &span.attributes {
-                        let val = kv_to_string(&kv.value);
+                        let val = kv_to_attr(&kv.value);
                         if kv.key == "target" || kv.key == "code.namespace" {
-                            span_target = val.clone();
+                            span_target = val.to_string();
                         }
                         attributes.push((kv.key.clone(), val));
                     }
@@ -128,6 +126,33 @@ impl TraceService for OtlpTraceReceiver {
                         _ => "unset",
                     }
                     .to_string();
+                    let status_message = span.status.as_ref().map(|s| s.message.clone()).unwrap_or_default();
+
+                    let kind = match span.kind {
+                        1 => "internal",
+                        2 => "server",
+                        3 => "client",
+                        4 => "producer",
+                        5 => "consumer",
+                        _ => "unspecified",
+                    }
+                    .to_string();
+
+                    let events: Vec<SpanEventRecord> = span.events.iter().map(|e| SpanEventRecord {
+                        name:                     e.name.clone(),
+                        time_unix_nano:           e.time_unix_nano,
+                        attributes:               e.attributes.iter().map(|kv| (kv.key.clone(), kv_to_attr(&kv.value))).collect(),
+                        dropped_attributes_count: e.dropped_attributes_count,
+                    }).collect();
+                    let exception = exception_from_events(&events);
+
+                    let links: Vec<SpanLink> = span.links.iter().map(|l| SpanLink {
+                        trace_id:                 hex::encode(&l.trace_id),
+                        span_id:                  hex::encode(&l.span_id),
+                        trace_state:              l.trace_state.clone(),
+                        attributes:               l.attributes.iter().map(|kv| (kv.key.clone(), kv_to_attr(&kv.value))).collect(),
+                        dropped_attributes_count: l.dropped_attributes_count,
+                    }).collect();
 
                     batch.push(SpanEvent {
                         trace_id,
@@ -142,68 +167,106 @@ impl TraceService for OtlpTraceReceiver {
                         status,
                         service_name: service_name.clone(),
                         instance_id: instance_id.clone(),
+                        kind,
+                        trace_state: span.trace_state.clone(),
+                        status_message,
+                        events,
+                        links,
+                        dropped_attributes_count: span.dropped_attributes_count,
+                        dropped_events_count: span.dropped_events_count,
+                        dropped_links_count: span.dropped_links_count,
+                        exception,
+                        resource_id: resource.resource_id.clone(),
+                        scope_name: scope_name.clone(),
+                        scope_version: scope_version.clone(),
                     });
                 }
             }
         }
 
-        // ── Collect root trace IDs, broadcast, store in in_flight ─────────────
-        let mut root_trace_ids: Vec<String> = Vec::new();
-        for s in &batch {
-            self.state.total_spans.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            if s.parent_span_id.is_none() {
-                root_trace_ids.push(s.trace_id.clone());
-            }
-        }
+        // ── Broadcast, then store in in_flight ────────────────────────────────
+        self.state.total_spans.fetch_add(batch.len() as u64, std::sync::atomic::Ordering::Relaxed);
 
         // Broadcast full spans (clone needed; original moves into in_flight below)
         if !batch.is_empty() {
-            let msg = WsMessage::SpansBatch { spans: batch.clone() };
-            if let Ok(json) = serde_json::to_string(&msg) {
-                let _ = self.state.broadcast.send(Arc::new(json));
-            }
+            self.state.publish(WsMessage::SpansBatch { spans: batch.clone() });
         }
 
         // Store in in_flight for SQLite persistence on trace completion
-        for s in batch {
-            self.state.in_flight
-                .entry(s.trace_id.clone())
-                .or_default()
-                .insert(s.span_id.clone(), s);
-        }
+        self.state.ingest_spans(batch);
 
-        // ── Finalise completed traces ──────────────────────────────────────────
-        for trace_id in root_trace_ids {
-            self.state.finalize_trace(&trace_id);
-        }
+        let partial_success = (rejected_spans > 0).then(|| ExportTracePartialSuccess {
+            rejected_spans,
+            error_message: format!("{rejected_spans} span(s) with an invalid trace_id or span_id"),
+        });
+        Ok(Response::new(ExportTraceServiceResponse { partial_success }))
+    }
+}
+
+/// Lift `exception.*` attributes out of the first `exception` span event.
+fn exception_from_events(events: &[SpanEventRecord]) -> Option<SpanException> {
+    let event = events.iter().find(|e| e.name == "exception")?;
+    let attr = |key: &str| {
+        event.attributes.iter().find(|(k, _)| k == key).map(|(_, v)| v.to_string()).unwrap_or_default()
+    };
+    Some(SpanException {
+        exception_type: attr("exception.type"),
+        message:        attr("exception.message"),
+        stacktrace:     attr("exception.stacktrace"),
+    })
+}
+
+/// Trace and span IDs must have their fixed length and must not be all zeroes.
+fn is_valid_id(id: &[u8], len: usize) -> bool {
+    id.len() == len && id.iter().any(|&b| b != 0)
+}
 
-        Ok(Response::new(ExportTraceServiceResponse {
-            partial_success: None,
-        }))
+/// Map a backpressure condition to the gRPC status OTLP exporters retry on.
+fn overload_status(overload: Overload) -> Status {
+    match overload {
+        Overload::InFlightFull => Status::unavailable("too many in-flight traces, retry later"),
+        Overload::WriteBacklog => Status::resource_exhausted("trace persistence is lagging, retry later"),
     }
 }
 
-fn kv_to_string(value: &Option<AnyValue>) -> String {
+fn resource_attributes(resource: &Option<Resource>) -> Attributes {
+    resource
+        .as_ref()
+        .map(|r| r.attributes.iter().map(|kv| (kv.key.clone(), kv_to_attr(&kv.value))).collect())
+        .unwrap_or_default()
+}
+
+fn service_name_of(resource: &ResourceInfo) -> String {
+    if resource.service_name.is_empty() {
+        "unknown".to_string()
+    } else {
+        resource.service_name.clone()
+    }
+}
+
+/// Instrumentation scope name and version.
+fn scope_of(scope: &Option<InstrumentationScope>) -> (String, String) {
+    scope
+        .as_ref()
+        .map(|s| (s.name.clone(), s.version.clone()))
+        .unwrap_or_default()
+}
+
+fn kv_to_attr(value: &Option<AnyValue>) -> AttrValue {
     use AnyValueKind as Value;
     match value.as_ref().and_then(|v| v.value.as_ref()) {
-        None                          => String::new(),
-        Some(Value::StringValue(s))   => s.clone(),
-        Some(Value::BoolValue(b))     => b.to_string(),
-        Some(Value::IntValue(i))      => i.to_string(),
-        Some(Value::DoubleValue(d))   => d.to_string(),
-        Some(Value::BytesValue(b))    => hex::encode(b),
-        Some(Value::ArrayValue(arr))  => {
-            let parts: Vec<String> = arr.values.iter()
-                .map(|v| kv_to_string(&Some(v.clone())))
-                .collect();
-            format!("[{}]", parts.join(", "))
-        }
-        Some(Value::KvlistValue(kv))  => {
-            let parts: Vec<String> = kv.values.iter()
-                .map(|kv| format!("{}={}", kv.key, kv_to_string(&kv.value)))
-                .collect();
-            format!("{{{}}}", parts.join(", "))
-        }
+        None                          => AttrValue::String(String::new()),
+        Some(Value::StringValue(s))   => AttrValue::String(s.clone()),
+        Some(Value::BoolValue(b))     => AttrValue::Bool(*b),
+        Some(Value::IntValue(i))      => AttrValue::Int(*i),
+        Some(Value::DoubleValue(d))   => AttrValue::Double(*d),
+        Some(Value::BytesValue(b))    => AttrValue::Bytes(b.clone()),
+        Some(Value::ArrayValue(arr))  => AttrValue::Array(
+            arr.values.iter().map(|v| kv_to_attr(&Some(v.clone()))).collect(),
+        ),
+        Some(Value::KvlistValue(kv))  => AttrValue::Map(
+            kv.values.iter().map(|kv| (kv.key.clone(), kv_to_attr(&kv.value))).collect(),
+        ),
     }
 }
 
@@ -221,29 +284,29 @@ impl MetricsService for OtlpMetricsReceiver {
     ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
         let req = request.into_inner();
         let mut batch: Vec<MetricEvent> = Vec::new();
+        let mut rejected_data_points: i64 = 0;
+        let mut unsupported_types = false;
 
         for resource_metrics in req.resource_metrics {
-            let service_name = resource_metrics
-                .resource
-                .as_ref()
-                .and_then(|r| {
-                    r.attributes.iter().find(|kv| kv.key == "service.name").and_then(|kv| {
-                        kv.value.as_ref().and_then(|v| {
-                            if let Some(AnyValueKind::StringValue(s)) = &v.value {
-                                Some(s.clone())
-                            } else {
-                                None
-                            }
-                        })
-                    })
-                })
-                .unwrap_or_else(|| "unknown".to_string());
+            let resource = self.state.register_resource(resource_attributes(&resource_metrics.resource));
+            let service_name = service_name_of(&resource);
 
             for scope_metrics in resource_metrics.scope_metrics {
+                let (scope_name, scope_version) = scope_of(&scope_metrics.scope);
                 for metric in scope_metrics.metrics {
                     let name        = metric.name.clone();
                     let description = metric.description.clone();
                     let unit        = metric.unit.clone();
+                    // Registers the point's series, or collapses it into the
+                    // overflow series past the cardinality limits.
+                    let admit = |kind: &'static str, attributes: &[KeyValue]| {
+                        self.state.metric_series.admit(
+                            &resource.resource_id,
+                            &service_name,
+                            &MetricInfo { name: &name, description: &description, unit: &unit, kind },
+                            attributes.iter().map(|kv| (kv.key.clone(), kv_to_attr(&kv.value))).collect(),
+                        )
+                    };
 
                     match metric.data {
                         Some(Data::Gauge(g)) => {
@@ -251,7 +314,11 @@ impl MetricsService for OtlpMetricsReceiver {
                                 let v = match dp.value {
                                     Some(NumberValue::AsDouble(d)) => d,
                                     Some(NumberValue::AsInt(i))    => i as f64,
-                                    None                           => continue,
+                                    None                           => {
+                                        rejected_data_points += 1;
+                                        self.state.dropped_metric_points.record(&name, DropReason::NoValue, 1);
+                                        continue;
+                                    }
                                 };
                                 batch.push(MetricEvent {
                                     service_name:        service_name.clone(),
@@ -259,18 +326,39 @@ impl MetricsService for OtlpMetricsReceiver {
                                     description:         description.clone(),
                                     unit:                unit.clone(),
                                     timestamp_unix_nano: dp.time_unix_nano,
-                                    attributes:          dp.attributes.iter().map(|kv| (kv.key.clone(), kv_to_string(&kv.value))).collect(),
+                                    attributes:          admit("gauge", &dp.attributes).attributes,
                                     value:               MetricValue::Gauge { value: v },
+                                    resource_id:         resource.resource_id.clone(),
+                                    scope_name:          scope_name.clone(),
+                                    scope_version:       scope_version.clone(),
                                 });
                             }
                         }
                         Some(Data::Sum(s)) => {
                             let is_monotonic = s.is_monotonic;
+                            let temporality  = Temporality::from_otlp(s.aggregation_temporality);
                             for dp in s.data_points {
                                 let v = match dp.value {
                                     Some(NumberValue::AsDouble(d)) => d,
                                     Some(NumberValue::AsInt(i))    => i as f64,
-                                    None                           => continue,
+                                    None                           => {
+                                        rejected_data_points += 1;
+                                        self.state.dropped_metric_points.record(&name, DropReason::NoValue, 1);
+                                        continue;
+                                    }
+                                };
+                                let series = admit("sum", &dp.attributes);
+                                let change = if series.overflow {
+                                    SumChange::untracked(temporality, dp.start_time_unix_nano, dp.time_unix_nano, v)
+                                } else {
+                                    self.state.sum_series.observe(
+                                        series.series_key,
+                                        temporality,
+                                        is_monotonic,
+                                        dp.start_time_unix_nano,
+                                        dp.time_unix_nano,
+                                        v,
+                                    )
                                 };
                                 batch.push(MetricEvent {
                                     service_name:        service_name.clone(),
@@ -278,46 +366,140 @@ impl MetricsService for OtlpMetricsReceiver {
                                     description:         description.clone(),
                                     unit:                unit.clone(),
                                     timestamp_unix_nano: dp.time_unix_nano,
-                                    attributes:          dp.attributes.iter().map(|kv| (kv.key.clone(), kv_to_string(&kv.value))).collect(),
-                                    value:               MetricValue::Sum { value: v, is_monotonic },
+                                    attributes:          series.attributes,
+                                    value:               MetricValue::Sum {
+                                        value:                v,
+                                        is_monotonic,
+                                        temporality,
+                                        start_time_unix_nano: dp.start_time_unix_nano,
+                                        delta:                change.delta,
+                                        rate:                 change.rate,
+                                        reset:                change.reset,
+                                    },
+                                    resource_id:         resource.resource_id.clone(),
+                                    scope_name:          scope_name.clone(),
+                                    scope_version:       scope_version.clone(),
                                 });
                             }
                         }
                         Some(Data::Histogram(h)) => {
                             for dp in h.data_points {
+                                let min = dp.min.unwrap_or(f64::NAN);
+                                let max = dp.max.unwrap_or(f64::NAN);
                                 batch.push(MetricEvent {
                                     service_name:        service_name.clone(),
                                     metric_name:         name.clone(),
                                     description:         description.clone(),
                                     unit:                unit.clone(),
                                     timestamp_unix_nano: dp.time_unix_nano,
-                                    attributes:          dp.attributes.iter().map(|kv| (kv.key.clone(), kv_to_string(&kv.value))).collect(),
+                                    attributes:          admit("histogram", &dp.attributes).attributes,
                                     value:               MetricValue::Histogram {
-                                        count: dp.count,
-                                        sum:   dp.sum.unwrap_or(0.0),
-                                        min:   dp.min.unwrap_or(f64::NAN),
-                                        max:   dp.max.unwrap_or(f64::NAN),
+                                        count:           dp.count,
+                                        sum:             dp.sum.unwrap_or(0.0),
+                                        min,
+                                        max,
+                                        percentiles:     Percentiles::estimate(&dp.explicit_bounds, &dp.bucket_counts, min, max),
+                                        explicit_bounds: dp.explicit_bounds,
+                                        bucket_counts:   dp.bucket_counts,
+                                    },
+                                    resource_id:         resource.resource_id.clone(),
+                                    scope_name:          scope_name.clone(),
+                                    scope_version:       scope_version.clone(),
+                                });
+                            }
+                        }
+                        Some(Data::ExponentialHistogram(h)) => {
+                            for dp in h.data_points {
+                                let min = dp.min.unwrap_or(f64::NAN);
+                                let max = dp.max.unwrap_or(f64::NAN);
+                                let positive = dp.positive.map(exponential_buckets).unwrap_or_default();
+                                let negative = dp.negative.map(exponential_buckets).unwrap_or_default();
+                                batch.push(MetricEvent {
+                                    service_name:        service_name.clone(),
+                                    metric_name:         name.clone(),
+                                    description:         description.clone(),
+                                    unit:                unit.clone(),
+                                    timestamp_unix_nano: dp.time_unix_nano,
+                                    attributes:          admit("exponential_histogram", &dp.attributes).attributes,
+                                    value:               MetricValue::ExponentialHistogram {
+                                        count:          dp.count,
+                                        sum:            dp.sum.unwrap_or(0.0),
+                                        min,
+                                        max,
+                                        scale:          dp.scale,
+                                        zero_count:     dp.zero_count,
+                                        zero_threshold: dp.zero_threshold,
+                                        percentiles:    Percentiles::estimate_exponential(
+                                            dp.scale, dp.zero_count, dp.zero_threshold, &positive, &negative, min, max,
+                                        ),
+                                        positive,
+                                        negative,
                                     },
+                                    resource_id:         resource.resource_id.clone(),
+                                    scope_name:          scope_name.clone(),
+                                    scope_version:       scope_version.clone(),
                                 });
                             }
                         }
-                        _ => {}
+                        Some(Data::Summary(s)) => {
+                            for dp in s.data_points {
+                                let quantiles: Vec<QuantileValue> = dp
+                                    .quantile_values
+                                    .iter()
+                                    .map(|q| QuantileValue { quantile: q.quantile, value: q.value })
+                                    .collect();
+                                batch.push(MetricEvent {
+                                    service_name:        service_name.clone(),
+                                    metric_name:         name.clone(),
+                                    description:         description.clone(),
+                                    unit:                unit.clone(),
+                                    timestamp_unix_nano: dp.time_unix_nano,
+                                    attributes:          admit("summary", &dp.attributes).attributes,
+                                    value:               MetricValue::Summary {
+                                        count:       dp.count,
+                                        sum:         dp.sum,
+                                        percentiles: Percentiles::from_summary(&quantiles),
+                                        quantiles,
+                                    },
+                                    resource_id:         resource.resource_id.clone(),
+                                    scope_name:          scope_name.clone(),
+                                    scope_version:       scope_version.clone(),
+                                });
+                            }
+                        }
+                        None => {
+                            // A metric type newer than this receiver: its
+                            // points cannot be counted, so the metric counts once.
+                            rejected_data_points += 1;
+                            unsupported_types = true;
+                            self.state.dropped_metric_points.record(&name, DropReason::Unsupported, 1);
+                        }
                     }
                 }
             }
         }
 
         if !batch.is_empty() {
-            let msg = WsMessage::MetricsBatch { metrics: batch };
-            if let Ok(json) = serde_json::to_string(&msg) {
-                let _ = self.state.broadcast.send(Arc::new(json));
-            }
+            self.state.metric_writer.enqueue(batch.clone());
+            self.state.publish(WsMessage::MetricsBatch { metrics: batch });
         }
 
-        Ok(Response::new(ExportMetricsServiceResponse { partial_success: None }))
+        let partial_success = (rejected_data_points > 0).then(|| ExportMetricsPartialSuccess {
+            rejected_data_points,
+            error_message: if unsupported_types {
+                format!("{rejected_data_points} data point(s) without a value or of an unsupported metric type")
+            } else {
+                format!("{rejected_data_points} data point(s) without a value")
+            },
+        });
+        Ok(Response::new(ExportMetricsServiceResponse { partial_success }))
     }
 }
 
+fn exponential_buckets(buckets: exponential_histogram_data_point::Buckets) -> ExponentialBuckets {
+    ExponentialBuckets { offset: buckets.offset, bucket_counts: buckets.bucket_counts }
+}
+
 pub async fn run_otlp_server(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
     let addr = addr.parse()?;
     info!("OTLP gRPC receiver on {}", addr);
@@ -348,23 +530,11 @@ impl LogsService for OtlpLogsReceiver {
         let mut batch: Vec<LogEvent> = Vec::new();
 
         for resource_logs in req.resource_logs {
-            let service_name = resource_logs
-                .resource
-                .as_ref()
-                .and_then(|r| {
-                    r.attributes.iter().find(|kv| kv.key == "service.name").and_then(|kv| {
-                        kv.value.as_ref().and_then(|v| {
-                            if let Some(AnyValueKind::StringValue(s)) = &v.value {
-                                Some(s.clone())
-                            } else {
-                                None
-                            }
-                        })
-                    })
-                })
-                .unwrap_or_else(|| "unknown".to_string());
+            let resource = self.state.register_resource(resource_attributes(&resource_logs.resource));
+            let service_name = service_name_of(&resource);
 
             for scope_logs in resource_logs.scope_logs {
+                let (scope_name, scope_version) = scope_of(&scope_logs.scope);
                 for lr in scope_logs.log_records {
                     let trace_id = if lr.trace_id.is_empty() { None } else { Some(hex::encode(&lr.trace_id)) };
                     let span_id  = if lr.span_id.is_empty()  { None } else { Some(hex::encode(&lr.span_id))  };
@@ -381,18 +551,18 @@ impl LogsService for OtlpLogsReceiver {
                         body,
                         trace_id,
                         span_id,
-                        attributes:          lr.attributes.iter().map(|kv| (kv.key.clone(), kv_to_string(&kv.value))).collect(),
+                        attributes:          lr.attributes.iter().map(|kv| (kv.key.clone(), kv_to_attr(&kv.value))).collect(),
                         service_name:        service_name.clone(),
+                        resource_id:         resource.resource_id.clone(),
+                        scope_name:          scope_name.clone(),
+                        scope_version:       scope_version.clone(),
                     });
                 }
             }
         }
 
         if !batch.is_empty() {
-            let msg = WsMessage::LogsBatch { logs: batch };
-            if let Ok(json) = serde_json::to_string(&msg) {
-                let _ = self.state.broadcast.send(std::sync::Arc::new(json));
-            }
+            self.state.publish(WsMessage::LogsBatch { logs: batch });
         }
 
         Ok(Response::new(ExportLogsServiceResponse { partial_success: None }))
@@ -401,72 +571,653 @@ impl LogsService for OtlpLogsReceiver {
 
 // ── OTLP/HTTP (port 4318) ────────────────────────────────────────────────────
 
-/// Process a raw protobuf body as an ExportTraceServiceRequest.
-async fn http_traces(
-    State(state): State<Arc<AppState>>,
-    _headers: HeaderMap,
+/// Body encoding of an OTLP/HTTP request, negotiated from `Content-Type`.
+/// Responses are always sent back in the same encoding as the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OtlpEncoding {
+    Protobuf,
+    /// OTLP/JSON mapping: hex trace/span IDs, lowerCamelCase field names and
+    /// string-encoded 64-bit integers.
+    Json,
+}
+
+impl OtlpEncoding {
+    /// Returns `None` for a media type that is neither protobuf nor JSON.
+    /// A missing `Content-Type` is treated as protobuf, like before JSON support.
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let Some(value) = headers.get(header::CONTENT_TYPE) else {
+            return Some(Self::Protobuf);
+        };
+        let mime = value.to_str().ok()?.split(';').next()?.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "application/x-protobuf" | "application/protobuf" => Some(Self::Protobuf),
+            "application/json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Protobuf => "application/x-protobuf",
+            Self::Json     => "application/json",
+        }
+    }
+
+    fn decode<T>(self, body: &[u8]) -> Result<T, String>
+    where
+        T: Message + Default + FromOtlpJson,
+    {
+        match self {
+            Self::Protobuf => T::decode(body).map_err(|e| e.to_string()),
+            Self::Json     => T::from_otlp_json(body).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Build an HTTP response carrying `msg` in this encoding.
+    fn respond<T>(self, status: StatusCode, msg: &T) -> axum::response::Response
+    where
+        T: Message + Serialize,
+    {
+        let body = match self {
+            Self::Protobuf => msg.encode_to_vec(),
+            Self::Json     => serde_json::to_vec(msg).unwrap_or_default(),
+        };
+        (status, [(header::CONTENT_TYPE, self.content_type())], body).into_response()
+    }
+}
+
+/// An export request decodable from OTLP/JSON.
+trait FromOtlpJson: DeserializeOwned {
+    fn from_otlp_json(body: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(body)
+    }
+}
+
+impl FromOtlpJson for ExportTraceServiceRequest {}
+impl FromOtlpJson for ExportLogsServiceRequest {}
+
+impl FromOtlpJson for ExportMetricsServiceRequest {
+    fn from_otlp_json(body: &[u8]) -> serde_json::Result<Self> {
+        let mut request: serde_json::Value = serde_json::from_slice(body)?;
+        normalize_metrics_json(&mut request);
+        serde_json::from_value(request)
+    }
+}
+
+/// Rewrite an OTLP/JSON metrics export into what opentelemetry-proto's serde
+/// accepts. The spec writes 64-bit integers as strings and lets exporters
+/// omit fields holding default values, but the derived deserializers take
+/// most 64-bit fields of data points only as JSON numbers, and require every
+/// field of exponential histogram, summary and exemplar points. A point they
+/// reject decodes as a metric without data, or without a value. Exemplar
+/// values are also nested where the crate looks for them.
+fn normalize_metrics_json(request: &mut serde_json::Value) {
+    use serde_json::{json, Value};
+
+    fn each<'a>(value: &'a mut Value, key: &str) -> impl Iterator<Item = &'a mut Value> {
+        value.get_mut(key).and_then(Value::as_array_mut).into_iter().flatten()
+    }
+    fn defaults(point: &mut Value, fields: &[(&str, Value)]) {
+        if let Some(point) = point.as_object_mut() {
+            for (key, value) in fields {
+                point.entry(*key).or_insert_with(|| value.clone());
+            }
+        }
+    }
+    fn integer(value: &mut Value) {
+        if let Some(s) = value.as_str() {
+            if let Ok(n) = s.parse::<u64>() {
+                *value = n.into();
+            } else if let Ok(n) = s.parse::<i64>() {
+                *value = n.into();
+            }
+        }
+    }
+    fn integers(point: &mut Value, keys: &[&str]) {
+        for key in keys {
+            if let Some(value) = point.get_mut(*key) {
+                match value {
+                    Value::Array(values) => values.iter_mut().for_each(integer),
+                    value => integer(value),
+                }
+            }
+        }
+    }
+    fn exemplars(point: &mut Value) {
+        for exemplar in each(point, "exemplars") {
+            defaults(exemplar, &[
+                ("filteredAttributes", json!([])),
+                ("timeUnixNano", json!(0)),
+                ("spanId", json!("")),
+                ("traceId", json!("")),
+            ]);
+            integers(exemplar, &["timeUnixNano", "asInt"]);
+            // The spec flattens the value oneof into the exemplar; the crate
+            // expects it nested under `value`.
+            if let Some(exemplar) = exemplar.as_object_mut() {
+                for key in ["asDouble", "asInt"] {
+                    if let Some(value) = exemplar.remove(key) {
+                        exemplar.insert("value".into(), json!({ key: value }));
+                    }
+                }
+            }
+        }
+    }
+
+    for resource in each(request, "resourceMetrics") {
+        for scope in each(resource, "scopeMetrics") {
+            for metric in each(scope, "metrics") {
+                for data in ["gauge", "sum"] {
+                    for point in metric.get_mut(data).into_iter().flat_map(|d| each(d, "dataPoints")) {
+                        integers(point, &["asInt"]);
+                        exemplars(point);
+                    }
+                }
+                for point in metric.get_mut("histogram").into_iter().flat_map(|d| each(d, "dataPoints")) {
+                    integers(point, &["count", "bucketCounts"]);
+                    exemplars(point);
+                }
+                for point in metric.get_mut("exponentialHistogram").into_iter().flat_map(|d| each(d, "dataPoints")) {
+                    defaults(point, &[
+                        ("attributes", json!([])),
+                        ("startTimeUnixNano", json!(0)),
+                        ("timeUnixNano", json!(0)),
+                        ("count", json!(0)),
+                        ("scale", json!(0)),
+                        ("zeroCount", json!(0)),
+                        ("flags", json!(0)),
+                        ("exemplars", json!([])),
+                        ("zeroThreshold", json!(0.0)),
+                    ]);
+                    integers(point, &["startTimeUnixNano", "timeUnixNano", "count", "zeroCount"]);
+                    for side in ["positive", "negative"] {
+                        if let Some(buckets) = point.get_mut(side) {
+                            defaults(buckets, &[("offset", json!(0)), ("bucketCounts", json!([]))]);
+                            integers(buckets, &["bucketCounts"]);
+                        }
+                    }
+                    exemplars(point);
+                }
+                for point in metric.get_mut("summary").into_iter().flat_map(|d| each(d, "dataPoints")) {
+                    defaults(point, &[
+                        ("attributes", json!([])),
+                        ("startTimeUnixNano", json!(0)),
+                        ("timeUnixNano", json!(0)),
+                        ("count", json!(0)),
+                        ("sum", json!(0.0)),
+                        ("quantileValues", json!([])),
+                        ("flags", json!(0)),
+                    ]);
+                    integers(point, &["startTimeUnixNano", "timeUnixNano", "count"]);
+                    for quantile in each(point, "quantileValues") {
+                        defaults(quantile, &[("quantile", json!(0.0)), ("value", json!(0.0))]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Upper bound on a decompressed request body, to defuse compression bombs.
+const MAX_DECOMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Seconds exporters are asked to wait before retrying a throttled export.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Minimal `google.rpc.Status`, the body OTLP/HTTP mandates for error responses.
+#[derive(Clone, PartialEq, Message, Serialize)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+/// Undo the `Content-Encoding` of a request body (`gzip`, `zstd` or identity).
+fn decompress(headers: &HeaderMap, body: Bytes) -> Result<Bytes, (StatusCode, String)> {
+    let encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let mut out = Vec::new();
+    let read = match encoding.as_str() {
+        "" | "identity" => return Ok(body),
+        "gzip" => flate2::read::MultiGzDecoder::new(body.as_ref())
+            .take(MAX_DECOMPRESSED_BYTES + 1)
+            .read_to_end(&mut out),
+        "zstd" => zstd::stream::read::Decoder::new(body.as_ref())
+            .and_then(|d| d.take(MAX_DECOMPRESSED_BYTES + 1).read_to_end(&mut out)),
+        other => {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported Content-Encoding {other:?}, expected gzip or zstd"),
+            ));
+        }
+    };
+    match read {
+        Ok(n) if n as u64 > MAX_DECOMPRESSED_BYTES => Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("decompressed body exceeds {MAX_DECOMPRESSED_BYTES} bytes"),
+        )),
+        Ok(_) => Ok(out.into()),
+        Err(e) => Err((StatusCode::BAD_REQUEST, format!("{encoding} decompression failed: {e}"))),
+    }
+}
+
+/// Translate a gRPC status from a receiver into the matching OTLP/HTTP error.
+/// Throttling statuses carry `Retry-After` so exporters back off and retry.
+fn error_response(encoding: OtlpEncoding, status: &Status) -> axum::response::Response {
+    let http_status = match status.code() {
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unavailable       => StatusCode::SERVICE_UNAVAILABLE,
+        Code::InvalidArgument   => StatusCode::BAD_REQUEST,
+        _                       => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let body = RpcStatus { code: status.code() as i32, message: status.message().to_string() };
+    let mut resp = encoding.respond(http_status, &body);
+    if matches!(http_status, StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) {
+        resp.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+    }
+    resp
+}
+
+/// Shared OTLP/HTTP pipeline: negotiate the encoding, decompress and decode
+/// the body, hand it to the gRPC receiver and encode its reply (or error).
+async fn handle_http_export<Req, Resp, F, Fut>(
+    signal: &str,
+    headers: &HeaderMap,
     body: Bytes,
-) -> StatusCode {
-    let req = match ExportTraceServiceRequest::decode(body) {
+    export: F,
+) -> axum::response::Response
+where
+    Req: Message + Default + FromOtlpJson,
+    Resp: Message + Serialize,
+    F: FnOnce(Req) -> Fut,
+    Fut: Future<Output = Result<Response<Resp>, Status>>,
+{
+    let Some(encoding) = OtlpEncoding::from_headers(headers) else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected Content-Type application/x-protobuf or application/json",
+        )
+            .into_response();
+    };
+    let body = match decompress(headers, body) {
+        Ok(b) => b,
+        Err((code, msg)) => {
+            tracing::warn!("OTLP/HTTP {} body error: {}", signal, msg);
+            let status = RpcStatus { code: Code::InvalidArgument as i32, message: msg };
+            return encoding.respond(code, &status);
+        }
+    };
+    let req: Req = match encoding.decode(&body) {
         Ok(r) => r,
         Err(e) => {
-            tracing::warn!("OTLP/HTTP trace decode error: {}", e);
-            return StatusCode::BAD_REQUEST;
+            tracing::warn!("OTLP/HTTP {} decode error ({:?}): {}", signal, encoding, e);
+            return error_response(encoding, &Status::invalid_argument(e));
         }
     };
+    match export(req).await {
+        Ok(resp) => encoding.respond(StatusCode::OK, resp.get_ref()),
+        Err(status) => {
+            tracing::warn!("OTLP/HTTP {} export refused: {}", signal, status.message());
+            error_response(encoding, &status)
+        }
+    }
+}
+
+/// Process a protobuf or JSON body as an ExportTraceServiceRequest.
+async fn http_traces(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> axum::response::Response {
     // Reuse the same processing logic as the gRPC handler.
     let receiver = OtlpTraceReceiver { state };
-    let _ = receiver.export(Request::new(req)).await;
-    StatusCode::OK
+    handle_http_export("trace", &headers, body, |req: ExportTraceServiceRequest| {
+        receiver.export(Request::new(req))
+    })
+    .await
 }
 
-/// Process a raw protobuf body as an ExportMetricsServiceRequest.
+/// Process a protobuf or JSON body as an ExportMetricsServiceRequest.
 async fn http_metrics(
     State(state): State<Arc<AppState>>,
-    _headers: HeaderMap,
+    headers: HeaderMap,
     body: Bytes,
-) -> StatusCode {
-    let req = match ExportMetricsServiceRequest::decode(body) {
-        Ok(r) => r,
-        Err(e) => {
-            tracing::warn!("OTLP/HTTP metrics decode error: {}", e);
-            return StatusCode::BAD_REQUEST;
-        }
-    };
+) -> axum::response::Response {
     let receiver = OtlpMetricsReceiver { state };
-    let _ = receiver.export(Request::new(req)).await;
-    StatusCode::OK
+    handle_http_export("metrics", &headers, body, |req: ExportMetricsServiceRequest| {
+        receiver.export(Request::new(req))
+    })
+    .await
 }
 
-/// Process a raw protobuf body as an ExportLogsServiceRequest.
+/// Process a protobuf or JSON body as an ExportLogsServiceRequest.
 async fn http_logs(
     State(state): State<Arc<AppState>>,
-    _headers: HeaderMap,
+    headers: HeaderMap,
     body: Bytes,
-) -> StatusCode {
-    let req = match ExportLogsServiceRequest::decode(body) {
-        Ok(r) => r,
-        Err(e) => {
-            tracing::warn!("OTLP/HTTP logs decode error: {}", e);
-            return StatusCode::BAD_REQUEST;
-        }
-    };
+) -> axum::response::Response {
     let receiver = OtlpLogsReceiver { state };
-    let _ = receiver.export(Request::new(req)).await;
-    StatusCode::OK
+    handle_http_export("logs", &headers, body, |req: ExportLogsServiceRequest| {
+        receiver.export(Request::new(req))
+    })
+    .await
 }
 
 pub async fn run_otlp_http_server(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
     let addr: std::net::SocketAddr = addr.parse()?;
-    info!("OTLP HTTP receiver on {} (protobuf, /v1/traces + /v1/metrics + /v1/logs)", addr);
+    info!("OTLP HTTP receiver on {} (protobuf + JSON, /v1/traces + /v1/metrics + /v1/logs)", addr);
 
     let app = Router::new()
         .route("/v1/traces",  post(http_traces))
         .route("/v1/metrics", post(http_metrics))
         .route("/v1/logs",    post(http_logs))
+        .layer(DefaultBodyLimit::max(MAX_DECOMPRESSED_BYTES as usize))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
     axum::serve(listener, app).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_proto::tonic::metrics::v1::exemplar;
+
+    /// An export shaped like the spec's example, as an http/json exporter
+    /// writes it: 64-bit integers as strings, default-valued fields omitted.
+    const METRICS_JSON: &str = r#"{
+      "resourceMetrics": [{
+        "resource": {
+          "attributes": [{ "key": "service.name", "value": { "stringValue": "checkout" } }]
+        },
+        "scopeMetrics": [{
+          "scope": { "name": "my.library", "version": "1.0.0" },
+          "metrics": [
+            {
+              "name": "my.counter",
+              "unit": "1",
+              "sum": {
+                "aggregationTemporality": 1,
+                "isMonotonic": true,
+                "dataPoints": [{
+                  "asInt": "5",
+                  "startTimeUnixNano": "1544712660300000000",
+                  "timeUnixNano": "1544712660300000000",
+                  "attributes": [{ "key": "my.counter.attr", "value": { "intValue": "3" } }],
+                  "exemplars": [{ "asInt": "2", "timeUnixNano": "1544712660300000000" }]
+                }]
+              }
+            },
+            {
+              "name": "my.gauge",
+              "gauge": {
+                "dataPoints": [{ "asInt": "-7", "timeUnixNano": "1544712660300000000" }]
+              }
+            },
+            {
+              "name": "my.histogram",
+              "histogram": {
+                "aggregationTemporality": 1,
+                "dataPoints": [{
+                  "startTimeUnixNano": "1544712660300000000",
+                  "timeUnixNano": "1544712660300000000",
+                  "count": "2",
+                  "sum": 2,
+                  "bucketCounts": ["1", "1"],
+                  "explicitBounds": [1],
+                  "min": 0,
+                  "max": 2
+                }]
+              }
+            },
+            {
+              "name": "my.exponential.histogram",
+              "exponentialHistogram": {
+                "aggregationTemporality": 2,
+                "dataPoints": [{
+                  "timeUnixNano": "1544712660300000000",
+                  "count": "3",
+                  "sum": 10,
+                  "scale": 1,
+                  "zeroCount": "1",
+                  "positive": { "bucketCounts": ["1", "1"] }
+                }]
+              }
+            },
+            {
+              "name": "my.summary",
+              "summary": {
+                "dataPoints": [{
+                  "timeUnixNano": "1544712660300000000",
+                  "count": "4",
+                  "sum": 8,
+                  "quantileValues": [{ "quantile": 0.5, "value": 2 }, { "quantile": 1 }]
+                }]
+              }
+            }
+          ]
+        }]
+      }]
+    }"#;
+
+    #[test]
+    fn json_metrics_decode_string_integers_and_omitted_defaults() {
+        let request: ExportMetricsServiceRequest =
+            OtlpEncoding::Json.decode(METRICS_JSON.as_bytes()).expect("decodes");
+        let metrics = &request.resource_metrics[0].scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 5);
+
+        let Some(Data::Sum(sum)) = &metrics[0].data else { panic!("sum expected") };
+        let point = &sum.data_points[0];
+        assert_eq!(point.value, Some(NumberValue::AsInt(5)));
+        assert_eq!(point.time_unix_nano, 1_544_712_660_300_000_000);
+        assert_eq!(point.exemplars[0].value, Some(exemplar::Value::AsInt(2)));
+        assert_eq!(point.exemplars[0].time_unix_nano, 1_544_712_660_300_000_000);
+
+        let Some(Data::Gauge(gauge)) = &metrics[1].data else { panic!("gauge expected") };
+        assert_eq!(gauge.data_points[0].value, Some(NumberValue::AsInt(-7)));
+
+        let Some(Data::Histogram(histogram)) = &metrics[2].data else { panic!("histogram expected") };
+        assert_eq!(histogram.data_points[0].count, 2);
+        assert_eq!(histogram.data_points[0].bucket_counts, vec![1, 1]);
+
+        let Some(Data::ExponentialHistogram(exponential)) = &metrics[3].data else {
+            panic!("exponential histogram expected")
+        };
+        let point = &exponential.data_points[0];
+        assert_eq!((point.count, point.zero_count, point.start_time_unix_nano), (3, 1, 0));
+        let positive = point.positive.as_ref().expect("positive buckets");
+        assert_eq!((positive.offset, positive.bucket_counts.clone()), (0, vec![1, 1]));
+        assert!(point.negative.is_none());
+
+        let Some(Data::Summary(summary)) = &metrics[4].data else { panic!("summary expected") };
+        let point = &summary.data_points[0];
+        assert_eq!(point.count, 4);
+        assert_eq!(point.quantile_values[1].value, 0.0);
+    }
+
+    fn encoded(content_encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_str(content_encoding).unwrap());
+        headers
+    }
+
+    fn gzip(body: &[u8]) -> Bytes {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    #[test]
+    fn decompress_undoes_gzip_and_zstd() {
+        let body = b"otlp payload".repeat(100);
+        let zstd = zstd::encode_all(body.as_slice(), 0).unwrap();
+        for (content_encoding, compressed) in [("gzip", gzip(&body)), (" ZSTD ", zstd.into())] {
+            assert_eq!(decompress(&encoded(content_encoding), compressed).unwrap(), body, "{content_encoding}");
+        }
+        for content_encoding in [None, Some("identity")] {
+            let headers = content_encoding.map(encoded).unwrap_or_default();
+            assert_eq!(decompress(&headers, Bytes::from(body.clone())).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn decompress_rejects_bad_encodings() {
+        let (code, _) = decompress(&encoded("br"), Bytes::from_static(b"x")).unwrap_err();
+        assert_eq!(code, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (code, _) = decompress(&encoded("gzip"), Bytes::from_static(b"not gzip")).unwrap_err();
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn decompress_caps_the_decompressed_size() {
+        let bomb = vec![0u8; MAX_DECOMPRESSED_BYTES as usize + 1];
+        let zstd = zstd::encode_all(bomb.as_slice(), 0).unwrap();
+        let (code, _) = decompress(&encoded("zstd"), zstd.into()).unwrap_err();
+        assert_eq!(code, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let gzip = gzip(&bomb[1..]);
+        assert_eq!(decompress(&encoded("gzip"), gzip).unwrap().len() as u64, MAX_DECOMPRESSED_BYTES);
+    }
+
+    /// Send `body` through [`handle_http_export`] to a receiver that replies
+    /// with `reply`.
+    async fn export(
+        headers: HeaderMap,
+        body: &'static [u8],
+        reply: Result<ExportTraceServiceResponse, Status>,
+    ) -> axum::response::Response {
+        handle_http_export("trace", &headers, Bytes::from_static(body), |_: ExportTraceServiceRequest| async {
+            reply.map(Response::new)
+        })
+        .await
+    }
+
+    fn content_type(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(value));
+        headers
+    }
+
+    async fn rpc_status(resp: axum::response::Response) -> RpcStatus {
+        RpcStatus::decode(axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_export_replies_in_the_request_encoding() {
+        let resp = export(content_type("application/json"), br#"{"resourceSpans": []}"#, Ok(Default::default())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+
+        let resp = export(HeaderMap::new(), b"", Ok(Default::default())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/x-protobuf");
+
+        let resp = export(content_type("text/plain"), b"", Ok(Default::default())).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn http_export_throttling_carries_retry_after() {
+        for (status, expected) in [
+            (Status::resource_exhausted("queue full"), StatusCode::TOO_MANY_REQUESTS),
+            (Status::unavailable("shutting down"), StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let code = status.code() as i32;
+            let resp = export(HeaderMap::new(), b"", Err(status)).await;
+            assert_eq!(resp.status(), expected);
+            assert_eq!(resp.headers()[header::RETRY_AFTER], RETRY_AFTER_SECS.to_string().as_str());
+            assert_eq!(rpc_status(resp).await.code, code);
+        }
+
+        let resp = export(HeaderMap::new(), b"", Err(Status::internal("boom"))).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!resp.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn http_export_rejects_undecodable_bodies() {
+        let resp = export(content_type("application/json"), b"{", Ok(Default::default())).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!resp.headers().contains_key(header::RETRY_AFTER));
+
+        let mut headers = encoded("gzip");
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let resp = export(headers, b"not gzip", Ok(Default::default())).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let status: serde_json::Value =
+            serde_json::from_slice(&axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(status["code"], Code::InvalidArgument as i32);
+    }
+
+    #[test]
+    fn json_traces_decode_hex_ids_and_string_times() {
+        let request: ExportTraceServiceRequest = OtlpEncoding::Json
+            .decode(
+                br#"{
+                  "resourceSpans": [{
+                    "resource": { "attributes": [{ "key": "service.name", "value": { "stringValue": "checkout" } }] },
+                    "scopeSpans": [{
+                      "scope": { "name": "my.library" },
+                      "spans": [{
+                        "traceId": "5b8efff798038103d269b633813fc60c",
+                        "spanId": "eee19b7ec3c1b174",
+                        "parentSpanId": "eee19b7ec3c1b173",
+                        "name": "I'm a server span",
+                        "startTimeUnixNano": "1544712660000000000",
+                        "endTimeUnixNano": "1544712661000000000",
+                        "kind": 2,
+                        "attributes": [{ "key": "retries", "value": { "intValue": "3" } }],
+                        "status": {}
+                      }]
+                    }]
+                  }]
+                }"#,
+            )
+            .expect("decodes");
+        let span = &request.resource_spans[0].scope_spans[0].spans[0];
+        assert_eq!(hex::encode(&span.trace_id), "5b8efff798038103d269b633813fc60c");
+        assert_eq!(hex::encode(&span.parent_span_id), "eee19b7ec3c1b173");
+        assert_eq!(span.end_time_unix_nano - span.start_time_unix_nano, 1_000_000_000);
+        let retries = span.attributes[0].value.as_ref().and_then(|v| v.value.clone());
+        assert_eq!(retries, Some(AnyValueKind::IntValue(3)));
+    }
+
+    #[test]
+    fn json_logs_decode() {
+        let request: ExportLogsServiceRequest = OtlpEncoding::Json
+            .decode(
+                br#"{
+                  "resourceLogs": [{
+                    "resource": {},
+                    "scopeLogs": [{
+                      "scope": {},
+                      "logRecords": [{
+                        "timeUnixNano": "1544712660300000000",
+                        "observedTimeUnixNano": "1544712660300000000",
+                        "severityNumber": 10,
+                        "severityText": "Information",
+                        "traceId": "5b8efff798038103d269b633813fc60c",
+                        "spanId": "eee19b7ec3c1b174",
+                        "body": { "stringValue": "Example log record" },
+                        "attributes": []
+                      }]
+                    }]
+                  }]
+                }"#,
+            )
+            .expect("decodes");
+        let record = &request.resource_logs[0].scope_logs[0].log_records[0];
+        assert_eq!(record.time_unix_nano, 1_544_712_660_300_000_000);
+        assert_eq!(hex::encode(&record.span_id), "eee19b7ec3c1b174");
+        let body = record.body.as_ref().and_then(|b| b.value.clone());
+        assert_eq!(body, Some(AnyValueKind::StringValue("Example log record".into())));
+    }
+}
@@ -0,0 +1,223 @@
+//! Deduplicated registry of OTLP resources (service, version, host, pod, …).
+//!
+//! Every span, log record and metric point carries the `resource_id` of the
+//! resource that produced it instead of a copy of its attributes.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{AttrValue, Attributes};
+
+/// A resource as reported by an SDK, with the well-known attributes lifted out
+/// so that callers can filter and group without walking `attributes`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceInfo {
+    pub resource_id: String,
+    pub attributes: Attributes,
+    pub service_name: String,
+    pub service_version: String,
+    pub environment: String,
+    pub host_name: String,
+    pub k8s_pod_name: String,
+    #[serde(default)]
+    pub instance_id: String,
+    pub first_seen_unix_nano: u64,
+}
+
+impl ResourceInfo {
+    pub fn new(attributes: Attributes, first_seen_unix_nano: u64) -> Self {
+        let get = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| attributes.iter().find(|(k, _)| k == key))
+                .map(|(_, v)| v.to_string())
+                .unwrap_or_default()
+        };
+        Self {
+            resource_id: resource_id(&attributes),
+            service_name: get(&["service.name"]),
+            service_version: get(&["service.version"]),
+            environment: get(&["deployment.environment.name", "deployment.environment"]),
+            host_name: get(&["host.name"]),
+            k8s_pod_name: get(&["k8s.pod.name"]),
+            instance_id: get(&["service.instance.id"]),
+            first_seen_unix_nano,
+            attributes,
+        }
+    }
+
+    /// Value of a grouping key: one of the short aliases accepted by
+    /// [`ResourceFilter`] or any raw attribute key.
+    pub fn group_value(&self, by: &str) -> String {
+        match by {
+            "service"     => self.service_name.clone(),
+            "version"     => self.service_version.clone(),
+            "environment" => self.environment.clone(),
+            "host"        => self.host_name.clone(),
+            "pod"         => self.k8s_pod_name.clone(),
+            key => self
+                .attributes
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Stable identifier of a resource: FNV-1a over its attributes sorted by key,
+/// so the same process gets the same ID across exports and restarts.
+pub fn resource_id(attributes: &Attributes) -> String {
+    let mut pairs: Vec<(&str, String)> = attributes
+        .iter()
+        .map(|(k, v)| (k.as_str(), canonical(v)))
+        .collect();
+    pairs.sort();
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for (k, v) in pairs {
+        for b in k.bytes().chain([0]).chain(v.bytes()).chain([0]) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+/// Type-preserving text form of a value (`int` 1 and `string` "1" differ).
+fn canonical(value: &AttrValue) -> String {
+    value.typed_json().to_string()
+}
+
+/// Exact-match filters on the well-known resource attributes.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ResourceFilter {
+    pub service: Option<String>,
+    pub environment: Option<String>,
+    pub version: Option<String>,
+    pub host: Option<String>,
+    pub pod: Option<String>,
+}
+
+impl ResourceFilter {
+    pub fn matches(&self, r: &ResourceInfo) -> bool {
+        let ok = |want: &Option<String>, have: &str| want.as_deref().is_none_or(|w| w == have);
+        ok(&self.service, &r.service_name)
+            && ok(&self.environment, &r.environment)
+            && ok(&self.version, &r.service_version)
+            && ok(&self.host, &r.host_name)
+            && ok(&self.pod, &r.k8s_pod_name)
+    }
+}
+
+/// Resources sharing the same value of a grouping key.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceGroup {
+    pub value: String,
+    pub resource_ids: Vec<String>,
+    pub service_names: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct ResourceRegistry {
+    by_id: DashMap<String, Arc<ResourceInfo>>,
+}
+
+impl ResourceRegistry {
+    /// Seed the registry with resources loaded from SQLite.
+    pub fn extend(&self, resources: impl IntoIterator<Item = ResourceInfo>) {
+        for r in resources {
+            self.by_id.insert(r.resource_id.clone(), Arc::new(r));
+        }
+    }
+
+    /// Look up or insert the resource described by `attributes`. The flag is
+    /// `true` when the resource had not been seen before.
+    pub fn register(&self, attributes: Attributes, now_unix_nano: u64) -> (Arc<ResourceInfo>, bool) {
+        let id = resource_id(&attributes);
+        if let Some(existing) = self.by_id.get(&id) {
+            return (Arc::clone(existing.value()), false);
+        }
+        let mut inserted = false;
+        let info = self
+            .by_id
+            .entry(id)
+            .or_insert_with(|| {
+                inserted = true;
+                Arc::new(ResourceInfo::new(attributes, now_unix_nano))
+            })
+            .clone();
+        (info, inserted)
+    }
+
+    pub fn get(&self, resource_id: &str) -> Option<Arc<ResourceInfo>> {
+        self.by_id.get(resource_id).map(|r| Arc::clone(r.value()))
+    }
+
+    pub fn list(&self, filter: &ResourceFilter) -> Vec<Arc<ResourceInfo>> {
+        let mut out: Vec<Arc<ResourceInfo>> = self
+            .by_id
+            .iter()
+            .filter(|r| filter.matches(r.value()))
+            .map(|r| Arc::clone(r.value()))
+            .collect();
+        out.sort_by(|a, b| {
+            (&a.service_name, &a.resource_id).cmp(&(&b.service_name, &b.resource_id))
+        });
+        out
+    }
+
+    pub fn groups(&self, by: &str, filter: &ResourceFilter) -> Vec<ResourceGroup> {
+        let mut groups: Vec<ResourceGroup> = Vec::new();
+        for r in self.list(filter) {
+            let value = r.group_value(by);
+            let group = match groups.iter_mut().position(|g| g.value == value) {
+                Some(i) => &mut groups[i],
+                None => {
+                    groups.push(ResourceGroup { value, resource_ids: Vec::new(), service_names: Vec::new() });
+                    groups.last_mut().unwrap()
+                }
+            };
+            group.resource_ids.push(r.resource_id.clone());
+            if !group.service_names.contains(&r.service_name) {
+                group.service_names.push(r.service_name.clone());
+            }
+        }
+        groups.sort_by(|a, b| a.value.cmp(&b.value));
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{with_attr_format, AttrFormat};
+
+    fn attributes() -> Attributes {
+        vec![
+            ("service.name".into(), AttrValue::String("checkout".into())),
+            ("pid".into(), AttrValue::Int(42)),
+            ("ratio".into(), AttrValue::Double(0.5)),
+            ("debug".into(), AttrValue::Bool(true)),
+            ("blob".into(), AttrValue::Bytes(vec![0, 1, 255])),
+            ("tags".into(), AttrValue::Array(vec![AttrValue::String("a".into()), AttrValue::Int(1)])),
+            ("labels".into(), AttrValue::Map(vec![("k".into(), AttrValue::String("v".into()))])),
+        ]
+    }
+
+    #[test]
+    fn canonical_form_is_the_typed_serialization() {
+        // Resource IDs stored by earlier versions were hashed from it.
+        for (_, value) in attributes() {
+            assert_eq!(canonical(&value), serde_json::to_string(&value).unwrap());
+        }
+    }
+
+    #[test]
+    fn resource_id_ignores_the_attr_format() {
+        let typed = resource_id(&attributes());
+        let string = with_attr_format(AttrFormat::String, || resource_id(&attributes()));
+        assert_eq!(typed, string);
+    }
+}
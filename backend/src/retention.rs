@@ -0,0 +1,198 @@
+//! How long persisted traces are kept, and how large the database may grow.
+//!
+//! Rules are written `<match>:<days>d`, e.g. `error:30d` or
+//! `service=payments:14d`, on the command line and in the
+//! `--retention-config` file alike. The first rule matching a trace decides
+//! how long it is kept; traces matched by no rule are kept `default_days`.
+//! Metric points are kept per resolution, see [`MetricRetention`].
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const NANOS_PER_DAY: i64 = 24 * 3_600 * 1_000_000_000;
+
+/// Which traces a [`RetentionRule`] applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetentionMatch {
+    /// Traces with at least one error span.
+    Error,
+    /// Traces with at least one span from this service (exact name).
+    Service(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub matches: RetentionMatch,
+    /// 0 keeps matching traces forever.
+    pub days: u64,
+}
+
+impl FromStr for RetentionRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid retention rule `{s}`, expected e.g. `error:30d` or `service=payments:14d`");
+        let (matches, days) = s.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let days = days.strip_suffix('d').unwrap_or(days).parse().map_err(|_| invalid())?;
+        let matches = match matches.split_once('=') {
+            None if matches == "error" => RetentionMatch::Error,
+            Some(("service", name)) if !name.is_empty() => RetentionMatch::Service(name.to_string()),
+            _ => return Err(invalid()),
+        };
+        Ok(Self { matches, days })
+    }
+}
+
+impl fmt::Display for RetentionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.matches {
+            RetentionMatch::Error => write!(f, "error:{}d", self.days),
+            RetentionMatch::Service(name) => write!(f, "service={}:{}d", name, self.days),
+        }
+    }
+}
+
+impl Serialize for RetentionRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RetentionRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RetentionPolicy {
+    /// Applied in order: the first rule matching a trace decides.
+    pub rules: Vec<RetentionRule>,
+    /// Days traces matched by no rule are kept (0 = forever).
+    pub default_days: u64,
+    /// Once trace data takes more than this, the oldest traces are evicted.
+    pub max_db_size_mb: Option<u64>,
+    pub metrics: MetricRetention,
+}
+
+/// Days metric points are kept at each resolution (0 = forever).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricRetention {
+    pub raw_days: u64,
+    pub minute_days: u64,
+    pub hour_days: u64,
+}
+
+impl Default for MetricRetention {
+    fn default() -> Self {
+        Self { raw_days: 1, minute_days: 7, hour_days: 90 }
+    }
+}
+
+impl MetricRetention {
+    /// Retention of the points with `resolution` seconds per point (0 = raw).
+    pub fn days(&self, resolution: u64) -> u64 {
+        match resolution {
+            0 => self.raw_days,
+            60 => self.minute_days,
+            _ => self.hour_days,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.raw_days > 0 || self.minute_days > 0 || self.hour_days > 0
+    }
+}
+
+/// Contents of a `--retention-config` file. Every field present replaces
+/// the corresponding command-line setting.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetentionFile {
+    rules: Option<Vec<RetentionRule>>,
+    default_days: Option<u64>,
+    max_db_size_mb: Option<u64>,
+    metrics: Option<MetricRetention>,
+}
+
+impl RetentionPolicy {
+    /// Override this policy with the settings of the JSON file at `path`.
+    pub fn with_file(mut self, path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let file: RetentionFile =
+            serde_json::from_str(&text).with_context(|| format!("invalid retention config {}", path.display()))?;
+        if let Some(rules) = file.rules {
+            self.rules = rules;
+        }
+        if let Some(days) = file.default_days {
+            self.default_days = days;
+        }
+        if file.max_db_size_mb.is_some() {
+            self.max_db_size_mb = file.max_db_size_mb;
+        }
+        if let Some(metrics) = file.metrics {
+            self.metrics = metrics;
+        }
+        Ok(self)
+    }
+
+    /// Whether pruning can delete any trace at all.
+    pub fn is_active(&self) -> bool {
+        self.default_days > 0 || self.rules.iter().any(|r| r.days > 0) || self.max_db_size_mb.is_some()
+    }
+}
+
+/// The timestamp `days` days before `now_ns`; `None` for 0 (forever).
+pub fn cutoff_ns(now_ns: i64, days: u64) -> Option<i64> {
+    (days > 0).then(|| now_ns.saturating_sub(i64::try_from(days).unwrap_or(i64::MAX).saturating_mul(NANOS_PER_DAY)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> Result<RetentionRule, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(rule("error:30d").unwrap(), RetentionRule { matches: RetentionMatch::Error, days: 30 });
+        assert_eq!(rule(" error:0 ").unwrap(), RetentionRule { matches: RetentionMatch::Error, days: 0 });
+        assert_eq!(
+            rule("service=payments:14d").unwrap(),
+            RetentionRule { matches: RetentionMatch::Service("payments".into()), days: 14 },
+        );
+        // The days follow the last colon.
+        assert_eq!(rule("service=a:b:7d").unwrap().matches, RetentionMatch::Service("a:b".into()));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for s in ["error", "error:", "error:xd", "error:-1d", "warn:3d", "service=:3d", "service:3d", "error=x:3d"] {
+            assert!(rule(s).is_err(), "{s} should not parse");
+        }
+    }
+
+    #[test]
+    fn displays_rules_as_parsed() {
+        for s in ["error:30d", "service=payments:14d", "service=a:b:0d"] {
+            assert_eq!(rule(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn cutoff_saturates_for_huge_retentions() {
+        let now = 100 * NANOS_PER_DAY;
+        assert_eq!(cutoff_ns(now, 0), None);
+        assert_eq!(cutoff_ns(now, 30), Some(70 * NANOS_PER_DAY));
+        for days in [i64::MAX as u64 / 2, i64::MAX as u64 + 1, u64::MAX] {
+            assert_eq!(cutoff_ns(now, days), Some(now.saturating_sub(i64::MAX)), "{days}");
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Snapshot replayed to a WebSocket client when it connects, so that a fresh
+//! or reloaded UI starts from recent data instead of an empty view.
+//!
+//! Recent span, log and metric batches are kept in a [`RecentBuffer`]; traces
+//! older than the buffer are read back from SQLite.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use crate::db::{SortOrder, TraceQuery};
+use crate::state::{AppState, LogEvent, MetricEvent, SpanEvent, WsFrame, WsMessage};
+
+/// Upper bound on the number of traces replayed, whatever the client asks for.
+const MAX_SNAPSHOT_TRACES: usize = 1_000;
+/// Items per replayed `spans_batch` / `logs_batch` / `metrics_batch`.
+const SNAPSHOT_BATCH_SIZE: usize = 1_000;
+
+/// The most recently published span, log and metric batches, bounded by the
+/// total number of spans, log records and metric points they hold.
+pub struct RecentBuffer {
+    frames: VecDeque<Arc<WsFrame>>,
+    items: usize,
+    capacity: usize,
+}
+
+impl RecentBuffer {
+    /// A zero `capacity` keeps nothing.
+    pub fn new(capacity: usize) -> Self {
+        Self { frames: VecDeque::new(), items: 0, capacity }
+    }
+
+    pub fn push(&mut self, frame: Arc<WsFrame>) {
+        let items = items_in(&frame.message);
+        if items == 0 || self.capacity == 0 {
+            return;
+        }
+        self.items += items;
+        self.frames.push_back(frame);
+        while self.items > self.capacity {
+            let Some(oldest) = self.frames.pop_front() else { break };
+            self.items -= items_in(&oldest.message);
+        }
+    }
+
+    pub fn frames(&self) -> Vec<Arc<WsFrame>> {
+        self.frames.iter().cloned().collect()
+    }
+}
+
+/// Spans of `trace_id` and the log records correlated with it found in
+/// `frames`, as returned by [`RecentBuffer::frames`].
+pub fn find_trace(frames: &[Arc<WsFrame>], trace_id: &str) -> (Vec<SpanEvent>, Vec<LogEvent>) {
+    let (mut spans, mut logs) = (Vec::new(), Vec::new());
+    for frame in frames {
+        match &frame.message {
+            WsMessage::SpansBatch { spans: s } => {
+                spans.extend(s.iter().filter(|s| s.trace_id == trace_id).cloned());
+            }
+            WsMessage::LogsBatch { logs: l } => {
+                logs.extend(l.iter().filter(|l| l.trace_id.as_deref() == Some(trace_id)).cloned());
+            }
+            _ => {}
+        }
+    }
+    (spans, logs)
+}
+
+fn items_in(message: &WsMessage) -> usize {
+    match message {
+        WsMessage::SpansBatch { spans }     => spans.len(),
+        WsMessage::MetricsBatch { metrics } => metrics.len(),
+        WsMessage::LogsBatch { logs }       => logs.len(),
+        WsMessage::TraceComplete(_) | WsMessage::TraceSummary(_) => 0,
+    }
+}
+
+/// What a client asked to be replayed on connect.
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotRequest {
+    /// Traces, logs and metrics received within the last this many minutes.
+    pub minutes: Option<u64>,
+    /// At most this many traces, most recent first.
+    pub traces: Option<usize>,
+}
+
+impl SnapshotRequest {
+    /// `None` when neither parameter is set: no snapshot.
+    pub fn new(minutes: Option<u64>, traces: Option<usize>) -> Option<Self> {
+        (minutes.is_some() || traces.is_some()).then_some(Self { minutes, traces })
+    }
+}
+
+pub struct Snapshot {
+    pub messages: Vec<WsMessage>,
+    pub traces: usize,
+    pub spans: usize,
+    pub logs: usize,
+    pub metrics: usize,
+}
+
+/// Build the snapshot from `recent` (as returned by
+/// [`AppState::subscribe_with_recent`]) and, when the buffer does not reach
+/// back far enough, SQLite. Spans come first, oldest first, then logs and
+/// metric points.
+pub async fn build(
+    state: &AppState,
+    request: SnapshotRequest,
+    recent: &[Arc<WsFrame>],
+    now_unix_nano: u64,
+) -> Snapshot {
+    let limit = request.traces.unwrap_or(MAX_SNAPSHOT_TRACES).min(MAX_SNAPSHOT_TRACES);
+    let since = request
+        .minutes
+        .map(|m| now_unix_nano.saturating_sub(m.saturating_mul(60_000_000_000)));
+    let in_window = |f: &&Arc<WsFrame>| since.is_none_or(|s| f.published_unix_nano >= s);
+
+    // trace_id → span_id → span
+    let mut by_trace: HashMap<String, HashMap<String, SpanEvent>> = HashMap::new();
+    for frame in recent.iter().filter(in_window) {
+        if let WsMessage::SpansBatch { spans } = &frame.message {
+            for s in spans {
+                by_trace.entry(s.trace_id.clone()).or_default().insert(s.span_id.clone(), s.clone());
+            }
+        }
+    }
+
+    // The buffer holds the whole window only if it starts before it.
+    let covered = since.is_some_and(|s| recent.first().is_some_and(|f| f.published_unix_nano <= s));
+    if by_trace.len() < limit && !covered {
+        let query = TraceQuery {
+            from_ns: since.map_or(0, |s| s as i64),
+            to_ns: i64::MAX,
+            limit,
+            order: SortOrder::Desc,
+            ..Default::default()
+        };
+        let db = Arc::clone(&state.db);
+        match tokio::task::spawn_blocking(move || db.query_traces(&query)).await {
+            Ok(Ok(page)) => {
+                for trace in page.traces {
+                    let spans = by_trace.entry(trace.trace_id).or_default();
+                    for s in trace.spans {
+                        spans.entry(s.span_id.clone()).or_insert(s);
+                    }
+                }
+            }
+            Ok(Err(e)) => tracing::error!("Snapshot trace query failed: {}", e),
+            Err(e) => tracing::error!("Snapshot trace query panicked: {}", e),
+        }
+    }
+
+    let mut traces: Vec<Vec<SpanEvent>> = by_trace.into_values().map(|t| t.into_values().collect()).collect();
+    traces.sort_by_key(|spans| Reverse(spans.iter().map(|s| s.end_time_unix_nano).max().unwrap_or(0)));
+    traces.truncate(limit);
+
+    // Without a time window, logs and metrics cover the same period as the traces.
+    let logs_since = since.unwrap_or_else(|| {
+        traces.iter().flatten().map(|s| s.start_time_unix_nano).min().unwrap_or(now_unix_nano)
+    });
+    let mut logs: Vec<LogEvent> = Vec::new();
+    let mut metrics: Vec<MetricEvent> = Vec::new();
+    for frame in recent.iter().filter(|f| f.published_unix_nano >= logs_since) {
+        match &frame.message {
+            WsMessage::LogsBatch { logs: l }       => logs.extend(l.iter().cloned()),
+            WsMessage::MetricsBatch { metrics: m } => metrics.extend(m.iter().cloned()),
+            _ => {}
+        }
+    }
+
+    let trace_count = traces.len();
+    let mut spans: Vec<SpanEvent> = traces.into_iter().flatten().collect();
+    spans.sort_by_key(|s| s.start_time_unix_nano);
+
+    let mut snapshot = Snapshot {
+        messages: Vec::new(),
+        traces: trace_count,
+        spans: spans.len(),
+        logs: logs.len(),
+        metrics: metrics.len(),
+    };
+    for chunk in spans.chunks(SNAPSHOT_BATCH_SIZE) {
+        snapshot.messages.push(WsMessage::SpansBatch { spans: chunk.to_vec() });
+    }
+    for chunk in logs.chunks(SNAPSHOT_BATCH_SIZE) {
+        snapshot.messages.push(WsMessage::LogsBatch { logs: chunk.to_vec() });
+    }
+    for chunk in metrics.chunks(SNAPSHOT_BATCH_SIZE) {
+        snapshot.messages.push(WsMessage::MetricsBatch { metrics: chunk.to_vec() });
+    }
+    snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MetricValue;
+    use crate::testing::{app_state, log, span, trace, TempPath};
+
+    const MINUTE: u64 = 60_000_000_000;
+    const NOW: u64 = 1_000 * MINUTE;
+
+    fn frame(message: WsMessage, published_unix_nano: u64) -> Arc<WsFrame> {
+        Arc::new(WsFrame::published_at(message, published_unix_nano))
+    }
+
+    fn spans(trace_id: &str, span_ids: &[&str], start: u64) -> WsMessage {
+        let spans = span_ids
+            .iter()
+            .map(|id| SpanEvent { span_id: id.to_string(), ..span(trace_id, "api", "ok", start) })
+            .collect();
+        WsMessage::SpansBatch { spans }
+    }
+
+    fn logs(trace_id: &str, time: u64) -> WsMessage {
+        WsMessage::LogsBatch { logs: vec![log("api", 9, Some(trace_id), time)] }
+    }
+
+    fn metric(time: u64) -> WsMessage {
+        let metric = MetricEvent {
+            service_name: "api".into(),
+            metric_name: "requests".into(),
+            description: String::new(),
+            unit: String::new(),
+            timestamp_unix_nano: time,
+            attributes: vec![],
+            value: MetricValue::Gauge { value: 1.0 },
+            resource_id: String::new(),
+            scope_name: String::new(),
+            scope_version: String::new(),
+        };
+        WsMessage::MetricsBatch { metrics: vec![metric] }
+    }
+
+    /// `trace_id/span_id` of the spans of a snapshot, in order.
+    fn span_ids(snapshot: &Snapshot) -> Vec<String> {
+        let mut ids = Vec::new();
+        for message in &snapshot.messages {
+            if let WsMessage::SpansBatch { spans } = message {
+                ids.extend(spans.iter().map(|s| format!("{}/{}", s.trace_id, s.span_id)));
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn recent_buffer_is_bounded_by_items() {
+        let mut buffer = RecentBuffer::new(3);
+        buffer.push(frame(spans("t1", &["a", "b"], 1), 1));
+        buffer.push(frame(logs("t1", 2), 2));
+        buffer.push(frame(WsMessage::TraceComplete(trace("t1", "api", "ok", 1)), 3));
+        assert_eq!(buffer.frames().len(), 2);
+
+        buffer.push(frame(spans("t2", &["c"], 4), 4));
+        let published: Vec<u64> = buffer.frames().iter().map(|f| f.published_unix_nano).collect();
+        assert_eq!(published, [2, 4]);
+
+        let mut disabled = RecentBuffer::new(0);
+        disabled.push(frame(spans("t1", &["a"], 1), 1));
+        assert!(disabled.frames().is_empty());
+    }
+
+    #[test]
+    fn find_trace_collects_spans_and_correlated_logs() {
+        let frames = [
+            frame(spans("t1", &["a"], 1), 1),
+            frame(spans("t2", &["b"], 1), 1),
+            frame(logs("t1", 2), 2),
+            frame(logs("t2", 2), 2),
+            frame(spans("t1", &["c"], 3), 3),
+        ];
+        let (spans, logs) = find_trace(&frames, "t1");
+        assert_eq!(spans.iter().map(|s| s.span_id.as_str()).collect::<Vec<_>>(), ["a", "c"]);
+        assert_eq!(logs.len(), 1);
+        assert!(find_trace(&frames, "t3").0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_of_a_window_the_buffer_covers() {
+        let path = TempPath::new("snapshot-window");
+        let state = app_state(&path, 100);
+        // Stored, but the buffer reaches back far enough not to look.
+        state.db.insert_trace(&trace("stored", "api", "ok", NOW - 2 * MINUTE)).unwrap();
+        let recent = [
+            frame(spans("old", &["a"], NOW - 20 * MINUTE), NOW - 20 * MINUTE),
+            frame(metric(NOW - 20 * MINUTE), NOW - 20 * MINUTE),
+            frame(spans("t2", &["c"], NOW - 2 * MINUTE), NOW - 2 * MINUTE),
+            frame(spans("t1", &["a"], NOW - 3 * MINUTE), NOW - 3 * MINUTE),
+            frame(spans("t1", &["b"], NOW - 3 * MINUTE + 1), NOW - 3 * MINUTE + 1),
+            frame(logs("t1", NOW - 3 * MINUTE), NOW - 3 * MINUTE + 2),
+            frame(metric(NOW - MINUTE), NOW - MINUTE),
+        ];
+
+        let request = SnapshotRequest::new(Some(10), None).unwrap();
+        let snapshot = build(&state, request, &recent, NOW).await;
+        assert_eq!((snapshot.traces, snapshot.spans, snapshot.logs, snapshot.metrics), (2, 3, 1, 1));
+        assert_eq!(span_ids(&snapshot), ["t1/a", "t1/b", "t2/c"]);
+        assert!(matches!(snapshot.messages[1], WsMessage::LogsBatch { .. }));
+        assert!(matches!(snapshot.messages[2], WsMessage::MetricsBatch { .. }));
+    }
+
+    #[tokio::test]
+    async fn snapshot_reads_older_traces_from_the_database() {
+        let path = TempPath::new("snapshot-db");
+        let state = app_state(&path, 100);
+        for (trace_id, minutes_ago) in [("oldest", 30), ("older", 20), ("live", 5)] {
+            state.db.insert_trace(&trace(trace_id, "api", "ok", NOW - minutes_ago * MINUTE)).unwrap();
+        }
+        // The buffer holds a later span of a stored trace, and a new trace.
+        let recent = [
+            frame(spans("live", &["0000000000000002"], NOW - 4 * MINUTE), NOW - 4 * MINUTE),
+            frame(spans("new", &["a"], NOW - MINUTE), NOW - MINUTE),
+        ];
+
+        let request = SnapshotRequest::new(None, Some(3)).unwrap();
+        let snapshot = build(&state, request, &recent, NOW).await;
+        assert_eq!(snapshot.traces, 3);
+        assert_eq!(
+            span_ids(&snapshot),
+            ["older/0000000000000001", "live/0000000000000001", "live/0000000000000002", "new/a"],
+        );
+    }
+}
@@ -1,12 +1,175 @@
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::sync::{Arc, atomic::Ordering};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock, atomic::Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use base64::Engine;
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::{broadcast, mpsc::UnboundedReceiver};
 
-use crate::db::Db;
+use crate::db::{Db, TracePin};
+use crate::metrics::{
+    DroppedPointsRegistry, ExponentialBuckets, Percentiles, QuantileValue, SeriesCatalog, SumTracker, Temporality,
+};
+use crate::resource::{ResourceInfo, ResourceRegistry};
+use crate::retention::RetentionPolicy;
+use crate::snapshot::RecentBuffer;
+use crate::writer::{MetricWriter, TraceWriter};
+
+/// A typed attribute value, mirroring OTLP's `AnyValue`.
+///
+/// Serialized as `{"type": "int", "value": 42}` so numeric and boolean values
+/// survive the WS protocol and SQLite. Bytes are base64-encoded and maps are
+/// `[key, value]` pairs like the attribute lists themselves. Rows written
+/// before typed attributes existed hold plain strings, which deserialize as
+/// [`AttrValue::String`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Array(Vec<AttrValue>),
+    Map(Vec<(String, AttrValue)>),
+}
+
+/// How attribute values are rendered for a client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttrFormat {
+    /// Tagged values (`{"type": "int", "value": 42}`).
+    #[default]
+    Typed,
+    /// Compatibility mode: every value flattened to a string, as before typed
+    /// attributes (arrays as `[a, b]`, maps as `{k=v}`, bytes as hex).
+    String,
+}
+
+thread_local! {
+    static STRING_ATTRS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` (typically a `serde_json::to_string` call) with every [`AttrValue`]
+/// serialized in the given format. Identifiers derived from attributes must
+/// not depend on this; see [`AttrValue::typed_json`].
+pub fn with_attr_format<R>(format: AttrFormat, f: impl FnOnce() -> R) -> R {
+    /// Restores the previous format, also when `f` panics.
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            STRING_ATTRS.with(|c| c.set(self.0));
+        }
+    }
+    let _restore = Restore(STRING_ATTRS.with(|c| c.replace(format == AttrFormat::String)));
+    f()
+}
+
+impl fmt::Display for AttrValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttrValue::String(s) => f.write_str(s),
+            AttrValue::Int(i)    => write!(f, "{i}"),
+            AttrValue::Double(d) => write!(f, "{d}"),
+            AttrValue::Bool(b)   => write!(f, "{b}"),
+            AttrValue::Bytes(b)  => f.write_str(&hex::encode(b)),
+            AttrValue::Array(values) => {
+                let parts: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", parts.join(", "))
+            }
+            AttrValue::Map(entries) => {
+                let parts: Vec<String> = entries.iter().map(|(k, v)| format!("{k}={v}")).collect();
+                write!(f, "{{{}}}", parts.join(", "))
+            }
+        }
+    }
+}
+
+impl AttrValue {
+    /// The typed JSON form (`{"type": "int", "value": 42}`), whatever the
+    /// format [`with_attr_format`] is applying. Content-derived identifiers
+    /// (resource IDs, metric series keys) are built from it.
+    pub fn typed_json(&self) -> serde_json::Value {
+        let (kind, value) = match self {
+            AttrValue::String(s) => ("string", serde_json::Value::from(s.as_str())),
+            AttrValue::Int(i)    => ("int",    serde_json::Value::from(*i)),
+            AttrValue::Double(d) => ("double", serde_json::Value::from(*d)),
+            AttrValue::Bool(b)   => ("bool",   serde_json::Value::from(*b)),
+            AttrValue::Bytes(b)  => ("bytes",  base64::engine::general_purpose::STANDARD.encode(b).into()),
+            AttrValue::Array(v)  => ("array",  v.iter().map(AttrValue::typed_json).collect()),
+            AttrValue::Map(m)    => (
+                "map",
+                m.iter().map(|(k, v)| serde_json::json!([k, v.typed_json()])).collect(),
+            ),
+        };
+        serde_json::json!({ "type": kind, "value": value })
+    }
+}
+
+impl Serialize for AttrValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if STRING_ATTRS.with(Cell::get) {
+            return serializer.collect_str(self);
+        }
+        let mut st = serializer.serialize_struct("AttrValue", 2)?;
+        match self {
+            AttrValue::String(s) => { st.serialize_field("type", "string")?; st.serialize_field("value", s)?; }
+            AttrValue::Int(i)    => { st.serialize_field("type", "int")?;    st.serialize_field("value", i)?; }
+            AttrValue::Double(d) => { st.serialize_field("type", "double")?; st.serialize_field("value", d)?; }
+            AttrValue::Bool(b)   => { st.serialize_field("type", "bool")?;   st.serialize_field("value", b)?; }
+            AttrValue::Bytes(b)  => {
+                st.serialize_field("type", "bytes")?;
+                st.serialize_field("value", &base64::engine::general_purpose::STANDARD.encode(b))?;
+            }
+            AttrValue::Array(v)  => { st.serialize_field("type", "array")?;  st.serialize_field("value", v)?; }
+            AttrValue::Map(m)    => { st.serialize_field("type", "map")?;    st.serialize_field("value", m)?; }
+        }
+        st.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for AttrValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(tag = "type", content = "value", rename_all = "snake_case")]
+        enum Tagged {
+            String(String),
+            Int(i64),
+            Double(f64),
+            Bool(bool),
+            Bytes(String),
+            Array(Vec<AttrValue>),
+            Map(Vec<(String, AttrValue)>),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Tagged(Tagged),
+            Legacy(String),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Legacy(s) => AttrValue::String(s),
+            Repr::Tagged(Tagged::String(s)) => AttrValue::String(s),
+            Repr::Tagged(Tagged::Int(i))    => AttrValue::Int(i),
+            Repr::Tagged(Tagged::Double(d)) => AttrValue::Double(d),
+            Repr::Tagged(Tagged::Bool(b))   => AttrValue::Bool(b),
+            Repr::Tagged(Tagged::Bytes(b))  => AttrValue::Bytes(
+                base64::engine::general_purpose::STANDARD
+                    .decode(b)
+                    .map_err(serde::de::Error::custom)?,
+            ),
+            Repr::Tagged(Tagged::Array(v))  => AttrValue::Array(v),
+            Repr::Tagged(Tagged::Map(m))    => AttrValue::Map(m),
+        })
+    }
+}
+
+/// Attribute lists keep OTLP's order and allow duplicate keys.
+pub type Attributes = Vec<(String, AttrValue)>;
 
 /// A single span decoded from OTLP.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,10 +182,69 @@ pub struct SpanEvent {
     pub start_time_unix_nano: u64,
     pub end_time_unix_nano: u64,
     pub duration_ms: f64,
-    pub attributes: Vec<(String, String)>,
+    pub attributes: Attributes,
     pub status: String,
     pub service_name: String,
     pub instance_id: String,
+    /// "internal" | "server" | "client" | "producer" | "consumer" | "unspecified".
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default)]
+    pub trace_state: String,
+    /// Developer-facing message attached to the span status (usually set on errors).
+    #[serde(default)]
+    pub status_message: String,
+    #[serde(default)]
+    pub events: Vec<SpanEventRecord>,
+    #[serde(default)]
+    pub links: Vec<SpanLink>,
+    #[serde(default)]
+    pub dropped_attributes_count: u32,
+    #[serde(default)]
+    pub dropped_events_count: u32,
+    #[serde(default)]
+    pub dropped_links_count: u32,
+    /// Details of the first `exception` event, if the span recorded one.
+    #[serde(default)]
+    pub exception: Option<SpanException>,
+    /// Key into the resource registry (`/api/resources/{id}`).
+    #[serde(default)]
+    pub resource_id: String,
+    #[serde(default)]
+    pub scope_name: String,
+    #[serde(default)]
+    pub scope_version: String,
+}
+
+/// A timestamped event recorded inside a span (an exception, a `tracing` event, …).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpanEventRecord {
+    pub name: String,
+    pub time_unix_nano: u64,
+    pub attributes: Attributes,
+    #[serde(default)]
+    pub dropped_attributes_count: u32,
+}
+
+/// A link from a span to a span of another trace (e.g. producer → consumer).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpanLink {
+    pub trace_id: String,
+    pub span_id: String,
+    #[serde(default)]
+    pub trace_state: String,
+    pub attributes: Attributes,
+    #[serde(default)]
+    pub dropped_attributes_count: u32,
+}
+
+/// Exception details following the `exception.*` semantic conventions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpanException {
+    #[serde(rename = "type")]
+    pub exception_type: String,
+    pub message: String,
+    pub stacktrace: String,
 }
 
 /// A complete trace (collection of spans for a single block processing run).
@@ -35,6 +257,119 @@ pub struct TraceComplete {
     pub started_at: u64,
     /// Identifies which process instance produced this trace (from service.instance.id).
     pub instance_id: String,
+    /// Other traces referenced by span links of this trace.
+    #[serde(default)]
+    pub linked_trace_ids: Vec<String>,
+    #[serde(flatten)]
+    pub stats: TraceStats,
+    /// Set on pinned traces in HTTP responses (`POST /api/traces/{id}/pin`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<TracePin>,
+}
+
+/// Figures derived from the spans of a trace, stored alongside it so that
+/// history queries can filter on them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TraceStats {
+    pub span_count: usize,
+    /// Spans with status "error".
+    pub error_count: usize,
+    /// Distinct services with at least one span, sorted.
+    pub services: Vec<String>,
+    /// Length of the longest parent → child chain (1 for a lone root span).
+    /// Spans whose parent is missing count as roots.
+    pub max_depth: usize,
+}
+
+impl TraceStats {
+    pub fn of(spans: &[SpanEvent]) -> Self {
+        let mut services: Vec<String> = spans.iter().map(|s| s.service_name.clone()).collect();
+        services.sort();
+        services.dedup();
+
+        let by_id: HashMap<&str, &SpanEvent> = spans.iter().map(|s| (s.span_id.as_str(), s)).collect();
+        let mut depths: HashMap<&str, usize> = HashMap::new();
+        for span in spans {
+            // Climb to the nearest span of known depth, or to a root, then
+            // assign depths on the way back down. The length check stops
+            // parent cycles in malformed input.
+            let mut chain: Vec<&str> = Vec::new();
+            let mut id = span.span_id.as_str();
+            let mut base = 0;
+            loop {
+                if let Some(&d) = depths.get(id) {
+                    base = d;
+                    break;
+                }
+                chain.push(id);
+                match by_id.get(id).and_then(|s| s.parent_span_id.as_deref()) {
+                    Some(parent) if by_id.contains_key(parent) && chain.len() <= spans.len() => id = parent,
+                    _ => break,
+                }
+            }
+            for (i, id) in chain.into_iter().rev().enumerate() {
+                depths.insert(id, base + i + 1);
+            }
+        }
+
+        Self {
+            span_count: spans.len(),
+            error_count: spans.iter().filter(|s| s.status == "error").count(),
+            services,
+            max_depth: depths.into_values().max().unwrap_or(0),
+        }
+    }
+}
+
+impl TraceComplete {
+    /// Assemble a trace from its spans, deriving the summary fields.
+    pub fn from_spans(trace_id: &str, mut spans: Vec<SpanEvent>) -> Self {
+        spans.sort_by_key(|s| s.start_time_unix_nano);
+
+        let started_at = spans.first().map(|s| s.start_time_unix_nano).unwrap_or(0);
+        let ended_at = spans.iter().map(|s| s.end_time_unix_nano).max().unwrap_or(0);
+        let duration_ms = (ended_at.saturating_sub(started_at)) as f64 / 1_000_000.0;
+        let root_span = spans.iter().find(|s| s.parent_span_id.is_none());
+        let root_span_name = root_span.map(|s| s.name.clone()).unwrap_or_default();
+        let instance_id = root_span.map(|s| s.instance_id.clone()).unwrap_or_default();
+        let linked_trace_ids = linked_trace_ids(trace_id, &spans);
+        let stats = TraceStats::of(&spans);
+
+        Self {
+            trace_id: trace_id.to_string(),
+            spans,
+            root_span_name,
+            duration_ms,
+            started_at,
+            instance_id,
+            linked_trace_ids,
+            stats,
+            pin: None,
+        }
+    }
+
+    /// Service of the root span, or empty while the root is unknown.
+    pub fn root_service_name(&self) -> &str {
+        self.spans
+            .iter()
+            .find(|s| s.parent_span_id.is_none())
+            .map(|s| s.service_name.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Distinct trace IDs that `spans` link to, excluding `trace_id` itself.
+pub fn linked_trace_ids(trace_id: &str, spans: &[SpanEvent]) -> Vec<String> {
+    let mut ids: Vec<String> = spans
+        .iter()
+        .flat_map(|s| s.links.iter())
+        .filter(|l| l.trace_id != trace_id)
+        .map(|l| l.trace_id.clone())
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
 }
 
 /// A single metric data point decoded from OTLP.
@@ -45,8 +380,14 @@ pub struct MetricEvent {
     pub description:         String,
     pub unit:                String,
     pub timestamp_unix_nano: u64,
-    pub attributes:          Vec<(String, String)>,
+    pub attributes:          Attributes,
     pub value:               MetricValue,
+    #[serde(default)]
+    pub resource_id:         String,
+    #[serde(default)]
+    pub scope_name:          String,
+    #[serde(default)]
+    pub scope_version:       String,
 }
 
 /// The decoded value of a metric data point.
@@ -54,8 +395,60 @@ pub struct MetricEvent {
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum MetricValue {
     Gauge     { value: f64 },
-    Sum       { value: f64, is_monotonic: bool },
-    Histogram { count: u64, sum: f64, min: f64, max: f64 },
+    Sum {
+        value:                f64,
+        is_monotonic:         bool,
+        #[serde(default)]
+        temporality:          Temporality,
+        /// Start of the interval `value` covers (0 = unknown).
+        #[serde(default)]
+        start_time_unix_nano: u64,
+        /// Change since the previous point of the series.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta:                Option<f64>,
+        /// `delta` per second.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rate:                 Option<f64>,
+        /// The cumulative series restarted before this point.
+        #[serde(default)]
+        reset:                bool,
+    },
+    Histogram {
+        count:           u64,
+        sum:             f64,
+        min:             f64,
+        max:             f64,
+        /// Upper bounds of all buckets but the last, which is unbounded.
+        #[serde(default)]
+        explicit_bounds: Vec<f64>,
+        #[serde(default)]
+        bucket_counts:   Vec<u64>,
+        /// Estimated from the buckets; absent without buckets or observations.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        percentiles:     Option<Percentiles>,
+    },
+    ExponentialHistogram {
+        count:           u64,
+        sum:             f64,
+        min:             f64,
+        max:             f64,
+        /// Bucket resolution: bucket boundaries are powers of `2^(2^-scale)`.
+        scale:           i32,
+        zero_count:      u64,
+        zero_threshold:  f64,
+        positive:        ExponentialBuckets,
+        negative:        ExponentialBuckets,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        percentiles:     Option<Percentiles>,
+    },
+    Summary {
+        count:           u64,
+        sum:             f64,
+        quantiles:       Vec<QuantileValue>,
+        /// Interpolated from `quantiles`; absent unless they bracket p50–p99.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        percentiles:     Option<Percentiles>,
+    },
 }
 
 /// A single log record decoded from OTLP.
@@ -68,8 +461,14 @@ pub struct LogEvent {
     pub body:                String,
     pub trace_id:            Option<String>,
     pub span_id:             Option<String>,
-    pub attributes:          Vec<(String, String)>,
+    pub attributes:          Attributes,
     pub service_name:        String,
+    #[serde(default)]
+    pub resource_id:         String,
+    #[serde(default)]
+    pub scope_name:          String,
+    #[serde(default)]
+    pub scope_version:       String,
 }
 
 /// Events broadcast to WebSocket clients.
@@ -88,104 +487,350 @@ pub enum WsMessage {
     LogsBatch {
         logs: Vec<LogEvent>,
     },
+    /// A finalized trace, in the same shape as `/api/traces` entries. Sent
+    /// again, merged, when late spans are added to an already finalized trace.
+    TraceComplete(TraceComplete),
+    /// Span-less rendering of `TraceComplete` for clients that asked for
+    /// summaries only; never published directly.
+    TraceSummary(TraceSummary),
+}
+
+/// A finalized trace without its spans.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceSummary {
+    pub trace_id: String,
+    pub root_span_name: String,
+    pub duration_ms: f64,
+    pub started_at: u64,
+    pub instance_id: String,
+    pub linked_trace_ids: Vec<String>,
+    pub span_count: usize,
+    pub error_count: usize,
+}
+
+impl From<&TraceComplete> for TraceSummary {
+    fn from(trace: &TraceComplete) -> Self {
+        Self {
+            trace_id: trace.trace_id.clone(),
+            root_span_name: trace.root_span_name.clone(),
+            duration_ms: trace.duration_ms,
+            started_at: trace.started_at,
+            instance_id: trace.instance_id.clone(),
+            linked_trace_ids: trace.linked_trace_ids.clone(),
+            span_count: trace.stats.span_count,
+            error_count: trace.stats.error_count,
+        }
+    }
+}
+
+/// How much of each finalized trace a WebSocket client receives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceDetail {
+    /// `trace_summary` messages.
+    #[default]
+    Summary,
+    /// `trace_complete` messages, spans included.
+    Full,
+    /// No per-trace messages; the client assembles traces from `spans_batch`.
+    None,
+}
+
+pub fn now_unix_nano() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// A broadcast message together with its JSON renderings. Each rendering is
+/// produced at most once and shared by every client that asked for it, so
+/// clients forward the same bytes without re-serializing.
+pub struct WsFrame {
+    pub message: WsMessage,
+    /// When the message was published, so that clients which lagged can be
+    /// told which time range they missed.
+    pub published_unix_nano: u64,
+    typed_json: OnceLock<Arc<String>>,
+    string_json: OnceLock<Arc<String>>,
+    summary_json: OnceLock<Arc<String>>,
+}
+
+impl WsFrame {
+    pub fn new(message: WsMessage) -> Self {
+        Self::published_at(message, now_unix_nano())
+    }
+
+    pub fn published_at(message: WsMessage, published_unix_nano: u64) -> Self {
+        Self {
+            message,
+            published_unix_nano,
+            typed_json: OnceLock::new(),
+            string_json: OnceLock::new(),
+            summary_json: OnceLock::new(),
+        }
+    }
+
+    /// The JSON to send to a client with the given preferences, or `None`
+    /// when the client does not want this message at all.
+    pub fn json(&self, format: AttrFormat, traces: TraceDetail) -> Option<Arc<String>> {
+        if let WsMessage::TraceComplete(trace) = &self.message {
+            match traces {
+                TraceDetail::None => return None,
+                TraceDetail::Summary => {
+                    let json = self.summary_json.get_or_init(|| {
+                        let summary = WsMessage::TraceSummary(TraceSummary::from(trace));
+                        Arc::new(serde_json::to_string(&summary).unwrap_or_default())
+                    });
+                    return Some(json.clone());
+                }
+                TraceDetail::Full => {}
+            }
+        }
+        let cell = match format {
+            AttrFormat::Typed  => &self.typed_json,
+            AttrFormat::String => &self.string_json,
+        };
+        let json = cell.get_or_init(|| {
+            let json = with_attr_format(format, || serde_json::to_string(&self.message));
+            Arc::new(json.unwrap_or_default())
+        });
+        Some(json.clone())
+    }
+}
+
+/// Spans of a trace that has not been finalized yet, keyed by span_id.
+#[derive(Debug)]
+pub struct InFlightTrace {
+    pub spans: HashMap<String, SpanEvent>,
+    /// When the root span arrived — or, for a trace that was already
+    /// finalized, when its first late span arrived. `None` until then.
+    pub root_seen_at: Option<Instant>,
+    pub last_span_at: Instant,
 }
 
-/// In-flight spans keyed by trace_id, then by span_id.
-pub type InFlightTraces = DashMap<String, HashMap<String, SpanEvent>>;
+impl InFlightTrace {
+    fn new() -> Self {
+        Self { spans: HashMap::new(), root_seen_at: None, last_span_at: Instant::now() }
+    }
+}
+
+/// In-flight traces keyed by trace_id.
+pub type InFlightTraces = DashMap<String, InFlightTrace>;
+
+/// Thresholds past which OTLP exports are refused so that exporters back off
+/// and retry instead of having their data dropped on the floor, and past
+/// which metric series are collapsed (see [`SeriesCatalog`]).
+#[derive(Debug, Clone, Copy)]
+pub struct IngestLimits {
+    /// Maximum number of traces waiting for their root span.
+    pub max_in_flight_traces: usize,
+    /// Maximum number of finalized traces waiting to be written to SQLite.
+    pub max_pending_writes: usize,
+    /// Maximum number of series of one metric.
+    pub max_series_per_metric: usize,
+    /// Maximum number of series of all metrics together.
+    pub max_metric_series: usize,
+}
+
+/// Why the backend is currently refusing new spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overload {
+    /// `in_flight` holds `max_in_flight_traces` traces.
+    InFlightFull,
+    /// SQLite persistence is lagging behind by `max_pending_writes` traces.
+    WriteBacklog,
+}
 
 pub struct AppState {
-    /// Pre-serialized JSON strings are broadcast so each connected WS client
-    /// can forward the same bytes without re-serializing.
-    pub broadcast: broadcast::Sender<Arc<String>>,
+    pub broadcast: broadcast::Sender<Arc<WsFrame>>,
     pub in_flight: InFlightTraces,
+    /// Traces finalized within the last `cleanup_stale_traces` window, so that
+    /// late spans are recognised and merged instead of waiting for a root.
+    pub recently_finalized: DashMap<String, Instant>,
+    /// How long a rooted trace must go without new spans before it is
+    /// finalized. Zero finalizes as soon as the root span arrives.
+    pub quiescence: Duration,
     pub total_traces: std::sync::atomic::AtomicU64,
     pub total_spans: std::sync::atomic::AtomicU64,
     /// Optional SQLite persistence layer.
     pub db: Arc<Db>,
+    /// Persists finalized traces; its queue depth is the write backlog.
+    pub writer: TraceWriter,
+    pub metric_writer: MetricWriter,
+    pub limits: IngestLimits,
+    pub resources: ResourceRegistry,
+    /// Recently published span, log and metric batches, replayed to new
+    /// WebSocket clients that ask for a snapshot.
+    pub recent: Mutex<RecentBuffer>,
+    /// Reported in `/config`; applied by the prune task in `main`.
+    pub retention: RetentionPolicy,
+    /// Metric data points the OTLP receiver could not forward.
+    pub dropped_metric_points: DroppedPointsRegistry,
+    /// Last point of every sum series, for deltas and rates.
+    pub sum_series: SumTracker,
+    /// Metrics and series seen recently, capped by `limits`.
+    pub metric_series: SeriesCatalog,
 }
 
 impl AppState {
-    pub fn new(db: Arc<Db>) -> Self {
-        let (tx, _): (broadcast::Sender<Arc<String>>, _) = broadcast::channel(4096);
+    pub fn new(
+        db: Arc<Db>,
+        writer: TraceWriter,
+        metric_writer: MetricWriter,
+        limits: IngestLimits,
+        quiescence: Duration,
+        recent_capacity: usize,
+        retention: RetentionPolicy,
+    ) -> Self {
+        let (tx, _): (broadcast::Sender<Arc<WsFrame>>, _) = broadcast::channel(4096);
         Self {
             broadcast: tx,
             in_flight: DashMap::new(),
+            recently_finalized: DashMap::new(),
+            quiescence,
             total_traces: std::sync::atomic::AtomicU64::new(0),
             total_spans: std::sync::atomic::AtomicU64::new(0),
             db,
+            writer,
+            metric_writer,
+            limits,
+            resources: ResourceRegistry::default(),
+            recent: Mutex::new(RecentBuffer::new(recent_capacity)),
+            retention,
+            dropped_metric_points: DroppedPointsRegistry::default(),
+            sum_series: SumTracker::default(),
+            metric_series: SeriesCatalog::new(limits.max_series_per_metric, limits.max_metric_series),
+        }
+    }
+
+    /// Register the resource described by `attributes`, persisting it the
+    /// first time it is seen.
+    pub fn register_resource(&self, attributes: Attributes) -> Arc<ResourceInfo> {
+        let (info, inserted) = self.resources.register(attributes, now_unix_nano());
+        if inserted {
+            let db = Arc::clone(&self.db);
+            let info = Arc::clone(&info);
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = db.insert_resource(&info) {
+                    tracing::error!("Failed to persist resource: {}", e);
+                }
+            });
+        }
+        info
+    }
+
+    /// Send a message to every connected WebSocket client.
+    pub fn publish(&self, message: WsMessage) {
+        let frame = Arc::new(WsFrame::new(message));
+        // Record and send under the same lock, so that `subscribe_with_recent`
+        // hands out every message exactly once.
+        let mut recent = self.recent.lock().unwrap();
+        recent.push(Arc::clone(&frame));
+        let _ = self.broadcast.send(frame);
+    }
+
+    /// Subscribe to the broadcast channel, together with the buffered
+    /// messages published before the subscription: no gap, no overlap.
+    pub fn subscribe_with_recent(&self) -> (broadcast::Receiver<Arc<WsFrame>>, Vec<Arc<WsFrame>>) {
+        let recent = self.recent.lock().unwrap();
+        (self.broadcast.subscribe(), recent.frames())
+    }
+
+    /// Returns the reason new spans should be refused, if any.
+    pub fn overload(&self) -> Option<Overload> {
+        if self.writer.queue_depth() >= self.limits.max_pending_writes {
+            Some(Overload::WriteBacklog)
+        } else if self.in_flight.len() >= self.limits.max_in_flight_traces {
+            Some(Overload::InFlightFull)
+        } else {
+            None
         }
     }
 
     /// Returns a JSON blob with the backend configuration sent to every new
     /// WebSocket client (and also available via GET /config).
     pub fn get_config_json(&self) -> Arc<String> {
-        Arc::new(serde_json::json!({}).to_string())
+        Arc::new(serde_json::json!({ "retention": self.retention }).to_string())
+    }
+
+    /// Add a batch of spans to `in_flight`. Traces whose root is in the batch
+    /// are finalized right away when there is no quiescence window; otherwise
+    /// [`AppState::finalize_quiescent_traces`] picks them up once they go quiet.
+    pub fn ingest_spans(self: &Arc<Self>, batch: Vec<SpanEvent>) {
+        let now = Instant::now();
+        let mut rooted: Vec<String> = Vec::new();
+        for s in batch {
+            let is_root = s.parent_span_id.is_none() || self.recently_finalized.contains_key(&s.trace_id);
+            let mut entry = self.in_flight.entry(s.trace_id.clone()).or_insert_with(InFlightTrace::new);
+            entry.last_span_at = now;
+            if is_root && entry.root_seen_at.is_none() {
+                entry.root_seen_at = Some(now);
+                rooted.push(s.trace_id.clone());
+            }
+            entry.spans.insert(s.span_id.clone(), s);
+        }
+
+        if self.quiescence.is_zero() {
+            for trace_id in rooted {
+                self.finalize_trace(&trace_id);
+            }
+        }
+    }
+
+    /// Finalize rooted traces that have not received a span for `quiescence`.
+    pub fn finalize_quiescent_traces(self: &Arc<Self>) {
+        let ready: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|entry| {
+                entry.root_seen_at.is_some() && entry.last_span_at.elapsed() >= self.quiescence
+            })
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        for trace_id in ready {
+            self.finalize_trace(&trace_id);
+        }
     }
 
     pub fn finalize_trace(self: &Arc<Self>, trace_id: &str) {
-        if let Some((_, spans_map)) = self.in_flight.remove(trace_id) {
-            self.total_traces.fetch_add(1, Ordering::Relaxed);
-
-            let mut spans: Vec<SpanEvent> = spans_map.into_values().collect();
-            spans.sort_by_key(|s| s.start_time_unix_nano);
-
-            let started_at = spans.first().map(|s| s.start_time_unix_nano).unwrap_or(0);
-            let ended_at = spans.iter().map(|s| s.end_time_unix_nano).max().unwrap_or(0);
-            let duration_ms = (ended_at.saturating_sub(started_at)) as f64 / 1_000_000.0;
-            let root_span = spans.iter().find(|s| s.parent_span_id.is_none());
-            let root_span_name = root_span.map(|s| s.name.clone()).unwrap_or_default();
-            let instance_id = root_span.map(|s| s.instance_id.clone()).unwrap_or_default();
-
-            let trace = TraceComplete {
-                trace_id: trace_id.to_string(),
-                spans,
-                root_span_name,
-                duration_ms,
-                started_at,
-                instance_id,
-            };
-
-            // Persist trace to SQLite asynchronously.
-            let db = Arc::clone(&self.db);
-            let service_name = trace.spans
-                .iter()
-                .find(|s| s.parent_span_id.is_none())
-                .map(|s| s.service_name.clone())
-                .unwrap_or_default();
-            tokio::spawn(async move {
-                if let Err(e) = tokio::task::spawn_blocking(move || {
-                    db.insert_trace(&trace, &service_name, &trace.instance_id)
-                }).await {
-                    tracing::error!("Failed to persist trace: {}", e);
-                }
-            });
+        if let Some((_, in_flight)) = self.in_flight.remove(trace_id) {
+            if self.recently_finalized.insert(trace_id.to_string(), Instant::now()).is_none() {
+                self.total_traces.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let trace = TraceComplete::from_spans(trace_id, in_flight.spans.into_values().collect());
+
+            // Persist trace to SQLite asynchronously. Spans of a trace that
+            // was already persisted are merged into the stored row, which
+            // `publish_written` then broadcasts.
+            if let Some(trace) = self.writer.enqueue(trace) {
+                tracing::error!("Trace write queue is full, trace {} not persisted", trace.trace_id);
+                self.publish(WsMessage::TraceComplete(trace));
+            }
         }
     }
 
-    /// Evict in-flight traces older than `max_age` whose spans have never produced
-    /// a root span (e.g. orphan partial traces dropped by the exporter).
+    /// Broadcast the traces handed back by the writer once persisted.
+    pub async fn publish_written(self: Arc<Self>, mut written: UnboundedReceiver<TraceComplete>) {
+        while let Some(trace) = written.recv().await {
+            self.publish(WsMessage::TraceComplete(trace));
+        }
+    }
+
+    /// Evict in-flight traces that have not received a span for `max_age` and
+    /// never produced a root span (e.g. orphan partial traces dropped by the
+    /// exporter). Also forgets traces finalized more than `max_age` ago.
     /// Called periodically from a background task so neither the index maps nor
     /// in_flight grow without bound under abnormal conditions.
     pub fn cleanup_stale_traces(self: &Arc<Self>, max_age: Duration) {
-        let now_ns = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_nanos() as u64;
-        let cutoff_ns = now_ns.saturating_sub(max_age.as_nanos() as u64);
+        self.recently_finalized.retain(|_, at| at.elapsed() < max_age);
 
         let stale: Vec<String> = self
             .in_flight
             .iter()
-            .filter(|entry| {
-                // A trace is stale if every span in it started before the cutoff,
-                // or if the entry is somehow empty.
-                entry
-                    .value()
-                    .values()
-                    .next()
-                    .map(|s| s.start_time_unix_nano < cutoff_ns)
-                    .unwrap_or(true)
-            })
+            .filter(|entry| entry.last_span_at.elapsed() >= max_age)
             .map(|entry| entry.key().clone())
             .collect();
 
@@ -196,5 +841,3 @@ impl AppState {
     }
 
 }
-
-
@@ -0,0 +1,597 @@
+//! Per-connection WebSocket subscriptions: which signals a client receives,
+//! server-side filters, and pause/resume. Updated through JSON control
+//! messages sent by the client.
+//!
+//! Also decides what a client that fell behind the broadcast channel is told
+//! and sent next, according to its [`LagPolicy`].
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::resource::ResourceRegistry;
+use crate::state::{
+    now_unix_nano, AttrFormat, LogEvent, MetricEvent, SpanEvent, TraceComplete, TraceDetail, WsFrame,
+    WsMessage,
+};
+
+/// A stream of broadcast messages a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    /// `spans_batch`
+    Spans,
+    /// `metrics_batch`
+    Metrics,
+    /// `logs_batch`
+    Logs,
+    /// `trace_complete` / `trace_summary`
+    Traces,
+}
+
+impl Signal {
+    const ALL: [Signal; 4] = [Signal::Spans, Signal::Metrics, Signal::Logs, Signal::Traces];
+
+    fn of(message: &WsMessage) -> Signal {
+        match message {
+            WsMessage::SpansBatch { .. }   => Signal::Spans,
+            WsMessage::MetricsBatch { .. } => Signal::Metrics,
+            WsMessage::LogsBatch { .. }    => Signal::Logs,
+            WsMessage::TraceComplete(_) | WsMessage::TraceSummary(_) => Signal::Traces,
+        }
+    }
+}
+
+/// Server-side filters. Every set criterion must match; criteria that do not
+/// apply to a signal (e.g. `min_severity` for spans) are ignored for it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientFilter {
+    /// Keep only these services (exact match).
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// Keep only these `service.instance.id`s (exact match).
+    #[serde(default)]
+    pub instances: Vec<String>,
+    /// Keep spans whose target, and logs whose scope, start with this prefix.
+    pub target_prefix: Option<String>,
+    /// Keep spans (and traces whose root span) have a name containing this.
+    pub span_name: Option<String>,
+    /// Keep logs with at least this OTLP severity number (9 = INFO, 13 = WARN, 17 = ERROR).
+    pub min_severity: Option<i32>,
+}
+
+impl ClientFilter {
+    fn is_empty(&self) -> bool {
+        self.services.is_empty()
+            && self.instances.is_empty()
+            && self.target_prefix.is_none()
+            && self.span_name.is_none()
+            && self.min_severity.is_none()
+    }
+
+    fn service_ok(&self, service: &str) -> bool {
+        self.services.is_empty() || self.services.iter().any(|s| s == service)
+    }
+
+    fn instance_ok(&self, instance: &str) -> bool {
+        self.instances.is_empty() || self.instances.iter().any(|i| i == instance)
+    }
+
+    fn target_ok(&self, target: &str) -> bool {
+        self.target_prefix.as_deref().is_none_or(|p| target.starts_with(p))
+    }
+
+    fn name_ok(&self, name: &str) -> bool {
+        self.span_name.as_deref().is_none_or(|n| name.contains(n))
+    }
+
+    fn matches_span(&self, s: &SpanEvent) -> bool {
+        self.service_ok(&s.service_name)
+            && self.instance_ok(&s.instance_id)
+            && self.target_ok(&s.target)
+            && self.name_ok(&s.name)
+    }
+
+    fn matches_log(&self, l: &LogEvent, resources: &ResourceRegistry) -> bool {
+        self.service_ok(&l.service_name)
+            && (self.instances.is_empty() || self.instance_ok(&instance_of(&l.resource_id, resources)))
+            && self.target_ok(&l.scope_name)
+            && self.min_severity.is_none_or(|min| l.severity_number >= min)
+    }
+
+    fn matches_metric(&self, m: &MetricEvent, resources: &ResourceRegistry) -> bool {
+        self.service_ok(&m.service_name)
+            && (self.instances.is_empty() || self.instance_ok(&instance_of(&m.resource_id, resources)))
+    }
+
+    /// A trace matches when any of its spans is from a wanted service and
+    /// target, its root instance is wanted and its root span name matches.
+    fn matches_trace(&self, t: &TraceComplete) -> bool {
+        self.instance_ok(&t.instance_id)
+            && self.name_ok(&t.root_span_name)
+            && t.spans.iter().any(|s| self.service_ok(&s.service_name) && self.target_ok(&s.target))
+    }
+
+    /// The part of `message` that passes the filter, or `None` if nothing does.
+    fn apply(&self, message: &WsMessage, resources: &ResourceRegistry) -> Option<WsMessage> {
+        match message {
+            WsMessage::SpansBatch { spans } => {
+                let spans: Vec<SpanEvent> = spans.iter().filter(|s| self.matches_span(s)).cloned().collect();
+                (!spans.is_empty()).then_some(WsMessage::SpansBatch { spans })
+            }
+            WsMessage::MetricsBatch { metrics } => {
+                let metrics: Vec<MetricEvent> =
+                    metrics.iter().filter(|m| self.matches_metric(m, resources)).cloned().collect();
+                (!metrics.is_empty()).then_some(WsMessage::MetricsBatch { metrics })
+            }
+            WsMessage::LogsBatch { logs } => {
+                let logs: Vec<LogEvent> =
+                    logs.iter().filter(|l| self.matches_log(l, resources)).cloned().collect();
+                (!logs.is_empty()).then_some(WsMessage::LogsBatch { logs })
+            }
+            WsMessage::TraceComplete(t) => self.matches_trace(t).then(|| message.clone()),
+            WsMessage::TraceSummary(_) => Some(message.clone()),
+        }
+    }
+}
+
+fn instance_of(resource_id: &str, resources: &ResourceRegistry) -> String {
+    resources
+        .get(resource_id)
+        .map(|r| r.instance_id.clone())
+        .unwrap_or_default()
+}
+
+/// Control messages sent by the client.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    Subscribe { signals: Vec<Signal> },
+    Unsubscribe { signals: Vec<Signal> },
+    /// Replace the current filter, e.g. `{"type": "set_filter", "services": ["api"]}`.
+    SetFilter(ClientFilter),
+    ClearFilter,
+    /// Stop forwarding messages (they are dropped, not queued) until `resume`.
+    Pause,
+    Resume,
+}
+
+/// What to do when a client falls so far behind that broadcast messages were
+/// dropped for it. A `gap` message is sent first in every case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// Skip what was dropped and carry on from the oldest buffered message.
+    #[default]
+    DropOldest,
+    /// Also drain everything still buffered and send it merged into one
+    /// batch per signal, so the client catches up in a few frames.
+    Coalesce,
+    /// Close the connection; the client is expected to reconnect.
+    Disconnect,
+}
+
+/// Messages sent to one client only, outside the broadcast stream.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionMessage<'a> {
+    /// The subscription state after applying a control message.
+    Subscription {
+        signals: &'a BTreeSet<Signal>,
+        filter: &'a ClientFilter,
+        paused: bool,
+    },
+    ControlError { message: String },
+    /// `dropped` broadcast messages published between the two timestamps
+    /// were never delivered to this client.
+    Gap {
+        dropped: u64,
+        from_unix_nano: u64,
+        to_unix_nano: u64,
+        policy: LagPolicy,
+    },
+    /// Sent after the snapshot requested on connect; live messages follow.
+    SnapshotComplete {
+        traces: usize,
+        spans: usize,
+        logs: usize,
+        metrics: usize,
+    },
+}
+
+/// What to send after the client lagged.
+pub struct CatchUp {
+    /// The serialized `gap` message.
+    pub notice: String,
+    pub frames: Vec<Arc<String>>,
+    pub disconnect: bool,
+}
+
+/// Everything that decides what one WebSocket client receives.
+pub struct ClientSession {
+    pub attr_format: AttrFormat,
+    pub traces: TraceDetail,
+    pub signals: BTreeSet<Signal>,
+    pub filter: ClientFilter,
+    pub paused: bool,
+    pub on_lag: LagPolicy,
+    /// Publication time of the last broadcast message received.
+    last_seen_unix_nano: u64,
+}
+
+impl ClientSession {
+    /// A session subscribed to every signal, without filters.
+    pub fn new(attr_format: AttrFormat, traces: TraceDetail, on_lag: LagPolicy) -> Self {
+        Self {
+            attr_format,
+            traces,
+            signals: Signal::ALL.into_iter().collect(),
+            filter: ClientFilter::default(),
+            paused: false,
+            on_lag,
+            last_seen_unix_nano: now_unix_nano(),
+        }
+    }
+
+    /// The JSON to forward for `frame`, or `None` if this client should not
+    /// see it. Unfiltered clients share the frame's cached serialization.
+    pub fn render(&mut self, frame: &WsFrame, resources: &ResourceRegistry) -> Option<Arc<String>> {
+        self.last_seen_unix_nano = self.last_seen_unix_nano.max(frame.published_unix_nano);
+        self.render_filtered(frame, resources)
+    }
+
+    fn render_filtered(&self, frame: &WsFrame, resources: &ResourceRegistry) -> Option<Arc<String>> {
+        if self.paused || !self.signals.contains(&Signal::of(&frame.message)) {
+            return None;
+        }
+        if self.filter.is_empty() {
+            return frame.json(self.attr_format, self.traces);
+        }
+        let filtered = self.filter.apply(&frame.message, resources)?;
+        WsFrame::new(filtered).json(self.attr_format, self.traces)
+    }
+
+    /// Apply a raw control message and return the JSON reply.
+    pub fn handle_control(&mut self, text: &str) -> String {
+        let msg = match serde_json::from_str::<ControlMessage>(text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                let reply = SessionMessage::ControlError { message: e.to_string() };
+                return serde_json::to_string(&reply).unwrap_or_default();
+            }
+        };
+        match msg {
+            ControlMessage::Subscribe { signals }   => self.signals.extend(signals),
+            ControlMessage::Unsubscribe { signals } => self.signals.retain(|s| !signals.contains(s)),
+            ControlMessage::SetFilter(filter)       => self.filter = filter,
+            ControlMessage::ClearFilter             => self.filter = ClientFilter::default(),
+            ControlMessage::Pause                   => self.paused = true,
+            ControlMessage::Resume                  => self.paused = false,
+        }
+        let reply = SessionMessage::Subscription {
+            signals: &self.signals,
+            filter: &self.filter,
+            paused: self.paused,
+        };
+        serde_json::to_string(&reply).unwrap_or_default()
+    }
+
+    /// Called after `rx` reported `dropped` lost messages: pulls what the
+    /// policy wants sent next from `rx` and builds the `gap` notice. The gap
+    /// ends at the oldest message still buffered (or now, if none is).
+    pub fn catch_up(
+        &mut self,
+        rx: &mut broadcast::Receiver<Arc<WsFrame>>,
+        mut dropped: u64,
+        resources: &ResourceRegistry,
+    ) -> CatchUp {
+        let mut received = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(frame) => {
+                    received.push(frame);
+                    if self.on_lag != LagPolicy::Coalesce {
+                        break;
+                    }
+                }
+                Err(TryRecvError::Lagged(n)) => dropped += n,
+                Err(_) => break,
+            }
+        }
+
+        let from_unix_nano = self.last_seen_unix_nano;
+        let to_unix_nano = received.first().map_or_else(now_unix_nano, |f| f.published_unix_nano);
+        let notice = SessionMessage::Gap { dropped, from_unix_nano, to_unix_nano, policy: self.on_lag };
+        let notice = serde_json::to_string(&notice).unwrap_or_default();
+
+        let received = match self.on_lag {
+            LagPolicy::Disconnect => Vec::new(),
+            LagPolicy::DropOldest => received,
+            LagPolicy::Coalesce => coalesce(received),
+        };
+        let frames = received.iter().filter_map(|f| self.render(f, resources)).collect();
+        CatchUp { notice, frames, disconnect: self.on_lag == LagPolicy::Disconnect }
+    }
+}
+
+/// Merge batches into one per signal. Finalized traces are kept as they are,
+/// except that only the latest version of a re-finalized trace is kept.
+fn coalesce(frames: Vec<Arc<WsFrame>>) -> Vec<Arc<WsFrame>> {
+    let Some(published) = frames.last().map(|f| f.published_unix_nano) else {
+        return frames;
+    };
+    let (mut spans, mut metrics, mut logs) = (Vec::new(), Vec::new(), Vec::new());
+    let mut traces: Vec<Arc<WsFrame>> = Vec::new();
+    for frame in frames {
+        match &frame.message {
+            WsMessage::SpansBatch { spans: s }   => spans.extend(s.iter().cloned()),
+            WsMessage::MetricsBatch { metrics: m } => metrics.extend(m.iter().cloned()),
+            WsMessage::LogsBatch { logs: l }     => logs.extend(l.iter().cloned()),
+            WsMessage::TraceComplete(t) => {
+                traces.retain(|f| !matches!(&f.message, WsMessage::TraceComplete(o) if o.trace_id == t.trace_id));
+                traces.push(frame);
+            }
+            WsMessage::TraceSummary(_) => traces.push(frame),
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut push = |message| out.push(Arc::new(WsFrame::published_at(message, published)));
+    if !spans.is_empty() {
+        push(WsMessage::SpansBatch { spans });
+    }
+    if !metrics.is_empty() {
+        push(WsMessage::MetricsBatch { metrics });
+    }
+    if !logs.is_empty() {
+        push(WsMessage::LogsBatch { logs });
+    }
+    out.extend(traces);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{AttrValue, MetricValue};
+    use crate::testing::{log, span, trace};
+
+    fn session() -> ClientSession {
+        ClientSession::new(AttrFormat::Typed, TraceDetail::Full, LagPolicy::DropOldest)
+    }
+
+    fn control(session: &mut ClientSession, text: &str) -> serde_json::Value {
+        serde_json::from_str(&session.handle_control(text)).unwrap()
+    }
+
+    /// What `session` is sent for `message`, parsed.
+    fn render(
+        session: &mut ClientSession,
+        message: WsMessage,
+        resources: &ResourceRegistry,
+    ) -> Option<serde_json::Value> {
+        let json = session.render(&WsFrame::new(message), resources)?;
+        Some(serde_json::from_str(&json).unwrap())
+    }
+
+    fn spans(services: &[&str]) -> WsMessage {
+        WsMessage::SpansBatch { spans: services.iter().map(|s| span("t", s, "ok", 1)).collect() }
+    }
+
+    fn services(json: &serde_json::Value, items: &str) -> Vec<String> {
+        json[items].as_array().unwrap().iter().map(|i| i["service_name"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn control_messages_update_the_subscription() {
+        let mut session = session();
+        let reply = control(&mut session, r#"{"type": "unsubscribe", "signals": ["metrics", "logs"]}"#);
+        assert_eq!(reply["type"], "subscription");
+        assert_eq!(reply["signals"], serde_json::json!(["spans", "traces"]));
+
+        let reply = control(&mut session, r#"{"type": "set_filter", "services": ["api"], "min_severity": 13}"#);
+        assert_eq!(reply["filter"]["services"], serde_json::json!(["api"]));
+        assert_eq!(session.filter.min_severity, Some(13));
+
+        assert_eq!(control(&mut session, r#"{"type": "pause"}"#)["paused"], true);
+        assert_eq!(control(&mut session, r#"{"type": "resume"}"#)["paused"], false);
+        control(&mut session, r#"{"type": "clear_filter"}"#);
+        control(&mut session, r#"{"type": "subscribe", "signals": ["logs"]}"#);
+        assert!(session.filter.is_empty());
+        assert_eq!(session.signals, [Signal::Spans, Signal::Logs, Signal::Traces].into_iter().collect());
+
+        for bad in ["not json", r#"{"type": "subscribe", "signals": ["events"]}"#, r#"{"type": "reset"}"#] {
+            assert_eq!(control(&mut session, bad)["type"], "control_error", "{bad}");
+        }
+    }
+
+    #[test]
+    fn unsubscribed_and_paused_sessions_get_nothing() {
+        let resources = ResourceRegistry::default();
+        let mut session = session();
+        control(&mut session, r#"{"type": "unsubscribe", "signals": ["spans"]}"#);
+        assert!(render(&mut session, spans(&["api"]), &resources).is_none());
+        assert!(render(&mut session, WsMessage::LogsBatch { logs: vec![log("api", 9, None, 1)] }, &resources).is_some());
+
+        control(&mut session, r#"{"type": "pause"}"#);
+        assert!(render(&mut session, WsMessage::LogsBatch { logs: vec![log("api", 9, None, 1)] }, &resources).is_none());
+    }
+
+    #[test]
+    fn filters_keep_the_matching_part_of_a_batch() {
+        let resources = ResourceRegistry::default();
+        let mut session = session();
+        control(&mut session, r#"{"type": "set_filter", "services": ["api", "db"], "min_severity": 13}"#);
+
+        let json = render(&mut session, spans(&["api", "web", "db"]), &resources).unwrap();
+        assert_eq!(json["type"], "spans_batch");
+        assert_eq!(services(&json, "spans"), ["api", "db"]);
+        assert!(render(&mut session, spans(&["web"]), &resources).is_none());
+
+        let logs = vec![log("api", 9, None, 1), log("api", 17, None, 1), log("web", 17, None, 1)];
+        let json = render(&mut session, WsMessage::LogsBatch { logs }, &resources).unwrap();
+        assert_eq!(json["logs"].as_array().unwrap().len(), 1);
+        assert_eq!(json["logs"][0]["severity_number"], 17);
+    }
+
+    #[test]
+    fn filters_match_traces_and_metrics() {
+        let resources = ResourceRegistry::default();
+        let (resource, _) = resources.register(
+            vec![
+                ("service.name".into(), AttrValue::String("api".into())),
+                ("service.instance.id".into(), AttrValue::String("api-1".into())),
+            ],
+            0,
+        );
+        let mut session = session();
+        control(&mut session, r#"{"type": "set_filter", "services": ["api"], "span_name": "GET"}"#);
+
+        let json = render(&mut session, WsMessage::TraceComplete(trace("t1", "api", "ok", 1)), &resources).unwrap();
+        assert_eq!(json["trace_id"], "t1");
+        assert!(render(&mut session, WsMessage::TraceComplete(trace("t2", "web", "ok", 1)), &resources).is_none());
+
+        let metric = |service: &str, resource_id: &str| MetricEvent {
+            service_name: service.into(),
+            metric_name: "requests".into(),
+            description: String::new(),
+            unit: String::new(),
+            timestamp_unix_nano: 1,
+            attributes: vec![],
+            value: MetricValue::Gauge { value: 1.0 },
+            resource_id: resource_id.into(),
+            scope_name: String::new(),
+            scope_version: String::new(),
+        };
+        control(&mut session, r#"{"type": "set_filter", "instances": ["api-1"]}"#);
+        let metrics = vec![metric("api", &resource.resource_id), metric("api", "unknown")];
+        let json = render(&mut session, WsMessage::MetricsBatch { metrics }, &resources).unwrap();
+        assert_eq!(json["metrics"].as_array().unwrap().len(), 1);
+        assert_eq!(json["metrics"][0]["resource_id"], resource.resource_id.as_str());
+    }
+
+    /// A receiver of a channel of `capacity` frames that was sent one frame
+    /// per message, published at 1, 2, …, and the number it dropped.
+    fn lagged(capacity: usize, messages: Vec<WsMessage>) -> (broadcast::Receiver<Arc<WsFrame>>, u64) {
+        let (tx, mut rx) = broadcast::channel(capacity);
+        for (i, message) in messages.into_iter().enumerate() {
+            assert!(tx.send(Arc::new(WsFrame::published_at(message, i as u64 + 1))).is_ok());
+        }
+        match rx.try_recv() {
+            Err(TryRecvError::Lagged(n)) => (rx, n),
+            _ => panic!("expected a lag"),
+        }
+    }
+
+    fn lagging_session(on_lag: LagPolicy) -> ClientSession {
+        let mut session = ClientSession::new(AttrFormat::Typed, TraceDetail::Full, on_lag);
+        session.last_seen_unix_nano = 0;
+        session
+    }
+
+    /// `dropped`, `from_unix_nano` and `to_unix_nano` of a `gap` notice.
+    fn gap(notice: &str) -> (u64, u64, u64) {
+        let gap: serde_json::Value = serde_json::from_str(notice).unwrap();
+        assert_eq!(gap["type"], "gap");
+        let field = |name: &str| gap[name].as_u64().unwrap();
+        (field("dropped"), field("from_unix_nano"), field("to_unix_nano"))
+    }
+
+    /// Spans batches as their services joined by `+`, traces as `id:spans`.
+    fn outline(frames: &[Arc<WsFrame>]) -> Vec<String> {
+        frames
+            .iter()
+            .map(|f| match &f.message {
+                WsMessage::SpansBatch { spans } => {
+                    spans.iter().map(|s| s.service_name.as_str()).collect::<Vec<_>>().join("+")
+                }
+                WsMessage::TraceComplete(t) => format!("{}:{}", t.trace_id, t.spans.len()),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect()
+    }
+
+    fn retried_trace() -> TraceComplete {
+        let mut trace = trace("t1", "api", "ok", 1);
+        trace.spans.push(span("t1", "db", "ok", 2));
+        trace
+    }
+
+    #[test]
+    fn drop_oldest_resumes_from_the_oldest_buffered_message() {
+        let resources = ResourceRegistry::default();
+        let (mut rx, dropped) = lagged(2, vec![spans(&["a"]), spans(&["b"]), spans(&["c"]), spans(&["d"])]);
+        let mut session = lagging_session(LagPolicy::DropOldest);
+
+        let catch_up = session.catch_up(&mut rx, dropped, &resources);
+        assert_eq!(gap(&catch_up.notice), (2, 0, 3));
+        assert!(!catch_up.disconnect);
+        assert_eq!(catch_up.frames.len(), 1);
+        let json: serde_json::Value = serde_json::from_str(&catch_up.frames[0]).unwrap();
+        assert_eq!(services(&json, "spans"), ["c"]);
+        // The rest is left to the live loop.
+        assert_eq!(rx.try_recv().unwrap().published_unix_nano, 4);
+    }
+
+    #[test]
+    fn coalesce_sends_everything_buffered_merged() {
+        let resources = ResourceRegistry::default();
+        let (mut rx, dropped) = lagged(
+            4,
+            vec![
+                spans(&["a"]),
+                spans(&["b"]),
+                spans(&["c"]),
+                WsMessage::TraceComplete(trace("t1", "api", "ok", 1)),
+                spans(&["d"]),
+                WsMessage::TraceComplete(retried_trace()),
+            ],
+        );
+        let mut session = lagging_session(LagPolicy::Coalesce);
+
+        let catch_up = session.catch_up(&mut rx, dropped, &resources);
+        assert_eq!(gap(&catch_up.notice), (2, 0, 3));
+        let frames: Vec<serde_json::Value> =
+            catch_up.frames.iter().map(|f| serde_json::from_str(f).unwrap()).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(services(&frames[0], "spans"), ["c", "d"]);
+        assert_eq!(frames[1]["type"], "trace_complete");
+        assert_eq!(frames[1]["spans"].as_array().unwrap().len(), 2);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn coalesce_keeps_the_latest_version_of_a_trace() {
+        let frames = [
+            spans(&["a"]),
+            WsMessage::TraceComplete(trace("t1", "api", "ok", 1)),
+            spans(&["b"]),
+            WsMessage::TraceComplete(trace("t2", "api", "ok", 1)),
+            WsMessage::TraceComplete(retried_trace()),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, m)| Arc::new(WsFrame::published_at(m, i as u64)))
+        .collect();
+
+        let merged = coalesce(frames);
+        assert_eq!(outline(&merged), ["a+b", "t2:1", "t1:2"]);
+        // Merged batches are dated by the last frame; traces keep their date.
+        let published: Vec<u64> = merged.iter().map(|f| f.published_unix_nano).collect();
+        assert_eq!(published, [4, 3, 4]);
+    }
+
+    #[test]
+    fn disconnect_only_sends_the_gap() {
+        let resources = ResourceRegistry::default();
+        let (mut rx, dropped) = lagged(2, vec![spans(&["a"]), spans(&["b"]), spans(&["c"])]);
+        let mut session = lagging_session(LagPolicy::Disconnect);
+
+        let catch_up = session.catch_up(&mut rx, dropped, &resources);
+        assert_eq!(gap(&catch_up.notice), (1, 0, 2));
+        assert!(catch_up.disconnect);
+        assert!(catch_up.frames.is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+//! Fixtures shared by the unit tests.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::Db;
+use crate::retention::RetentionPolicy;
+use crate::state::{AppState, IngestLimits, LogEvent, SpanEvent, TraceComplete};
+use crate::writer::{MetricWriter, TraceWriter, WriterConfig};
+
+/// A database path in the temporary directory, unique to this test process.
+/// The file and its WAL companions are deleted on drop.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("otel-ui-{name}-{}.db", std::process::id())))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", self.0.display()));
+        }
+    }
+}
+
+/// A database in a fresh temporary file.
+pub struct TempDb {
+    // Declared first, so that it is closed before the file is deleted.
+    db: Db,
+    path: TempPath,
+}
+
+impl TempDb {
+    pub fn new(name: &str) -> Self {
+        let path = TempPath::new(name);
+        Self { db: Db::open(&path, 1).unwrap(), path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TempDb {
+    type Target = Db;
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+/// An `AppState` persisting to the database at `path`, with writers that
+/// commit at once and a recent buffer of `recent_capacity` items.
+pub fn app_state(path: &Path, recent_capacity: usize) -> AppState {
+    let db = Arc::new(Db::open(path, 1).unwrap());
+    let config = WriterConfig { capacity: 1_000, batch_size: 1, flush_interval: Duration::ZERO };
+    let (writer, _) = TraceWriter::spawn(Arc::clone(&db), config);
+    AppState::new(
+        Arc::clone(&db),
+        writer,
+        MetricWriter::spawn(Arc::clone(&db), 1_000),
+        IngestLimits {
+            max_in_flight_traces:  1_000,
+            max_pending_writes:    1_000,
+            max_series_per_metric: 1_000,
+            max_metric_series:     1_000,
+        },
+        Duration::ZERO,
+        recent_capacity,
+        RetentionPolicy::default(),
+    )
+}
+
+/// A one-millisecond root span starting at `start`.
+pub fn span(trace_id: &str, service: &str, status: &str, start: u64) -> SpanEvent {
+    serde_json::from_value(serde_json::json!({
+        "trace_id": trace_id,
+        "span_id": "0000000000000001",
+        "parent_span_id": null,
+        "name": "GET /",
+        "target": "",
+        "start_time_unix_nano": start,
+        "end_time_unix_nano": start + 1_000_000,
+        "duration_ms": 1.0,
+        "attributes": [],
+        "status": status,
+        "service_name": service,
+        "instance_id": "",
+    }))
+    .unwrap()
+}
+
+/// A trace of a single [`span`].
+pub fn trace(trace_id: &str, service: &str, status: &str, start: u64) -> TraceComplete {
+    TraceComplete::from_spans(trace_id, vec![span(trace_id, service, status, start)])
+}
+
+/// A log record of `service`, correlated with `trace_id` if given.
+pub fn log(service: &str, severity_number: i32, trace_id: Option<&str>, time: u64) -> LogEvent {
+    LogEvent {
+        timestamp_unix_nano: time,
+        observed_unix_nano: time,
+        severity_text: String::new(),
+        severity_number,
+        body: format!("{service} says {severity_number}"),
+        trace_id: trace_id.map(str::to_string),
+        span_id: None,
+        attributes: vec![],
+        service_name: service.into(),
+        resource_id: String::new(),
+        scope_name: String::new(),
+        scope_version: String::new(),
+    }
+}
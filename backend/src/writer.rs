@@ -0,0 +1,322 @@
+//! Persistence of finalized traces by a single writer thread.
+//!
+//! Traces are queued on a bounded channel and committed to SQLite in batches,
+//! one transaction per batch, so that ingest never waits for the disk and
+//! history queries (served by [`Db`]'s read-only connections) do not contend
+//! with a stream of small writes. Metric data points have a writer thread of
+//! their own, see [`MetricWriter`].
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::db::Db;
+use crate::state::{MetricEvent, TraceComplete};
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriterConfig {
+    /// Maximum number of traces waiting to be written.
+    pub capacity: usize,
+    /// Commit once this many traces are queued...
+    pub batch_size: usize,
+    /// ...or once the first trace of the batch has waited this long.
+    pub flush_interval: Duration,
+}
+
+/// Handle on the writer thread.
+pub struct TraceWriter {
+    queue: SyncSender<TraceComplete>,
+    metrics: Arc<WriterMetrics>,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct WriterMetrics {
+    /// Traces queued or being committed.
+    depth: AtomicUsize,
+    batches: AtomicU64,
+    traces: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    last_batch_size: AtomicUsize,
+    last_commit_us: AtomicU64,
+    max_commit_us: AtomicU64,
+    total_commit_us: AtomicU64,
+}
+
+/// Writer queue and commit figures, as served by `/api/db/stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WriterStats {
+    /// Traces queued or being committed.
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    /// Transactions committed.
+    pub batches: u64,
+    /// Traces committed.
+    pub traces_written: u64,
+    /// Traces that failed to commit, alone as well as in their batch.
+    pub traces_failed: u64,
+    /// Traces not persisted because the queue was full.
+    pub traces_dropped: u64,
+    pub last_batch_size: usize,
+    pub last_commit_ms: f64,
+    pub avg_commit_ms: f64,
+    pub max_commit_ms: f64,
+}
+
+impl TraceWriter {
+    /// Start the writer thread. Every queued trace is then sent on the
+    /// returned channel once its batch is committed: as stored (merged with
+    /// spans persisted earlier), or as queued if the commit failed.
+    pub fn spawn(db: Arc<Db>, config: WriterConfig) -> (Self, UnboundedReceiver<TraceComplete>) {
+        let (queue, rx) = mpsc::sync_channel(config.capacity);
+        let (written_tx, written_rx) = tokio::sync::mpsc::unbounded_channel();
+        let metrics = Arc::new(WriterMetrics::default());
+        std::thread::Builder::new()
+            .name("db-writer".into())
+            .spawn({
+                let metrics = Arc::clone(&metrics);
+                move || run(&db, rx, config, &metrics, written_tx)
+            })
+            .expect("failed to spawn the database writer thread");
+        (Self { queue, metrics, capacity: config.capacity }, written_rx)
+    }
+
+    /// Queue `trace` for writing. When the queue is full the trace is handed
+    /// back, not persisted.
+    pub fn enqueue(&self, trace: TraceComplete) -> Option<TraceComplete> {
+        self.metrics.depth.fetch_add(1, Ordering::Relaxed);
+        match self.queue.try_send(trace) {
+            Ok(()) => None,
+            Err(TrySendError::Full(trace) | TrySendError::Disconnected(trace)) => {
+                self.metrics.depth.fetch_sub(1, Ordering::Relaxed);
+                self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                Some(trace)
+            }
+        }
+    }
+
+    /// Traces queued or being committed.
+    pub fn queue_depth(&self) -> usize {
+        self.metrics.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> WriterStats {
+        let m = &self.metrics;
+        let batches = m.batches.load(Ordering::Relaxed);
+        let total_us = m.total_commit_us.load(Ordering::Relaxed);
+        WriterStats {
+            queue_depth: self.queue_depth(),
+            queue_capacity: self.capacity,
+            batches,
+            traces_written: m.traces.load(Ordering::Relaxed),
+            traces_failed: m.failed.load(Ordering::Relaxed),
+            traces_dropped: m.dropped.load(Ordering::Relaxed),
+            last_batch_size: m.last_batch_size.load(Ordering::Relaxed),
+            last_commit_ms: m.last_commit_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            avg_commit_ms: if batches == 0 { 0.0 } else { total_us as f64 / batches as f64 / 1000.0 },
+            max_commit_ms: m.max_commit_us.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+fn run(
+    db: &Db,
+    rx: Receiver<TraceComplete>,
+    config: WriterConfig,
+    metrics: &WriterMetrics,
+    written: UnboundedSender<TraceComplete>,
+) {
+    // Ends once every sender is dropped and the queue is drained.
+    while let Ok(first) = rx.recv() {
+        let deadline = Instant::now() + config.flush_interval;
+        let mut batch = vec![first];
+        while batch.len() < config.batch_size {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(trace) => batch.push(trace),
+                Err(_) => break,
+            }
+        }
+
+        let count = batch.len();
+        let traces = commit(db, batch, metrics);
+        metrics.depth.fetch_sub(count, Ordering::Relaxed);
+
+        for trace in traces {
+            let _ = written.send(trace);
+        }
+    }
+}
+
+/// Write `batch` in one transaction. If that fails, each trace is retried in
+/// a transaction of its own so that one bad trace does not take the rest of
+/// the batch down with it. Returns the traces as stored, or as queued for
+/// those that could not be written.
+fn commit(db: &Db, batch: Vec<TraceComplete>, metrics: &WriterMetrics) -> Vec<TraceComplete> {
+    let started = Instant::now();
+    let count = batch.len();
+    match db.insert_traces(&batch) {
+        Ok(stored) => {
+            let elapsed_us = started.elapsed().as_micros() as u64;
+            metrics.batches.fetch_add(1, Ordering::Relaxed);
+            metrics.traces.fetch_add(count as u64, Ordering::Relaxed);
+            metrics.last_batch_size.store(count, Ordering::Relaxed);
+            metrics.last_commit_us.store(elapsed_us, Ordering::Relaxed);
+            metrics.max_commit_us.fetch_max(elapsed_us, Ordering::Relaxed);
+            metrics.total_commit_us.fetch_add(elapsed_us, Ordering::Relaxed);
+            stored
+        }
+        Err(e) if count == 1 => {
+            tracing::error!("Failed to persist trace {}: {}", batch[0].trace_id, e);
+            metrics.failed.fetch_add(1, Ordering::Relaxed);
+            batch
+        }
+        Err(e) => {
+            tracing::warn!("Failed to persist a batch of {} traces, retrying one by one: {}", count, e);
+            batch
+                .into_iter()
+                .map(|trace| match db.insert_trace(&trace) {
+                    Ok(stored) => {
+                        metrics.traces.fetch_add(1, Ordering::Relaxed);
+                        stored
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to persist trace {}: {}", trace.trace_id, e);
+                        metrics.failed.fetch_add(1, Ordering::Relaxed);
+                        trace
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+/// Data points committed in one transaction at most, unless a single
+/// export holds more.
+const MAX_METRIC_BATCH: usize = 10_000;
+
+/// Handle on the thread persisting metric data points, one transaction per
+/// OTLP export (or per queued exports, when it falls behind).
+pub struct MetricWriter {
+    queue: SyncSender<Vec<MetricEvent>>,
+    metrics: Arc<MetricWriterMetrics>,
+}
+
+#[derive(Default)]
+struct MetricWriterMetrics {
+    /// Exports queued or being committed.
+    depth: AtomicUsize,
+    written: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Metric writer figures, as served by `/api/db/stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricWriterStats {
+    /// Exports queued or being committed.
+    pub queue_depth: usize,
+    /// Data points stored (points repeating a stored timestamp are skipped).
+    pub points_written: u64,
+    /// Data points whose transaction failed to commit.
+    pub points_failed: u64,
+    /// Data points not persisted because the queue was full.
+    pub points_dropped: u64,
+}
+
+impl MetricWriter {
+    /// Start the writer thread, queueing up to `capacity` exports.
+    pub fn spawn(db: Arc<Db>, capacity: usize) -> Self {
+        let (queue, rx) = mpsc::sync_channel(capacity);
+        let metrics = Arc::new(MetricWriterMetrics::default());
+        std::thread::Builder::new()
+            .name("db-metric-writer".into())
+            .spawn({
+                let metrics = Arc::clone(&metrics);
+                move || run_metrics(&db, rx, &metrics)
+            })
+            .expect("failed to spawn the database metric writer thread");
+        Self { queue, metrics }
+    }
+
+    /// Queue the data points of one export; they are dropped (and counted)
+    /// when the queue is full.
+    pub fn enqueue(&self, batch: Vec<MetricEvent>) {
+        let points = batch.len() as u64;
+        self.metrics.depth.fetch_add(1, Ordering::Relaxed);
+        if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) = self.queue.try_send(batch) {
+            self.metrics.depth.fetch_sub(1, Ordering::Relaxed);
+            if self.metrics.dropped.fetch_add(points, Ordering::Relaxed) == 0 {
+                tracing::warn!("Metric writer queue full, metric data points are not persisted");
+            }
+        }
+    }
+
+    pub fn stats(&self) -> MetricWriterStats {
+        let m = &self.metrics;
+        MetricWriterStats {
+            queue_depth: m.depth.load(Ordering::Relaxed),
+            points_written: m.written.load(Ordering::Relaxed),
+            points_failed: m.failed.load(Ordering::Relaxed),
+            points_dropped: m.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn run_metrics(db: &Db, rx: Receiver<Vec<MetricEvent>>, metrics: &MetricWriterMetrics) {
+    while let Ok(mut batch) = rx.recv() {
+        // Fold whatever else is queued into the same transaction.
+        let mut exports = 1;
+        while batch.len() < MAX_METRIC_BATCH {
+            let Ok(more) = rx.try_recv() else { break };
+            batch.extend(more);
+            exports += 1;
+        }
+        match db.insert_metrics(&batch) {
+            Ok(stored) => {
+                metrics.written.fetch_add(stored as u64, Ordering::Relaxed);
+            }
+            Err(e) => {
+                tracing::error!("Failed to persist {} metric data points: {}", batch.len(), e);
+                metrics.failed.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            }
+        }
+        metrics.depth.fetch_sub(exports, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDb};
+
+    fn trace(trace_id: &str) -> TraceComplete {
+        testing::trace(trace_id, "svc", "ok", 1_000_000_000)
+    }
+
+    #[test]
+    fn failed_batch_only_loses_the_bad_trace() {
+        let db = TempDb::new("writer");
+        rusqlite::Connection::open(db.path())
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER reject_bad BEFORE INSERT ON traces WHEN NEW.trace_id = 'bad' \
+                 BEGIN SELECT RAISE(ABORT, 'rejected'); END;",
+            )
+            .unwrap();
+
+        let metrics = WriterMetrics::default();
+        let traces = commit(&db, vec![trace("good-1"), trace("bad"), trace("good-2")], &metrics);
+
+        assert_eq!(traces.len(), 3);
+        assert_eq!(metrics.traces.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.failed.load(Ordering::Relaxed), 1);
+        assert!(db.has_trace("good-1").unwrap());
+        assert!(db.has_trace("good-2").unwrap());
+        assert!(!db.has_trace("bad").unwrap());
+    }
+}
@@ -1,23 +1,35 @@
 //! HTTP server with WebSocket endpoint for the UI.
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Query, State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
     },
-    http::StatusCode,
+    http::{header, StatusCode},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
 use futures_util::{SinkExt, StreamExt};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{debug, info};
+use tracing::{info, warn};
 
-use crate::state::AppState;
+use crate::db::{
+    MatchMode, MetricQuery, SortOrder, SpanFilter, StorageStats, TraceCursor, TracePin, TraceQuery, TraceSort,
+};
+use crate::metrics::query_resolution;
+use crate::resource::{ResourceFilter, ResourceInfo};
+use crate::snapshot::{self, SnapshotRequest};
+use crate::subscription::{ClientSession, LagPolicy, SessionMessage};
+use crate::state::{
+    now_unix_nano, with_attr_format, AppState, AttrFormat, LogEvent, SpanEvent, TraceComplete, TraceDetail,
+    WsFrame,
+};
+use crate::writer::{MetricWriterStats, WriterStats};
 
 type SharedState = Arc<AppState>;
 
@@ -33,6 +45,17 @@ pub async fn run_http_server(state: SharedState, bind: &str) -> anyhow::Result<(
         .route("/config", get(config_handler))
         .route("/api/traces", get(traces_handler))
         .route("/api/traces/bounds", get(traces_bounds_handler))
+        .route("/api/traces/{trace_id}", get(trace_handler))
+        .route("/api/traces/{trace_id}/links", get(trace_links_handler))
+        .route("/api/traces/{trace_id}/pin", post(pin_handler).delete(unpin_handler))
+        .route("/api/pins", get(pins_handler))
+        .route("/api/db/stats", get(db_stats_handler))
+        .route("/api/metrics/dropped", get(dropped_metric_points_handler))
+        .route("/api/metrics/query", get(metrics_query_handler))
+        .route("/api/metrics/series", get(metric_series_handler))
+        .route("/api/resources", get(resources_handler))
+        .route("/api/resources/groups", get(resource_groups_handler))
+        .route("/api/resources/{resource_id}", get(resource_handler))
         .layer(cors)
         .with_state(state);
 
@@ -66,30 +89,106 @@ struct TraceQueryParams {
     service: Option<String>,
     min_duration_ms: Option<f64>,
     max_duration_ms: Option<f64>,
+    environment: Option<String>,
+    version: Option<String>,
+    host: Option<String>,
+    pod: Option<String>,
+    has_error: Option<bool>,
+    min_spans: Option<usize>,
+    involves_service: Option<String>,
+    involves_instance: Option<String>,
+    /// `exact` (default) or `substring`, for `involves_service` and `involves_instance`.
+    #[serde(default)]
+    involves_match: MatchMode,
+    /// Conditions on a single span of the trace; see [`SpanFilter`]. Attribute
+    /// conditions are given as repeated `attr=key=value` (or `attr=key`)
+    /// parameters.
+    span_name: Option<String>,
+    span_target: Option<String>,
+    span_status: Option<String>,
+    span_min_duration_ms: Option<f64>,
+    span_max_duration_ms: Option<f64>,
+    /// `started_at` (default), `duration` or `span_count`.
+    #[serde(default)]
+    sort: TraceSort,
+    /// `asc` (default) or `desc`.
+    #[serde(default)]
+    order: SortOrder,
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+    #[serde(default)]
+    attr_format: AttrFormat,
+}
+
+/// The repeated `attr` query parameters as `(key, value)` conditions.
+fn attr_conditions(pairs: Vec<(String, String)>) -> Vec<(String, Option<String>)> {
+    pairs
+        .into_iter()
+        .filter(|(name, _)| name == "attr")
+        .map(|(_, cond)| match cond.split_once('=') {
+            Some((key, value)) => (key.to_string(), Some(value.to_string())),
+            None => (cond, None),
+        })
+        .collect()
+}
+
+/// Serialize `value` as a JSON response, rendering attributes in `format`.
+fn json_response<T: Serialize>(value: &T, format: AttrFormat) -> axum::response::Response {
+    match with_attr_format(format, || serde_json::to_string(value)) {
+        Ok(json) => ([(header::CONTENT_TYPE, "application/json")], json).into_response(),
+        Err(e) => {
+            tracing::error!("JSON serialization error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
 async fn traces_handler(
     State(state): State<SharedState>,
     Query(params): Query<TraceQueryParams>,
+    Query(pairs): Query<Vec<(String, String)>>,
 ) -> impl IntoResponse {
     let db = Arc::clone(&state.db);
-    let limit = params.limit.unwrap_or(2000);
-    let service = params.service.clone();
-    let min_dur = params.min_duration_ms;
-    let max_dur = params.max_duration_ms;
-    match tokio::task::spawn_blocking(move || {
-        db.query_traces(
-            params.from,
-            params.to,
-            limit,
-            service.as_deref(),
-            min_dur,
-            max_dur,
-        )
-    })
+    let attr_format = params.attr_format;
+    let after = match params.cursor.as_deref() {
+        Some(token) => match TraceCursor::decode(token, params.sort, params.order) {
+            Some(cursor) => Some(cursor),
+            None => return (StatusCode::BAD_REQUEST, "invalid cursor").into_response(),
+        },
+        None => None,
+    };
+    let query = TraceQuery {
+        from_ns: params.from,
+        to_ns: params.to,
+        limit: params.limit.unwrap_or(2000),
+        service: params.service,
+        min_duration_ms: params.min_duration_ms,
+        max_duration_ms: params.max_duration_ms,
+        environment: params.environment,
+        version: params.version,
+        host: params.host,
+        pod: params.pod,
+        has_error: params.has_error,
+        min_spans: params.min_spans,
+        involves_service: params.involves_service,
+        involves_instance: params.involves_instance,
+        involves_match: params.involves_match,
+        span: SpanFilter {
+            name: params.span_name,
+            target: params.span_target,
+            status: params.span_status,
+            min_duration_ms: params.span_min_duration_ms,
+            max_duration_ms: params.span_max_duration_ms,
+            attributes: attr_conditions(pairs),
+        },
+        sort: params.sort,
+        order: params.order,
+        after,
+    };
+    match tokio::task::spawn_blocking(move || db.query_traces(&query))
     .await
     {
-        Ok(Ok(traces)) => Json(traces).into_response(),
+        Ok(Ok(page)) => json_response(&page, attr_format),
         Ok(Err(e)) => {
             tracing::error!("DB query error: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -118,39 +217,439 @@ async fn traces_bounds_handler(
     }
 }
 
+/// Health of the SQLite persistence layer.
+#[derive(Serialize)]
+struct DbStatsResponse {
+    writer: WriterStats,
+    metric_writer: MetricWriterStats,
+    storage: StorageStats,
+}
+
+async fn db_stats_handler(State(state): State<SharedState>) -> impl IntoResponse {
+    let db = Arc::clone(&state.db);
+    match tokio::task::spawn_blocking(move || db.storage_stats()).await {
+        Ok(Ok(storage)) => Json(DbStatsResponse {
+            writer: state.writer.stats(),
+            metric_writer: state.metric_writer.stats(),
+            storage,
+        })
+        .into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("DB stats error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            tracing::error!("Task join error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// ── Metrics ────────────────────────────────────────────────────────────────────
+
+/// Data points dropped by the OTLP metrics receiver since startup, per metric name.
+async fn dropped_metric_points_handler(State(state): State<SharedState>) -> impl IntoResponse {
+    Json(state.dropped_metric_points.snapshot())
+}
+
+#[derive(Deserialize)]
+struct MetricSeriesParams {
+    /// Substring of the metric name.
+    name: Option<String>,
+    service: Option<String>,
+}
+
+/// The metrics seen recently, with their series counts, attribute keys and
+/// example values, and the points collapsed by the cardinality limits.
+async fn metric_series_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<MetricSeriesParams>,
+) -> impl IntoResponse {
+    Json(state.metric_series.snapshot(params.name.as_deref(), params.service.as_deref()))
+}
+
+/// Points per series returned by default, when no `step` is given...
+const DEFAULT_METRIC_POINTS: u64 = 300;
+/// ...and at most, whatever the `step`.
+const MAX_METRIC_POINTS: u64 = 5_000;
+
+#[derive(Deserialize)]
+struct MetricQueryParams {
+    metric: String,
+    from: i64,
+    to: i64,
+    /// Seconds per returned point.
+    step: Option<u64>,
+    service: Option<String>,
+    resource_id: Option<String>,
+    /// Attribute conditions are given as repeated `attr=key=value` (or
+    /// `attr=key`) parameters.
+    #[serde(default)]
+    attr_format: AttrFormat,
+}
+
+/// Stored points of the series of one metric, aggregated per step, from
+/// the coarsest resolution that fits the step and still covers `from`.
+async fn metrics_query_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<MetricQueryParams>,
+    Query(pairs): Query<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    if params.to <= params.from {
+        return (StatusCode::BAD_REQUEST, "`to` must be after `from`").into_response();
+    }
+    let range_secs = params.to.abs_diff(params.from).div_ceil(1_000_000_000);
+    let min_step = range_secs.div_ceil(MAX_METRIC_POINTS);
+    // A step longer than the range returns the same single point.
+    let step = params
+        .step
+        .unwrap_or_else(|| range_secs.div_ceil(DEFAULT_METRIC_POINTS))
+        .clamp(min_step.max(1), range_secs);
+    if i64::try_from(step).ok().and_then(|s| s.checked_mul(1_000_000_000)).is_none() {
+        return (StatusCode::BAD_REQUEST, "range is too long").into_response();
+    }
+    let query = MetricQuery {
+        metric_name: params.metric,
+        service: params.service,
+        resource_id: params.resource_id,
+        attributes: attr_conditions(pairs),
+        from_ns: params.from,
+        to_ns: params.to,
+        step_secs: step,
+        resolution: query_resolution(step, params.from, now_unix_nano() as i64, &state.retention.metrics),
+    };
+    let db = Arc::clone(&state.db);
+    match tokio::task::spawn_blocking(move || db.query_metrics(&query)).await {
+        Ok(Ok(result)) => json_response(&result, params.attr_format),
+        Ok(Err(e)) => {
+            tracing::error!("DB metrics query error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            tracing::error!("Task join error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// ── Resource registry ──────────────────────────────────────────────────────────
+
+#[derive(Deserialize)]
+struct ResourceQueryParams {
+    #[serde(flatten)]
+    filter: ResourceFilter,
+    /// Grouping key for `/api/resources/groups`: `service`, `environment`,
+    /// `version`, `host`, `pod` or any resource attribute key.
+    by: Option<String>,
+    #[serde(default)]
+    attr_format: AttrFormat,
+}
+
+async fn resources_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<ResourceQueryParams>,
+) -> impl IntoResponse {
+    let resources = state.resources.list(&params.filter);
+    let resources: Vec<&ResourceInfo> = resources.iter().map(|r| r.as_ref()).collect();
+    json_response(&resources, params.attr_format)
+}
+
+async fn resource_groups_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<ResourceQueryParams>,
+) -> impl IntoResponse {
+    let Some(by) = params.by.as_deref() else {
+        return (StatusCode::BAD_REQUEST, "missing `by` query parameter").into_response();
+    };
+    Json(state.resources.groups(by, &params.filter)).into_response()
+}
+
+async fn resource_handler(
+    State(state): State<SharedState>,
+    Path(resource_id): Path<String>,
+    Query(params): Query<ResourceQueryParams>,
+) -> impl IntoResponse {
+    match state.resources.get(&resource_id) {
+        Some(resource) => json_response(resource.as_ref(), params.attr_format),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct FormatParams {
+    #[serde(default)]
+    attr_format: AttrFormat,
+}
+
+/// A trace with the log records correlated with it.
+#[derive(Serialize)]
+struct TraceResponse {
+    #[serde(flatten)]
+    trace: TraceComplete,
+    /// `true` while spans of the trace are still waiting to be finalized.
+    in_flight: bool,
+    logs: Vec<LogEvent>,
+}
+
+/// A single trace by ID, assembled from the in-flight spans, the recent
+/// buffer and SQLite (in that order of precedence for duplicate span IDs).
+/// Correlated logs are only kept in memory, so older traces come without.
+async fn trace_handler(
+    State(state): State<SharedState>,
+    Path(trace_id): Path<String>,
+    Query(params): Query<FormatParams>,
+) -> impl IntoResponse {
+    let trace_id = trace_id.to_ascii_lowercase();
+
+    let mut spans: HashMap<String, SpanEvent> = HashMap::new();
+    let db = Arc::clone(&state.db);
+    let id = trace_id.clone();
+    let pin = match tokio::task::spawn_blocking(move || Ok::<_, anyhow::Error>((db.get_trace(&id)?, db.get_pin(&id)?)))
+        .await
+    {
+        Ok(Ok((stored, pin))) => {
+            spans.extend(stored.into_iter().flat_map(|t| t.spans).map(|s| (s.span_id.clone(), s)));
+            pin
+        }
+        Ok(Err(e)) => {
+            tracing::error!("DB trace lookup error: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Err(e) => {
+            tracing::error!("Task join error: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let recent = state.recent.lock().unwrap().frames();
+    let (recent_spans, logs) = snapshot::find_trace(&recent, &trace_id);
+    spans.extend(recent_spans.into_iter().map(|s| (s.span_id.clone(), s)));
+    let in_flight = match state.in_flight.get(&trace_id) {
+        Some(entry) => {
+            spans.extend(entry.spans.iter().map(|(id, s)| (id.clone(), s.clone())));
+            true
+        }
+        None => false,
+    };
+
+    if spans.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let mut trace = TraceComplete::from_spans(&trace_id, spans.into_values().collect());
+    trace.pin = pin;
+    json_response(&TraceResponse { trace, in_flight, logs }, params.attr_format)
+}
+
+/// Span links leaving and entering a persisted trace, so the UI can follow
+/// producer → consumer hops across traces.
+async fn trace_links_handler(
+    State(state): State<SharedState>,
+    Path(trace_id): Path<String>,
+) -> impl IntoResponse {
+    let db = Arc::clone(&state.db);
+    match tokio::task::spawn_blocking(move || db.trace_links(&trace_id)).await {
+        Ok(Ok(links)) => Json(links).into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("DB links error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            tracing::error!("Task join error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct PinRequest {
+    #[serde(default)]
+    note: String,
+    /// Groups pins in `/api/pins`, e.g. an incident number.
+    #[serde(default)]
+    label: String,
+}
+
+/// Pin a trace so that retention never prunes it. Pinning a pinned trace
+/// replaces its note and label. The body is optional.
+async fn pin_handler(
+    State(state): State<SharedState>,
+    Path(trace_id): Path<String>,
+    body: Option<Json<PinRequest>>,
+) -> impl IntoResponse {
+    let trace_id = trace_id.to_ascii_lowercase();
+    let request = body.map(|Json(r)| r).unwrap_or_default();
+    let live = state.in_flight.contains_key(&trace_id) || state.recently_finalized.contains_key(&trace_id);
+    let db = Arc::clone(&state.db);
+    let pinned = tokio::task::spawn_blocking(move || -> anyhow::Result<Option<TracePin>> {
+        if !live && !db.has_trace(&trace_id)? {
+            return Ok(None);
+        }
+        Ok(Some(db.pin_trace(&trace_id, &request.note, &request.label, now_unix_nano())?))
+    })
+    .await;
+    match pinned {
+        Ok(Ok(Some(pin))) => Json(pin).into_response(),
+        Ok(Ok(None)) => StatusCode::NOT_FOUND.into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("DB pin error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            tracing::error!("Task join error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn unpin_handler(
+    State(state): State<SharedState>,
+    Path(trace_id): Path<String>,
+) -> impl IntoResponse {
+    let trace_id = trace_id.to_ascii_lowercase();
+    let db = Arc::clone(&state.db);
+    match tokio::task::spawn_blocking(move || db.unpin_trace(&trace_id)).await {
+        Ok(Ok(true)) => StatusCode::NO_CONTENT.into_response(),
+        Ok(Ok(false)) => StatusCode::NOT_FOUND.into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("DB unpin error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            tracing::error!("Task join error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PinsParams {
+    label: Option<String>,
+}
+
+/// Every pinned trace, most recently pinned first.
+async fn pins_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<PinsParams>,
+) -> impl IntoResponse {
+    let db = Arc::clone(&state.db);
+    match tokio::task::spawn_blocking(move || db.list_pins(params.label.as_deref())).await {
+        Ok(Ok(pins)) => Json(pins).into_response(),
+        Ok(Err(e)) => {
+            tracing::error!("DB pins error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            tracing::error!("Task join error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WsParams {
+    /// `string` keeps the pre-typed-attributes wire format for older clients.
+    #[serde(default)]
+    attr_format: AttrFormat,
+    /// `summary` (default), `full` or `none`: what to send when a trace is finalized.
+    #[serde(default)]
+    traces: TraceDetail,
+    /// `drop_oldest` (default), `coalesce` or `disconnect`: what to do when
+    /// the client falls behind and broadcast messages are dropped for it.
+    #[serde(default)]
+    on_lag: LagPolicy,
+    /// Replay traces, logs and metrics from the last this many minutes
+    /// before the live stream.
+    snapshot_minutes: Option<u64>,
+    /// Replay at most this many of the most recent traces.
+    snapshot_traces: Option<usize>,
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<SharedState>,
+    Query(params): Query<WsParams>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, params))
 }
 
-async fn handle_socket(socket: WebSocket, state: SharedState) {
+async fn handle_socket(socket: WebSocket, state: SharedState, params: WsParams) {
     let (mut sender, mut receiver) = socket.split();
+    let mut session = ClientSession::new(params.attr_format, params.traces, params.on_lag);
 
-    // Subscribe to broadcast channel
-    let mut rx = state.broadcast.subscribe();
+    // Subscribe to broadcast channel, replaying a snapshot first if asked to
+    let snapshot = SnapshotRequest::new(params.snapshot_minutes, params.snapshot_traces);
+    let mut rx = match snapshot {
+        Some(request) => {
+            let (rx, recent) = state.subscribe_with_recent();
+            let subscribed_at = now_unix_nano();
+            let snapshot = snapshot::build(&state, request, &recent, subscribed_at).await;
+            for message in snapshot.messages {
+                let frame = WsFrame::published_at(message, subscribed_at);
+                let Some(json) = session.render(&frame, &state.resources) else {
+                    continue;
+                };
+                if sender.send(Message::Text((*json).clone().into())).await.is_err() {
+                    return;
+                }
+            }
+            let complete = SessionMessage::SnapshotComplete {
+                traces: snapshot.traces,
+                spans: snapshot.spans,
+                logs: snapshot.logs,
+                metrics: snapshot.metrics,
+            };
+            let complete = serde_json::to_string(&complete).unwrap_or_default();
+            if sender.send(Message::Text(complete.into())).await.is_err() {
+                return;
+            }
+            rx
+        }
+        None => state.broadcast.subscribe(),
+    };
 
-    loop {
+    'conn: loop {
         tokio::select! {
             // Forward broadcast events to WS client
             msg = rx.recv() => {
                 match msg {
-                    Ok(event) => {
-                        if sender.send(Message::Text((*event).clone().into())).await.is_err() {
+                    Ok(frame) => {
+                        let Some(json) = session.render(&frame, &state.resources) else {
+                            continue;
+                        };
+                        if sender.send(Message::Text((*json).clone().into())).await.is_err() {
                             break;
                         }
                     }
                     Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
-                        debug!("WebSocket client lagged by {} messages", n);
+                        warn!("WebSocket client lagged by {} messages ({:?})", n, session.on_lag);
+                        let catch_up = session.catch_up(&mut rx, n, &state.resources);
+                        if sender.send(Message::Text(catch_up.notice.into())).await.is_err() {
+                            break;
+                        }
+                        if catch_up.disconnect {
+                            let frame = CloseFrame { code: close_code::AGAIN, reason: "lagged".into() };
+                            let _ = sender.send(Message::Close(Some(frame))).await;
+                            break;
+                        }
+                        for json in catch_up.frames {
+                            if sender.send(Message::Text((*json).clone().into())).await.is_err() {
+                                break 'conn;
+                            }
+                        }
                     }
                     Err(_) => break,
                 }
             }
 
-            // Handle incoming messages from client (ping/pong)
+            // Handle incoming messages from client: subscription control
+            // messages; ping/pong is answered by axum.
             msg = receiver.next() => {
                 match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        let reply = session.handle_control(&text);
+                        if sender.send(Message::Text(reply.into())).await.is_err() {
+                            break;
+                        }
+                    }
                     Some(Ok(Message::Close(_))) | None => break,
                     _ => {}
                 }
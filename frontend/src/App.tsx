@@ -85,6 +85,24 @@ const WS_URL = (() => {
   return 'ws://localhost:8080/ws';
 })();
 
+/** Connection options the UI needs: attribute values as plain strings (it
+ *  renders them as text), no per-trace messages since it assembles traces
+ *  from `spans_batch` itself, merged batches after falling behind, and a
+ *  replay of recent traces so a reload does not start from an empty view. */
+function wsConnectUrl(url: string): string {
+  try {
+    const u = new URL(url);
+    u.searchParams.set('attr_format', 'string');
+    u.searchParams.set('traces', 'none');
+    u.searchParams.set('on_lag', 'coalesce');
+    u.searchParams.set('snapshot_minutes', '5');
+    u.searchParams.set('snapshot_traces', '200');
+    return u.toString();
+  } catch {
+    return url;
+  }
+}
+
 const NODE_SPAN_MAX    = 200;
 /** Keep traces within this rolling window for the Statistics view (ms). */
 const STATS_WINDOW_MS  = 10 * 60 * 1000; // 10 minutes
@@ -94,6 +112,7 @@ const STATS_WINDOW_MS  = 10 * 60 * 1000; // 10 minutes
 export default function App() {
   // ── React state ────────────────────────────────────────────────────────────
   const [wsConnected,    setWsConnected]    = useState(false);
+  const [dataIncomplete, setDataIncomplete] = useState(false);
   const [demoMode,       setDemoMode]       = useState(false);
   const [demoScenario,   setDemoScenario]   = useState<DemoScenario>('standard');
   const [demoConfig,     setDemoConfigState] = useState<DemoConfig>({ ...DEFAULT_DEMO_CONFIG });
@@ -573,18 +592,24 @@ export default function App() {
         }
         break;
 
+      case 'gap':
+        // Messages were dropped for this connection: the live view is missing data.
+        setDataIncomplete(true);
+        break;
+
     }
   }, [rebuildMergedEdges]);
 
   // ── WS status handler ────────────────────────────────────────────────────────
   const handleStatus = useCallback((connected: boolean) => {
     wsConnectedRef.current = connected;
+    if (connected) setDataIncomplete(false);
     if (demoModeRef.current) return;
     setWsConnected(connected);
   }, []);
 
   // ── WebSocket ────────────────────────────────────────────────────────────────
-  useWebSocket({ url: WS_URL, onMessage: handleMessage, onStatus: handleStatus });
+  useWebSocket({ url: wsConnectUrl(WS_URL), onMessage: handleMessage, onStatus: handleStatus });
 
   // ── rAF frame loop (span queue drain + rate metrics) ───────────────────────
   useEffect(() => {
@@ -833,6 +858,7 @@ export default function App() {
         activeTab={activeTab}
         onTabChange={handleTabChange}
         wsConnected={wsConnected}
+        dataIncomplete={dataIncomplete}
         demoMode={demoMode}
         demoScenario={demoScenario}
         onExitDemo={deactivateDemo}
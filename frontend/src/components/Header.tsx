@@ -12,6 +12,7 @@ interface HeaderProps {
   activeTab: TabId;
   onTabChange: (tab: TabId) => void;
   wsConnected: boolean;
+  dataIncomplete: boolean;
   demoMode: boolean;
   demoScenario: DemoScenario;
   onExitDemo: () => void;
@@ -30,6 +31,7 @@ export default function Header({
   activeTab,
   onTabChange,
   wsConnected,
+  dataIncomplete,
   demoMode,
   demoScenario,
   onExitDemo,
@@ -56,8 +58,9 @@ export default function Header({
     historyEnabledRef.current = historyEnabled;
   }, [historyEnabled]);
 
-  const statusColor = demoMode ? '#64c8ff' : historyEnabled ? 'var(--c-amber)' : wsConnected ? 'var(--c-ok)' : 'var(--c-error)';
-  const statusLabel = demoMode ? 'demo' : historyEnabled ? 'history' : wsConnected ? 'live' : 'reconnecting\u2026';
+  const incomplete  = wsConnected && dataIncomplete;
+  const statusColor = demoMode ? '#64c8ff' : historyEnabled || incomplete ? 'var(--c-amber)' : wsConnected ? 'var(--c-ok)' : 'var(--c-error)';
+  const statusLabel = demoMode ? 'demo' : historyEnabled ? 'history' : incomplete ? 'live \u00b7 data incomplete' : wsConnected ? 'live' : 'reconnecting\u2026';
   const statusShadow = `0 0 8px ${statusColor}`;
 
   return (
@@ -96,7 +99,10 @@ export default function Header({
         className="status-dot"
         style={{ background: statusColor, boxShadow: statusShadow }}
       />
-      <span className="status-label">{statusLabel}</span>
+      <span
+        className="status-label"
+        title={incomplete ? 'The server dropped messages because the UI fell behind; the topology may be missing spans.' : undefined}
+      >{statusLabel}</span>
 
       {demoMode && (
         <div id="demo-banner">
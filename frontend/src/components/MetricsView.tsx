@@ -109,11 +109,18 @@ function seriesKey(e: MetricEvent): string {
   return `${e.service_name}\0${e.metric_name}\0${fp}`;
 }
 
+/** Cumulative counters are plotted as their per-second rate. */
+function plotsRate(e: MetricEvent): boolean {
+  return e.value.kind === 'sum' && e.value.is_monotonic && e.value.temporality === 'cumulative';
+}
+
 function extractValue(e: MetricEvent): number | null {
   switch (e.value.kind) {
     case 'gauge':     return e.value.value;
-    case 'sum':       return e.value.value;
-    case 'histogram': return e.value.count;
+    case 'sum':       return plotsRate(e) ? e.value.rate ?? null : e.value.value;
+    case 'histogram':
+    case 'exponential_histogram':
+    case 'summary':   return e.value.count;
   }
 }
 
@@ -461,7 +468,7 @@ const MetricsView = forwardRef<MetricsViewHandle>(function MetricsView(_props, r
             key,
             service:     e.service_name,
             metric:      e.metric_name,
-            unit:        e.unit,
+            unit:        plotsRate(e) ? `${e.unit}/s` : e.unit,
             description: e.description,
             attrs:       e.attributes,
           };
@@ -507,13 +507,15 @@ function emitDemoMetrics(onMessage: (msg: WsMessage) => void): void {
         value: { kind: 'histogram', count: Math.round(st.reqRate * 2),
                  sum: st.p99latency * st.reqRate * 2 * 0.4,
                  min: 1,
-                 max: parseFloat(st.p99latency.toFixed(1)) } },
+                 max: parseFloat(st.p99latency.toFixed(1)),
+                 explicit_bounds: [], bucket_counts: [] } },
 
       // Monotonic request counter
       { service_name: svc, metric_name: 'http.requests.total',
         description: 'Total requests handled', unit: 'req',
         timestamp_unix_nano: now, attributes: attrs,
-        value: { kind: 'sum', value: st.reqTotal, is_monotonic: true } },
+        value: { kind: 'sum', value: st.reqTotal, is_monotonic: true,
+                 temporality: 'cumulative', start_time_unix_nano: 0, rate: st.reqRate, reset: false } },
     );
   }
 
@@ -1,7 +1,9 @@
 // ── History REST client ────────────────────────────────────────────────────────
 // Mirrors the WS_URL logic: in dev mode (port 8080) the backend is on 8081.
 
-import type { TraceComplete, TraceBounds } from './types.ts';
+import type {
+  MetricQueryResult, SeriesCatalog, TraceComplete, TraceBounds, TraceLinks, TracePage,
+} from './types.ts';
 
 const API_BASE = (() => {
   const { hostname, port, protocol } = window.location;
@@ -23,32 +25,132 @@ export interface TraceQueryFilters {
   service?: string;
   min_duration_ms?: number;
   max_duration_ms?: number;
+  has_error?: boolean;
+  min_spans?: number;
+  involves_service?: string;
+  involves_instance?: string;
+  involves_match?: 'exact' | 'substring';
+}
+
+export interface TracePageOptions {
+  limit?:  number;
+  sort?:   'started_at' | 'duration' | 'span_count';
+  order?:  'asc' | 'desc';
+  /** `next_cursor` of the previous page. */
+  cursor?: string;
 }
 
 /**
- * Query persisted traces in the time range [from_ns, to_ns] (nanoseconds).
- * Returns at most `limit` traces ordered by started_at ascending.
+ * Query one page of persisted traces in the time range [from_ns, to_ns]
+ * (nanoseconds), ordered by started_at ascending unless `sort`/`order` say
+ * otherwise.
  */
-export async function fetchTraces(
+export async function fetchTracePage(
   from_ns: number,
   to_ns: number,
-  limit = 2000,
   filters: TraceQueryFilters = {},
-): Promise<TraceComplete[]> {
+  options: TracePageOptions = {},
+): Promise<TracePage | null> {
   try {
     const params = new URLSearchParams({
       from:  String(from_ns),
       to:    String(to_ns),
-      limit: String(limit),
+      limit: String(options.limit ?? 2000),
+      // The UI renders attribute values as plain strings.
+      attr_format: 'string',
     });
+    if (options.sort)   params.set('sort',   options.sort);
+    if (options.order)  params.set('order',  options.order);
+    if (options.cursor) params.set('cursor', options.cursor);
     if (filters.service)          params.set('service',        filters.service);
     if (filters.min_duration_ms != null) params.set('min_duration_ms', String(filters.min_duration_ms));
     if (filters.max_duration_ms != null) params.set('max_duration_ms', String(filters.max_duration_ms));
+    if (filters.has_error != null) params.set('has_error', String(filters.has_error));
+    if (filters.min_spans != null) params.set('min_spans', String(filters.min_spans));
+    if (filters.involves_service) params.set('involves_service', filters.involves_service);
+    if (filters.involves_instance) params.set('involves_instance', filters.involves_instance);
+    if (filters.involves_match) params.set('involves_match', filters.involves_match);
     const res = await fetch(`${API_BASE}/api/traces?${params}`);
-    if (!res.ok) return [];
-    return res.json() as Promise<TraceComplete[]>;
+    if (!res.ok) return null;
+    return res.json() as Promise<TracePage>;
+  } catch {
+    return null;
+  }
+}
+
+/**
+ * Query persisted traces in the time range [from_ns, to_ns] (nanoseconds).
+ * Returns at most `limit` traces ordered by started_at ascending.
+ */
+export async function fetchTraces(
+  from_ns: number,
+  to_ns: number,
+  limit = 2000,
+  filters: TraceQueryFilters = {},
+): Promise<TraceComplete[]> {
+  const page = await fetchTracePage(from_ns, to_ns, filters, { limit });
+  return page?.traces ?? [];
+}
+
+
+/** Span links leaving and entering a persisted trace. */
+export async function fetchTraceLinks(trace_id: string): Promise<TraceLinks | null> {
+  try {
+    const res = await fetch(`${API_BASE}/api/traces/${encodeURIComponent(trace_id)}/links`);
+    if (!res.ok) return null;
+    return res.json() as Promise<TraceLinks>;
   } catch {
-    return [];
+    return null;
   }
 }
 
+export interface MetricQueryFilters {
+  service?:     string;
+  resource_id?: string;
+  /** Attribute equality filters, all of which must match. */
+  attributes?:  [string, string][];
+  /** Seconds per returned point; chosen from the range when omitted. */
+  step?:        number;
+}
+
+/**
+ * Query persisted points of `metric` in the time range [from_ns, to_ns]
+ * (nanoseconds), aggregated per step.
+ */
+export async function fetchMetricHistory(
+  metric: string,
+  from_ns: number,
+  to_ns: number,
+  filters: MetricQueryFilters = {},
+): Promise<MetricQueryResult | null> {
+  try {
+    const params = new URLSearchParams({
+      metric,
+      from: String(from_ns),
+      to:   String(to_ns),
+      attr_format: 'string',
+    });
+    if (filters.service)     params.set('service',     filters.service);
+    if (filters.resource_id) params.set('resource_id', filters.resource_id);
+    if (filters.step != null) params.set('step', String(filters.step));
+    for (const [k, v] of filters.attributes ?? []) params.append('attr', `${k}=${v}`);
+    const res = await fetch(`${API_BASE}/api/metrics/query?${params}`);
+    if (!res.ok) return null;
+    return res.json() as Promise<MetricQueryResult>;
+  } catch {
+    return null;
+  }
+}
+
+/** Metrics seen recently, optionally those whose name contains `name`. */
+export async function fetchMetricSeries(name?: string): Promise<SeriesCatalog | null> {
+  try {
+    const params = new URLSearchParams();
+    if (name) params.set('name', name);
+    const res = await fetch(`${API_BASE}/api/metrics/series?${params}`);
+    if (!res.ok) return null;
+    return res.json() as Promise<SeriesCatalog>;
+  } catch {
+    return null;
+  }
+}
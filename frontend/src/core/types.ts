@@ -13,6 +13,39 @@ export interface SpanEvent {
   status: string;
   service_name: string;
   instance_id?: string;
+  kind?: string;
+  trace_state?: string;
+  status_message?: string;
+  events?: SpanEventRecord[];
+  links?: SpanLink[];
+  dropped_attributes_count?: number;
+  dropped_events_count?: number;
+  dropped_links_count?: number;
+  exception?: SpanException | null;
+  resource_id?: string;
+  scope_name?: string;
+  scope_version?: string;
+}
+
+export interface SpanEventRecord {
+  name: string;
+  time_unix_nano: number;
+  attributes: [string, string][];
+  dropped_attributes_count?: number;
+}
+
+export interface SpanLink {
+  trace_id: string;
+  span_id: string;
+  trace_state?: string;
+  attributes: [string, string][];
+  dropped_attributes_count?: number;
+}
+
+export interface SpanException {
+  type: string;
+  message: string;
+  stacktrace: string;
 }
 
 export interface Node {
@@ -35,12 +68,75 @@ export interface TraceComplete {
   duration_ms: number;
   started_at: number;
   instance_id: string;
+  linked_trace_ids?: string[];
+  span_count?: number;
+  /** Spans with status "error". */
+  error_count?: number;
+  /** Distinct services with at least one span. */
+  services?: string[];
+  /** Longest parent → child chain (1 for a lone root span). */
+  max_depth?: number;
+  /** Set while the trace is pinned; pinned traces are never pruned. */
+  pin?: TracePin;
+}
+
+export interface TracePin {
+  note: string;
+  label: string;
+  /** Unix nanoseconds. */
+  pinned_at: number;
+}
+
+export interface TraceSummary {
+  trace_id: string;
+  root_span_name: string;
+  duration_ms: number;
+  started_at: number;
+  instance_id: string;
+  linked_trace_ids: string[];
+  span_count: number;
+  error_count: number;
 }
 
 export type MetricValue =
   | { kind: 'gauge';     value: number }
-  | { kind: 'sum';       value: number; is_monotonic: boolean }
-  | { kind: 'histogram'; count: number; sum: number; min: number; max: number };
+  | { kind: 'sum';       value: number; is_monotonic: boolean;
+      temporality: 'cumulative' | 'delta' | 'unspecified';
+      /** Start of the interval `value` covers (0 = unknown). */
+      start_time_unix_nano: number;
+      /** Change since the previous point of the series. */
+      delta?: number;
+      /** `delta` per second. */
+      rate?: number;
+      /** The cumulative series restarted before this point. */
+      reset: boolean }
+  | { kind: 'histogram'; count: number; sum: number; min: number | null; max: number | null;
+      /** Upper bounds of all buckets but the last, which is unbounded. */
+      explicit_bounds: number[];
+      bucket_counts: number[];
+      /** Estimated server-side from the buckets. */
+      percentiles?: Percentiles }
+  | { kind: 'exponential_histogram'; count: number; sum: number; min: number | null; max: number | null;
+      /** Bucket boundaries are powers of 2^(2^-scale). */
+      scale: number; zero_count: number; zero_threshold: number;
+      positive: ExponentialBuckets; negative: ExponentialBuckets;
+      percentiles?: Percentiles }
+  | { kind: 'summary'; count: number; sum: number;
+      quantiles: { quantile: number; value: number }[];
+      /** Interpolated from `quantiles` when they bracket p50–p99. */
+      percentiles?: Percentiles };
+
+export interface ExponentialBuckets {
+  /** Index of the first bucket; bucket i covers (base^i, base^(i+1)] in absolute value. */
+  offset: number;
+  bucket_counts: number[];
+}
+
+export interface Percentiles {
+  p50: number;
+  p90: number;
+  p99: number;
+}
 
 export interface MetricEvent {
   service_name:        string;
@@ -50,6 +146,9 @@ export interface MetricEvent {
   timestamp_unix_nano: number;
   attributes:          [string, string][];
   value:               MetricValue;
+  resource_id?:        string;
+  scope_name?:         string;
+  scope_version?:      string;
 }
 
 export interface LogEvent {
@@ -62,15 +161,131 @@ export interface LogEvent {
   span_id:             string | null;
   attributes:          [string, string][];
   service_name:        string;
+  resource_id?:        string;
+  scope_name?:         string;
+  scope_version?:      string;
 }
 
 export type WsMessage =
   | { type: 'spans_batch';   spans:   SpanEvent[] }
   | { type: 'metrics_batch'; metrics: MetricEvent[] }
-  | { type: 'logs_batch';    logs:    LogEvent[] };
+  | { type: 'logs_batch';    logs:    LogEvent[] }
+  | ({ type: 'trace_complete' } & TraceComplete)
+  | ({ type: 'trace_summary' } & TraceSummary)
+  | ({ type: 'gap' } & GapNotice)
+  | { type: 'snapshot_complete'; traces: number; spans: number; logs: number; metrics: number };
+
+/** Sent when this connection fell behind and `dropped` broadcast messages
+ *  published between the two timestamps were never delivered. */
+export interface GapNotice {
+  dropped:        number;
+  from_unix_nano: number;
+  to_unix_nano:   number;
+  policy:         'drop_oldest' | 'coalesce' | 'disconnect';
+}
+
+export interface ResourceInfo {
+  resource_id:          string;
+  attributes:           [string, string][];
+  service_name:         string;
+  service_version:      string;
+  environment:          string;
+  host_name:            string;
+  k8s_pod_name:         string;
+  first_seen_unix_nano: number;
+}
+
+export interface TraceLinkRef {
+  trace_id: string;
+  span_id: string;
+  linked_trace_id: string;
+  linked_span_id: string;
+}
+
+export interface TraceLinks {
+  trace_id: string;
+  outgoing: TraceLinkRef[];
+  incoming: TraceLinkRef[];
+}
+
+/** One page of `/api/traces` results. */
+export interface TracePage {
+  traces:         TraceComplete[];
+  /** Pass back as `cursor` for the next page; null on the last page. */
+  next_cursor:    string | null;
+  /** Matching traces, exact up to 100 000. */
+  total_estimate: number;
+}
 
 export interface TraceBounds {
   min_started_at: number;
   max_started_at: number;
   count: number;
 }
+
+/** One step of a persisted metric series. */
+export interface MetricPoint {
+  /** Start of the step (ns). */
+  time:    number;
+  samples: number;
+  avg:     number;
+  min:     number;
+  max:     number;
+  last:    number;
+  /** Mean per-second rate of sums. */
+  rate:    number | null;
+  /** Highest percentile estimates of the step's data points. */
+  p50_max: number | null;
+  p90_max: number | null;
+  p99_max: number | null;
+}
+
+export interface MetricSeriesPoints {
+  service_name: string;
+  metric_name:  string;
+  kind:         MetricValue['kind'];
+  unit:         string;
+  description:  string;
+  resource_id:  string;
+  attributes:   [string, string][];
+  points:       MetricPoint[];
+}
+
+/** Result of `/api/metrics/query`. */
+export interface MetricQueryResult {
+  /** Seconds per stored point the result was computed from (0 = raw). */
+  resolution: number;
+  /** Seconds per returned point. */
+  step:       number;
+  series:     MetricSeriesPoints[];
+  /** More series matched than were returned. */
+  truncated:  boolean;
+}
+
+/** Example values of an attribute key in `/api/metrics/series`. */
+export interface LabelValues {
+  sample_values: string[];
+  /** The key had more values than the samples hold. */
+  more:          boolean;
+}
+
+export interface MetricSummary {
+  name:            string;
+  kind:            MetricValue['kind'];
+  unit:            string;
+  description:     string;
+  services:        string[];
+  series:          number;
+  labels:          Record<string, LabelValues>;
+  /** Points collapsed into the `otel.metric.overflow` series. */
+  overflow_points: number;
+}
+
+/** Result of `/api/metrics/series`. */
+export interface SeriesCatalog {
+  series:                number;
+  max_series:            number;
+  max_series_per_metric: number;
+  overflow_points:       number;
+  metrics:               MetricSummary[];
+}
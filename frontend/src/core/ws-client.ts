@@ -12,6 +12,7 @@ export class WsClient {
   private onStatus: StatusHandler;
   private reconnectTimer: ReturnType<typeof setTimeout> | null = null;
   private reconnectDelay = 1000;
+  private opened = false;
 
   constructor(url: string, onMessage: MessageHandler, onStatus: StatusHandler) {
     this.url = url;
@@ -34,13 +35,14 @@ export class WsClient {
     }
 
     try {
-      this.ws = new WebSocket(this.url);
+      this.ws = new WebSocket(this.opened ? withoutSnapshot(this.url) : this.url);
     } catch {
       this.scheduleReconnect();
       return;
     }
 
     this.ws.onopen = () => {
+      this.opened = true;
       this.reconnectDelay = 1000;
       this.onStatus(true);
     };
@@ -84,3 +86,16 @@ export class WsClient {
     this.ws = null;
   }
 }
+
+/** The snapshot is only replayed on the first connection: after a reconnect
+ *  the view still holds it, and replaying it again would duplicate spans. */
+function withoutSnapshot(url: string): string {
+  try {
+    const u = new URL(url);
+    u.searchParams.delete('snapshot_minutes');
+    u.searchParams.delete('snapshot_traces');
+    return u.toString();
+  } catch {
+    return url;
+  }
+}
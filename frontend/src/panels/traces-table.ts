@@ -444,6 +444,25 @@ export class TracesTable {
         <td class="da-val">${escHtml(String(v))}</td>
       </tr>`).join('');
 
+    const exc = full.exception;
+    const excSection = exc ? `<div class="dp-section"><div class="dp-section-title">Exception</div>
+          ${exc.type ? `<div class="dp-row"><span class="dp-label">Type</span><span class="dp-mono">${escHtml(exc.type)}</span></div>` : ''}
+          ${exc.message ? `<div class="dp-row"><span class="dp-label">Message</span><span class="dp-mono">${escHtml(exc.message)}</span></div>` : ''}
+          ${exc.stacktrace ? `<pre class="dp-stack">${escHtml(exc.stacktrace)}</pre>` : ''}
+        </div>` : '';
+
+    const eventRows = (full.events ?? [])
+      .map(e => `<tr>
+        <td class="da-key">${escHtml(fmtTime(e.time_unix_nano))}</td>
+        <td class="da-val">${escHtml(e.name)}</td>
+      </tr>`).join('');
+
+    const linkRows = (full.links ?? [])
+      .map(l => `<tr>
+        <td class="da-key" title="${escHtml(l.trace_id)}">${escHtml(l.trace_id)}</td>
+        <td class="da-val">${escHtml(l.span_id)}</td>
+      </tr>`).join('');
+
     this.detailPanel.innerHTML = `
       <div class="dp-header">
         <span class="dp-name" title="${escHtml(full.name)}">${escHtml(full.name)}</span>
@@ -455,6 +474,8 @@ export class TracesTable {
           <div class="dp-row"><span class="dp-label">Service</span><span class="dp-mono">${escHtml(full.service_name || '—')}</span></div>
           <div class="dp-row"><span class="dp-label">Duration</span><span class="dp-mono ${durCls}">${escHtml(fmtDur(full.duration_ms))}</span></div>
           <div class="dp-row"><span class="dp-label">Status</span><span class="st-badge ${statusOk ? 'st-ok' : 'st-err'}">${escHtml(full.status || 'unset')}</span></div>
+          ${full.status_message ? `<div class="dp-row"><span class="dp-label">Message</span><span class="dp-mono">${escHtml(full.status_message)}</span></div>` : ''}
+          ${full.kind ? `<div class="dp-row"><span class="dp-label">Kind</span><span class="dp-mono">${escHtml(full.kind)}</span></div>` : ''}
         </div>
         <div class="dp-section">
           <div class="dp-row"><span class="dp-label">Trace ID</span><span class="dp-mono dp-small">${escHtml(full.trace_id)}</span></div>
@@ -462,7 +483,10 @@ export class TracesTable {
           ${full.parent_span_id ? `<div class="dp-row"><span class="dp-label">Parent</span><span class="dp-mono dp-small">${escHtml(full.parent_span_id)}</span></div>` : ''}
           <div class="dp-row"><span class="dp-label">Start</span><span class="dp-mono dp-small">${escHtml(fmtTime(full.start_time_unix_nano))}</span></div>
         </div>
+        ${excSection}
         ${attrRows ? `<div class="dp-section"><div class="dp-section-title">Attributes</div><table class="dp-attrs"><tbody>${attrRows}</tbody></table></div>` : ''}
+        ${eventRows ? `<div class="dp-section"><div class="dp-section-title">Events</div><table class="dp-attrs"><tbody>${eventRows}</tbody></table></div>` : ''}
+        ${linkRows ? `<div class="dp-section"><div class="dp-section-title">Links</div><table class="dp-attrs"><tbody>${linkRows}</tbody></table></div>` : ''}
       </div>`;
 
     this.detailPanel.classList.add('dp-open');